@@ -0,0 +1,76 @@
+//! Decoding speed with and without the decoder's KV cache, on a `whisper-base` sized model with random weights
+//! Run with `cargo bench --no-default-features --bench decode`
+
+use std::time::Instant;
+
+use audio_instruct::asr;
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::whisper::{model, Config};
+
+const TOKENS: usize = 200;
+
+fn main() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = Config {
+        num_mel_bins: 80,
+        max_source_positions: 1500,
+        d_model: 512,
+        encoder_attention_heads: 8,
+        encoder_layers: 6,
+        vocab_size: 51865,
+        max_target_positions: 448,
+        decoder_attention_heads: 8,
+        decoder_layers: 6,
+        suppress_tokens: vec![],
+    };
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let mut reference = model::Whisper::load(&vb, config.clone())?;
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.02f32, var.shape(), &dev)?)?;
+    }
+    let mut cached = asr::Whisper::load(&asr::Weights::Full(vb), config)?;
+
+    let mel = Tensor::randn(0f32, 1f32, (1, 80, 3000), &dev)?;
+    let features = cached.encoder.forward(&mel)?;
+    let prompt = [50258u32, 50259, 50360, 50364];
+
+    // the whole sequence every step
+    let start = Instant::now();
+    let mut tokens = prompt.to_vec();
+    for i in 0..TOKENS {
+        let x = Tensor::new(&tokens[..], &dev)?.unsqueeze(0)?;
+        let dec = reference.decoder.forward(&x, &features, i == 0)?;
+        let logits = reference
+            .decoder
+            .final_linear(&dec.i((.., tokens.len() - 1..))?)?;
+        tokens.push(logits.flatten_all()?.argmax(0)?.to_scalar::<u32>()?);
+    }
+    let full = TOKENS as f64 / start.elapsed().as_secs_f64();
+
+    // the newest token every step
+    let start = Instant::now();
+    let mut tokens = prompt.to_vec();
+    cached.decoder.reset();
+    for i in 0..TOKENS {
+        let new = if i == 0 {
+            &tokens[..]
+        } else {
+            &tokens[tokens.len() - 1..]
+        };
+        let dec = cached
+            .decoder
+            .forward(&Tensor::new(new, &dev)?.unsqueeze(0)?, &features)?;
+        let (_, seq_len, _) = dec.dims3()?;
+        let logits = cached.decoder.final_linear(&dec.i((.., seq_len - 1..))?)?;
+        tokens.push(logits.flatten_all()?.argmax(0)?.to_scalar::<u32>()?);
+    }
+    let incremental = TOKENS as f64 / start.elapsed().as_secs_f64();
+
+    println!("whole sequence  {full:>7.1} tokens/s");
+    println!("kv cache        {incremental:>7.1} tokens/s");
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Times each stage of the audio clean up on 30 seconds of noisy audio
+//! Run with `cargo bench --no-default-features --bench dsp`
+
+use std::{
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
+
+use audio_instruct::{config::DspConfig, dsp};
+
+const SECS: usize = 30;
+const RUNS: u32 = 10;
+
+fn main() {
+    // a warbling tone over hum, a DC offset and hiss
+    let mut x = 12345u32;
+    let pcm = (0..SECS * 16_000)
+        .map(|i| {
+            let t = i as f32 / 16_000.;
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            let hiss = (x as f32 / u32::MAX as f32 * 2. - 1.) * 0.02;
+
+            (2. * PI * (300. + 50. * (2. * PI * 3. * t).sin()) * t).sin() * 0.05
+                + (2. * PI * 50. * t).sin() * 0.02
+                + 0.01
+                + hiss
+        })
+        .collect::<Vec<_>>();
+
+    let stages = [
+        (
+            "highpass",
+            DspConfig {
+                highpass: true,
+                normalize: false,
+                denoise: false,
+            },
+        ),
+        (
+            "normalize",
+            DspConfig {
+                highpass: false,
+                normalize: true,
+                denoise: false,
+            },
+        ),
+        (
+            "denoise",
+            DspConfig {
+                highpass: false,
+                normalize: false,
+                denoise: true,
+            },
+        ),
+        ("default", DspConfig::default()),
+        (
+            "all",
+            DspConfig {
+                highpass: true,
+                normalize: true,
+                denoise: true,
+            },
+        ),
+    ];
+
+    for (name, config) in stages {
+        let mut total = Duration::ZERO;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            std::hint::black_box(dsp::process(&pcm[..], &config));
+            total += start.elapsed();
+        }
+
+        let avg = total / RUNS;
+        println!(
+            "{name:<10} {avg:>10.2?} per {SECS} s of audio, {:.0}x real time",
+            SECS as f64 / avg.as_secs_f64()
+        );
+    }
+}
@@ -1,3 +1,5 @@
 fn main() {
+    // The tauri context is only required for the desktop app, the headless cli doesn't need it
+    #[cfg(feature = "desktop")]
     tauri_build::build()
 }
@@ -0,0 +1,594 @@
+//! The `whisper` encoder-decoder, loading the same weights as `candle_transformers::models::whisper::model`
+//! The decoder keeps the keys and values of the tokens decoded so far, so that every step only runs the newest token
+//! instead of the whole transcript
+//! The weights are either `safetensors` or a quantized `gguf`, as `quantized_model` loads them
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Shape, Tensor, D};
+use candle_nn::{ops::softmax_last_dim, Conv1d, Conv1dConfig, Embedding, LayerNorm, VarBuilder};
+use candle_transformers::{
+    models::whisper::Config, quantized_nn, quantized_var_builder::VarBuilder as QVarBuilder,
+};
+
+/// Where the weights are loaded from
+#[derive(Clone)]
+pub enum Weights<'a> {
+    Full(VarBuilder<'a>),
+    /// the matrices of the linear layers stay quantized, everything else is dequantized
+    Quantized(QVarBuilder),
+}
+
+impl Weights<'_> {
+    fn pp(&self, s: impl ToString) -> Self {
+        match self {
+            Self::Full(vb) => Self::Full(vb.pp(s)),
+            Self::Quantized(vb) => Self::Quantized(vb.pp(s)),
+        }
+    }
+
+    fn device(&self) -> &Device {
+        match self {
+            Self::Full(vb) => vb.device(),
+            Self::Quantized(vb) => vb.device(),
+        }
+    }
+
+    // the dequantized weights are `f32`
+    fn dtype(&self) -> DType {
+        match self {
+            Self::Full(vb) => vb.dtype(),
+            Self::Quantized(_) => DType::F32,
+        }
+    }
+
+    fn get<S: Into<Shape>>(&self, s: S, name: &str) -> Result<Tensor> {
+        match self {
+            Self::Full(vb) => vb.get(s, name),
+            Self::Quantized(vb) => vb.get(s, name)?.dequantize(vb.device()),
+        }
+    }
+}
+
+enum Linear {
+    Full(candle_nn::Linear),
+    Quantized(quantized_nn::Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(l) => l.forward(x),
+            Self::Quantized(l) => l.forward(x),
+        }
+    }
+}
+
+fn linear(in_dim: usize, out_dim: usize, vb: Weights) -> Result<Linear> {
+    Ok(match vb {
+        Weights::Full(vb) => Linear::Full(candle_nn::linear(in_dim, out_dim, vb)?),
+        Weights::Quantized(vb) => Linear::Quantized(quantized_nn::linear(in_dim, out_dim, vb)?),
+    })
+}
+
+fn linear_no_bias(in_dim: usize, out_dim: usize, vb: Weights) -> Result<Linear> {
+    Ok(match vb {
+        Weights::Full(vb) => Linear::Full(candle_nn::linear_no_bias(in_dim, out_dim, vb)?),
+        Weights::Quantized(vb) => {
+            Linear::Quantized(quantized_nn::linear_no_bias(in_dim, out_dim, vb)?)
+        }
+    })
+}
+
+fn conv1d(
+    in_channels: usize,
+    out_channels: usize,
+    config: Conv1dConfig,
+    vb: Weights,
+) -> Result<Conv1d> {
+    let weight = vb.get((out_channels, in_channels, 3), "weight")?;
+    let bias = vb.get(out_channels, "bias")?;
+    Ok(Conv1d::new(weight, Some(bias), config))
+}
+
+fn layer_norm(size: usize, vb: Weights) -> Result<LayerNorm> {
+    let weight = vb.get(size, "weight")?;
+    let bias = vb.get(size, "bias")?;
+    Ok(LayerNorm::new(weight, bias, 1e-5))
+}
+
+struct MultiHeadAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    out: Linear,
+    n_head: usize,
+    /// the keys and values: of the tokens so far for self attention, of the audio for cross attention
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl MultiHeadAttention {
+    fn load(n_state: usize, n_head: usize, vb: Weights) -> Result<Self> {
+        Ok(Self {
+            query: linear(n_state, n_state, vb.pp("q_proj"))?,
+            key: linear_no_bias(n_state, n_state, vb.pp("k_proj"))?,
+            value: linear(n_state, n_state, vb.pp("v_proj"))?,
+            out: linear(n_state, n_state, vb.pp("out_proj"))?,
+            n_head,
+            kv_cache: None,
+        })
+    }
+
+    // self attention over `x`, following the cached keys and values if `cache`
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, cache: bool) -> Result<Tensor> {
+        let q = self.query.forward(x)?;
+        let mut k = self.key.forward(x)?;
+        let mut v = self.value.forward(x)?;
+        if cache {
+            if let Some((pk, pv)) = &self.kv_cache {
+                k = Tensor::cat(&[pk, &k], 1)?;
+                v = Tensor::cat(&[pv, &v], 1)?;
+            }
+            self.kv_cache = Some((k.clone(), v.clone()));
+        }
+
+        let wv = self.qkv_attention(&q, &k, &v, mask)?;
+        self.out.forward(&wv)
+    }
+
+    // cross attention from `x` to the audio features `xa`, their keys and values are computed once per segment
+    fn forward_cross(&mut self, x: &Tensor, xa: &Tensor) -> Result<Tensor> {
+        let q = self.query.forward(x)?;
+        let (k, v) = match &self.kv_cache {
+            Some((k, v)) => (k.clone(), v.clone()),
+            None => {
+                let kv = (self.key.forward(xa)?, self.value.forward(xa)?);
+                self.kv_cache = Some(kv.clone());
+                kv
+            }
+        };
+
+        let wv = self.qkv_attention(&q, &k, &v, None)?;
+        self.out.forward(&wv)
+    }
+
+    fn reshape_head(&self, x: &Tensor) -> Result<Tensor> {
+        let (n_batch, n_ctx, n_state) = x.dims3()?;
+        x.reshape((n_batch, n_ctx, self.n_head, n_state / self.n_head))?
+            .transpose(1, 2)
+    }
+
+    fn qkv_attention(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (_, _, n_state) = q.dims3()?;
+        let scale = ((n_state / self.n_head) as f64).powf(-0.25);
+        let q = (self.reshape_head(q)? * scale)?;
+        let k = (self.reshape_head(k)?.transpose(2, 3)? * scale)?;
+        let v = self.reshape_head(v)?.contiguous()?;
+
+        let mut qk = q.matmul(&k)?;
+        if let Some(mask) = mask {
+            qk = qk.broadcast_add(mask)?;
+        }
+
+        softmax_last_dim(&qk)?
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .flatten_from(2)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.kv_cache = None;
+    }
+}
+
+struct ResidualAttentionBlock {
+    attn: MultiHeadAttention,
+    attn_ln: LayerNorm,
+    cross_attn: Option<(MultiHeadAttention, LayerNorm)>,
+    mlp_linear1: Linear,
+    mlp_linear2: Linear,
+    mlp_ln: LayerNorm,
+}
+
+impl ResidualAttentionBlock {
+    fn load(n_state: usize, n_head: usize, ca: bool, vb: Weights) -> Result<Self> {
+        let cross_attn = if ca {
+            Some((
+                MultiHeadAttention::load(n_state, n_head, vb.pp("encoder_attn"))?,
+                layer_norm(n_state, vb.pp("encoder_attn_layer_norm"))?,
+            ))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            attn: MultiHeadAttention::load(n_state, n_head, vb.pp("self_attn"))?,
+            attn_ln: layer_norm(n_state, vb.pp("self_attn_layer_norm"))?,
+            cross_attn,
+            mlp_linear1: linear(n_state, n_state * 4, vb.pp("fc1"))?,
+            mlp_linear2: linear(n_state * 4, n_state, vb.pp("fc2"))?,
+            mlp_ln: layer_norm(n_state, vb.pp("final_layer_norm"))?,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        xa: Option<&Tensor>,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        // only the decoder blocks, with cross attention, cache their keys and values
+        let cache = self.cross_attn.is_some();
+        let attn = self.attn.forward(&self.attn_ln.forward(x)?, mask, cache)?;
+        let mut x = (x + attn)?;
+        if let (Some((attn, ln)), Some(xa)) = (&mut self.cross_attn, xa) {
+            x = (&x + attn.forward_cross(&ln.forward(&x)?, xa)?)?;
+        }
+        let mlp = self.mlp_linear2.forward(
+            &self
+                .mlp_linear1
+                .forward(&self.mlp_ln.forward(&x)?)?
+                .gelu()?,
+        )?;
+
+        x + mlp
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.attn.reset_kv_cache();
+        if let Some((attn, _)) = &mut self.cross_attn {
+            attn.reset_kv_cache();
+        }
+    }
+}
+
+fn sinusoids(length: usize, channels: usize, vb: &Weights) -> Result<Tensor> {
+    let max_timescale = 10000f32;
+    let log_timescale_increment = max_timescale.ln() / (channels / 2 - 1) as f32;
+    let inv_timescales: Vec<_> = (0..channels / 2)
+        .map(|i| (i as f32 * (-log_timescale_increment)).exp())
+        .collect();
+    let inv_timescales = Tensor::new(inv_timescales.as_slice(), vb.device())?.unsqueeze(0)?;
+    let arange = Tensor::arange(0, length as u32, vb.device())?
+        .to_dtype(DType::F32)?
+        .unsqueeze(1)?;
+    let sh = (length, channels / 2);
+    let scaled_time = (arange.broadcast_as(sh)? * inv_timescales.broadcast_as(sh)?)?;
+
+    Tensor::cat(&[scaled_time.sin()?, scaled_time.cos()?], 1)?.to_dtype(vb.dtype())
+}
+
+/// Turns a log-mel spectrogram into the audio features the decoder attends to
+pub struct AudioEncoder {
+    conv1: Conv1d,
+    conv2: Conv1d,
+    positional_embedding: Tensor,
+    blocks: Vec<ResidualAttentionBlock>,
+    ln_post: LayerNorm,
+}
+
+impl AudioEncoder {
+    fn load(vb: Weights, cfg: &Config) -> Result<Self> {
+        let n_state = cfg.d_model;
+        let conv = |stride| Conv1dConfig {
+            padding: 1,
+            stride,
+            ..Default::default()
+        };
+        let blocks = (0..cfg.encoder_layers)
+            .map(|i| {
+                ResidualAttentionBlock::load(
+                    n_state,
+                    cfg.encoder_attention_heads,
+                    false,
+                    vb.pp(format!("layers.{i}")),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            conv1: conv1d(cfg.num_mel_bins, n_state, conv(1), vb.pp("conv1"))?,
+            conv2: conv1d(n_state, n_state, conv(2), vb.pp("conv2"))?,
+            positional_embedding: sinusoids(cfg.max_source_positions, n_state, &vb)?,
+            blocks,
+            ln_post: layer_norm(n_state, vb.pp("layer_norm"))?,
+        })
+    }
+
+    /// `x` is a `(batch, n_mel, frames)` spectrogram, of at most `N_FRAMES`
+    /// The features are in the precision of the weights
+    pub fn forward(&mut self, x: &Tensor) -> Result<Tensor> {
+        let x = x.to_dtype(self.conv1.weight().dtype())?;
+        let x = self.conv1.forward(&x)?.gelu()?;
+        let x = self.conv2.forward(&x)?.gelu()?.transpose(1, 2)?;
+        let (_, seq_len, _) = x.dims3()?;
+        let mut x = x.broadcast_add(&self.positional_embedding.narrow(0, 0, seq_len)?)?;
+        for block in self.blocks.iter_mut() {
+            x = block.forward(&x, None, None)?;
+        }
+
+        self.ln_post.forward(&x)
+    }
+}
+
+/// Predicts the next token from the ones so far and the audio features, a step at a time
+pub struct TextDecoder {
+    token_embedding: Embedding,
+    positional_embedding: Tensor,
+    blocks: Vec<ResidualAttentionBlock>,
+    ln: LayerNorm,
+    mask: Tensor,
+    /// the tokens in the cache
+    offset: usize,
+}
+
+impl TextDecoder {
+    fn load(vb: Weights, cfg: &Config) -> Result<Self> {
+        let n_state = cfg.d_model;
+        let n_ctx = cfg.max_target_positions;
+        let blocks = (0..cfg.decoder_layers)
+            .map(|i| {
+                ResidualAttentionBlock::load(
+                    n_state,
+                    cfg.decoder_attention_heads,
+                    true,
+                    vb.pp(format!("layers.{i}")),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mask: Vec<_> = (0..n_ctx)
+            .flat_map(|i| (0..n_ctx).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
+            .collect();
+
+        Ok(Self {
+            token_embedding: Embedding::new(
+                vb.get((cfg.vocab_size, n_state), "embed_tokens.weight")?,
+                n_state,
+            ),
+            positional_embedding: vb.get((n_ctx, n_state), "embed_positions.weight")?,
+            blocks,
+            ln: layer_norm(n_state, vb.pp("layer_norm"))?,
+            mask: Tensor::from_vec(mask, (n_ctx, n_ctx), vb.device())?.to_dtype(vb.dtype())?,
+            offset: 0,
+        })
+    }
+
+    /// Runs the `(batch, tokens)` following the ones decoded since the last `reset()`, attending to the audio `xa`
+    /// Returns the hidden states of the new `tokens` only
+    pub fn forward(&mut self, tokens: &Tensor, xa: &Tensor) -> Result<Tensor> {
+        let seq_len = tokens.dim(D::Minus1)?;
+        let offset = self.offset;
+
+        let mut x = self
+            .token_embedding
+            .forward(tokens)?
+            .broadcast_add(&self.positional_embedding.narrow(0, offset, seq_len)?)?;
+        // a single token attends to all of the cached ones, several need to be kept from the ones after them
+        let mask = if seq_len > 1 {
+            Some(
+                self.mask
+                    .i((offset..offset + seq_len, ..offset + seq_len))?,
+            )
+        } else {
+            None
+        };
+        for block in self.blocks.iter_mut() {
+            x = block.forward(&x, Some(xa), mask.as_ref())?;
+        }
+        self.offset += seq_len;
+
+        self.ln.forward(&x)
+    }
+
+    /// The `f32` logits of the hidden states `x`, the output projection shares its weights with the token embedding
+    pub fn final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        let b_size = x.dim(0)?;
+        let w = self.token_embedding.embeddings().broadcast_left(b_size)?;
+        x.matmul(&w.t()?)?.to_dtype(DType::F32)
+    }
+
+    /// Forgets the tokens and the audio, before decoding the next segment
+    pub fn reset(&mut self) {
+        self.offset = 0;
+        self.blocks.iter_mut().for_each(|b| b.reset_kv_cache());
+    }
+}
+
+pub struct Whisper {
+    pub encoder: AudioEncoder,
+    pub decoder: TextDecoder,
+    pub config: Config,
+}
+
+impl Whisper {
+    pub fn load(vb: &Weights, config: Config) -> Result<Self> {
+        Ok(Self {
+            encoder: AudioEncoder::load(vb.pp("model.encoder"), &config)?,
+            decoder: TextDecoder::load(vb.pp("model.decoder"), &config)?,
+            config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{
+        quantized::{gguf_file, GgmlDType, QTensor},
+        DType, Device, IndexOp, Tensor,
+    };
+    use candle_nn::{VarBuilder, VarMap};
+    use candle_transformers::{
+        models::whisper::{model, Config},
+        quantized_var_builder,
+    };
+
+    use super::{Weights, Whisper};
+
+    fn config() -> Config {
+        Config {
+            num_mel_bins: 80,
+            max_source_positions: 50,
+            d_model: 64,
+            encoder_attention_heads: 4,
+            encoder_layers: 2,
+            vocab_size: 100,
+            max_target_positions: 32,
+            decoder_attention_heads: 4,
+            decoder_layers: 2,
+            suppress_tokens: vec![],
+        }
+    }
+
+    // the same random weights for both implementations
+    fn weights(dev: &Device) -> anyhow::Result<VarMap> {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+        model::Whisper::load(&vb, config())?;
+        for var in varmap.all_vars() {
+            var.set(&Tensor::randn(0f32, 0.2f32, var.shape(), dev)?)?;
+        }
+
+        Ok(varmap)
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> anyhow::Result<f32> {
+        Ok((a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?)
+    }
+
+    #[test]
+    fn matches_candle() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let varmap = weights(&dev)?;
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+        let mut reference = model::Whisper::load(&vb, config())?;
+        let mut cached = Whisper::load(&Weights::Full(vb), config())?;
+
+        let mel = Tensor::randn(0f32, 1f32, (1, 80, 100), &dev)?;
+        let features = reference.encoder.forward(&mel, true)?;
+        assert!(max_diff(&features, &cached.encoder.forward(&mel)?)? < 1e-5);
+
+        // greedy decoding, the whole sequence every step vs the newest token
+        let mut tokens = vec![1u32, 2, 3, 4];
+        for i in 0..24 {
+            let all = Tensor::new(&tokens[..], &dev)?.unsqueeze(0)?;
+            let dec = reference.decoder.forward(&all, &features, i == 0)?;
+            let expected = reference
+                .decoder
+                .final_linear(&dec.i((.., tokens.len() - 1..))?)?;
+
+            let new = if i == 0 {
+                &tokens[..]
+            } else {
+                &tokens[tokens.len() - 1..]
+            };
+            let dec = cached
+                .decoder
+                .forward(&Tensor::new(new, &dev)?.unsqueeze(0)?, &features)?;
+            let (_, seq_len, _) = dec.dims3()?;
+            let logits = cached.decoder.final_linear(&dec.i((.., seq_len - 1..))?)?;
+            assert!(max_diff(&logits, &expected)? < 1e-4, "step {i}");
+
+            let next = logits.flatten_all()?.argmax(0)?.to_scalar::<u32>()?;
+            assert_eq!(next, expected.flatten_all()?.argmax(0)?.to_scalar::<u32>()?);
+            tokens.push(next);
+        }
+
+        // a new segment starts from scratch
+        cached.decoder.reset();
+        let dec = cached
+            .decoder
+            .forward(&Tensor::new(&tokens[..4], &dev)?.unsqueeze(0)?, &features)?;
+        let dec_ref = reference.decoder.forward(
+            &Tensor::new(&tokens[..4], &dev)?.unsqueeze(0)?,
+            &features,
+            true,
+        )?;
+        assert!(max_diff(&dec, &dec_ref)? < 1e-4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_gguf() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let varmap = weights(&dev)?;
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+        let mut full = Whisper::load(&Weights::Full(vb), config())?;
+
+        // the same weights in a `gguf`, unquantized so that the outputs match
+        let tensors = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| {
+                Ok((
+                    name.clone(),
+                    QTensor::quantize(var.as_tensor(), GgmlDType::F32)?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut gguf = std::io::Cursor::new(vec![]);
+        gguf_file::write(
+            &mut gguf,
+            &[],
+            &tensors
+                .iter()
+                .map(|(n, t)| (n.as_str(), t))
+                .collect::<Vec<_>>()[..],
+        )?;
+        let vb = quantized_var_builder::VarBuilder::from_gguf_buffer(gguf.get_ref(), &dev)?;
+        let mut quantized = Whisper::load(&Weights::Quantized(vb), config())?;
+
+        let mel = Tensor::randn(0f32, 1f32, (1, 80, 100), &dev)?;
+        let features = full.encoder.forward(&mel)?;
+        assert!(max_diff(&features, &quantized.encoder.forward(&mel)?)? < 1e-5);
+
+        let tokens = Tensor::new(&[1u32, 2, 3, 4], &dev)?.unsqueeze(0)?;
+        let dec = full.decoder.forward(&tokens, &features)?;
+        assert!(max_diff(&dec, &quantized.decoder.forward(&tokens, &features)?)? < 1e-4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_in_half_precision() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let varmap = weights(&dev)?;
+        let mel = Tensor::randn(0f32, 1f32, (1, 80, 100), &dev)?;
+        let tokens = Tensor::new(&[1u32, 2, 3, 4], &dev)?.unsqueeze(0)?;
+
+        // the varmap ignores the dtype asked for, a map of its tensors converts them
+        let tensors: HashMap<_, _> = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+            .collect();
+        let logits = |dtype| -> anyhow::Result<Tensor> {
+            let vb = VarBuilder::from_tensors(tensors.clone(), dtype, &dev);
+            let mut model = Whisper::load(&Weights::Full(vb), config())?;
+            let features = model.encoder.forward(&mel)?;
+            assert_eq!(features.dtype(), dtype);
+            let dec = model.decoder.forward(&tokens, &features)?;
+
+            Ok(model.decoder.final_linear(&dec)?)
+        };
+
+        let expected = logits(DType::F32)?;
+        // `candle` has no `bf16` matmul on the CPU
+        let logits = logits(DType::F16)?;
+        assert_eq!(logits.dtype(), DType::F32);
+        let diff = max_diff(&logits, &expected)?;
+        assert!(diff < 0.05, "{diff}");
+
+        Ok(())
+    }
+}
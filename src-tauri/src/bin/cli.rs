@@ -0,0 +1,194 @@
+//! `audio-instruct-cli`: a headless frontend to the `Instruct` pipeline, meant for scripting and machines without a display
+//!
+//! Examples:
+//!   audio-instruct-cli ask "Who is Steve Wozniak?"
+//!   audio-instruct-cli ask --grammar yes-no.gbnf "Is the sky blue?"
+//!   audio-instruct-cli --format json transcribe meeting.wav
+//!   audio-instruct-cli voice question.wav
+//!   audio-instruct-cli diarize --speakers 3 --summarize meeting.wav
+//!   audio-instruct-cli download-models
+//!   audio-instruct-cli ingest ~/notes manual.pdf
+//!   audio-instruct-cli serve --port 8765
+
+use std::{fs::File, io::BufReader, path::PathBuf};
+
+use anyhow::Result;
+use audio_instruct::{
+    config::ServerConfig, instruct::Instruct, rag::Documents, scheduler::Cancel, server::Server,
+    types::ResponseFormat, utils,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "audio-instruct-cli",
+    version,
+    about = "Headless audio-instruct: Llama3 + Whisper"
+)]
+struct Cli {
+    /// The directory holding the models, defaults to the desktop app's data directory
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    /// The output format
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Respond to a text instruction
+    Ask {
+        text: String,
+        /// A GBNF grammar file the answer must match
+        #[arg(long)]
+        grammar: Option<PathBuf>,
+    },
+    /// Transcribe a `.wav` file
+    Transcribe { file: PathBuf },
+    /// Transcribe a `.wav` file and respond to the transcript as an instruction
+    Voice { file: PathBuf },
+    /// Transcribe a `.wav` file of several speakers, labelling who said what
+    Diarize {
+        file: PathBuf,
+        /// The number of speakers, if known
+        #[arg(long)]
+        speakers: Option<usize>,
+        /// Summarize the conversation and list the action items
+        #[arg(long)]
+        summarize: bool,
+    },
+    /// Download all the models, without loading them
+    DownloadModels,
+    /// Show the device, threads and precision the models run with
+    SystemInfo,
+    /// Index text, Markdown and PDF files, or folders of them, to answer from
+    Ingest { paths: Vec<PathBuf> },
+    /// Run the OpenAI compatible HTTP API on `127.0.0.1`, defaults to the port in `config.json`
+    Serve {
+        #[arg(long)]
+        port: Option<u16>,
+    },
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("error: {e:?}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let datadir = match cli.data_dir {
+        Some(d) => d,
+        None => utils::default_data_dir()?,
+    };
+    std::fs::create_dir_all(&datadir)?;
+
+    match cli.command {
+        Cmd::DownloadModels => {
+            Instruct::download(datadir.clone())?;
+            output(cli.format, &datadir.display().to_string(), &datadir)
+        }
+        Cmd::SystemInfo => {
+            // the models are loaded on first use, this doesn't load them
+            let info = Instruct::new(datadir)?.system_info()?;
+            let text = format!(
+                "device: {}\nthreads: {} of {} cores\nmmap: {}\nwhisper dtype: {}\ncpu features: {}",
+                info.device,
+                info.threads,
+                info.cores,
+                info.mmap,
+                info.asr_dtype,
+                info.cpu_features.join(", ")
+            );
+            output(cli.format, &text, &info)
+        }
+        Cmd::Ingest { paths } => {
+            // only the embedding model is needed, no need to load the others
+            let docs = Documents::new(datadir.as_path(), Default::default());
+            let files = docs.ingest(&paths[..], &Cancel::default())?;
+            let names = files
+                .iter()
+                .map(|f| f.display().to_string())
+                .collect::<Vec<_>>();
+            output(cli.format, &names.join("\n"), &files)
+        }
+        Cmd::Ask { text, grammar } => {
+            let format = match grammar {
+                Some(g) => Some(ResponseFormat::Grammar {
+                    grammar: std::fs::read_to_string(g)?,
+                }),
+                None => None,
+            };
+            let app = Instruct::new(datadir)?;
+            let res = app.text(&text, None, format.as_ref(), &Cancel::default())?;
+            output(cli.format, res.text(), &res)
+        }
+        Cmd::Transcribe { file } => {
+            let pcm = utils::read_wav(BufReader::new(File::open(file)?))?;
+            let app = Instruct::new(datadir)?;
+            let res = app.transcribe(&pcm[..], &Cancel::default())?;
+            output(cli.format, res.text(), &res)
+        }
+        Cmd::Voice { file } => {
+            let pcm = utils::read_wav(BufReader::new(File::open(file)?))?;
+            let app = Instruct::new(datadir)?;
+            let res = app.voice(&pcm[..], None, None, &Cancel::default())?;
+            output(cli.format, res.text(), &res)
+        }
+        Cmd::Diarize {
+            file,
+            speakers,
+            summarize,
+        } => {
+            let pcm = utils::read_wav(BufReader::new(File::open(file)?))?;
+            let app = Instruct::new(datadir)?;
+            let res = app.diarize(&pcm[..], speakers, summarize, &Cancel::default())?;
+            let text = match &res.summary {
+                Some(s) => format!("{}\n\n{s}", res.transcript()),
+                None => res.transcript(),
+            };
+            output(cli.format, &text, &res)
+        }
+        Cmd::Serve { port } => {
+            let app = Instruct::new(datadir)?;
+            let mut config = ServerConfig {
+                enabled: true,
+                ..app.config().server
+            };
+            if let Some(p) = port {
+                config.port = p;
+            }
+
+            let _server = Server::start(app, &config)?;
+            // serve till killed
+            loop {
+                std::thread::park();
+            }
+        }
+    }
+}
+
+// prints either the plain `text` or the `json` serialized `data` to stdout
+fn output<T: Serialize>(format: Format, text: &str, data: &T) -> Result<()> {
+    match format {
+        Format::Text => println!("{}", text.trim()),
+        Format::Json => println!("{}", serde_json::to_string(data)?),
+    }
+
+    Ok(())
+}
@@ -1,69 +1,39 @@
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use audio_instruct::{
+    config::{AsrConfig, Config, LlmConfig, Profile, Speed},
+    diarize::Diarization,
+    events::ModelKind,
+    history::{ExportFormat, HistoryEntry},
+    instruct::Instruct,
+    scheduler::{Priority, ScheduleError},
+    server::Server,
+    tts::VOICES,
+    types::{Command, Mode, Response, SystemInfo},
+    utils::bytes_to_f32,
+};
 use tauri::ipc;
 
-use crate::{instruct::Instruct, utils::bytes_to_f32};
-
-/// A struct to represent the incoming request
-/// For `text` inference it would contain the instruction itself,
-/// But for `audio` instructions we'll just need to indicate that we are looking for the audio recorded to generate inference since audio data is sent in chunks and
-/// already maintained in the app state
-#[derive(Debug, Deserialize)]
-pub struct Command {
-    text: Option<String>,
-    audio: Option<bool>,
-}
-
-/// Enum to maintain what kind of instruction this is
-pub enum Mode {
-    Text(String),
-    Audio,
-}
-
-impl Command {
-    pub fn mode(&self) -> Result<Mode> {
-        if let Some(t) = self.text.as_ref() {
-            Ok(Mode::Text(t.to_owned()))
-        } else if self.audio.map_or(false, |d| d) {
-            Ok(Mode::Audio)
-        } else {
-            anyhow::bail!("not a valid command")
-        }
-    }
-}
-
-/// A struct to hold the response data and some stats or metadata required to show the inference
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Response {
-    text: String,
-    meta: Meta,
-    instruct: String,
-}
-
-/// A struct to hold some metadata and additional information about the QA/ Response/ Instruction etc.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Meta {
-    // number of tokens generated
-    n_tokens: u32,
-    // number of seconds elapsed
-    n_secs: u64,
-}
-
-impl Response {
-    pub fn new(instruct: &str, txt: &str, n_tokens: u32, n_secs: u64) -> Self {
-        Self {
-            instruct: instruct.to_string(),
-            text: txt.to_string(),
-            meta: Meta { n_secs, n_tokens },
-        }
+/// The message shown to the user when the scheduler turns a request down
+fn rejected(e: &ScheduleError) -> &'static str {
+    match e {
+        ScheduleError::QueueFull(_) => "inference queue is full, try again later",
+        ScheduleError::Duplicate(_) => "a request with this id is already pending",
+        ScheduleError::ShuttingDown => "the app is shutting down",
+        ScheduleError::Unavailable => "the inference queue is unavailable",
     }
 }
 
 /// A command to accept incoming `instruction` and respond with the `inference`
+/// The inference runs on the scheduler's worker threads, this just awaits the result without holding up an IPC thread
 #[tauri::command]
-pub fn ask(app: tauri::State<'_, Arc<Instruct>>, cmd: Command) -> Result<Response, &'static str> {
+pub async fn ask(
+    app: tauri::State<'_, Arc<Instruct>>,
+    cmd: Command,
+) -> Result<Response, &'static str> {
     let command = match cmd.mode() {
         Ok(c) => c,
         Err(e) => {
@@ -72,12 +42,45 @@ pub fn ask(app: tauri::State<'_, Arc<Instruct>>, cmd: Command) -> Result<Respons
         }
     };
 
-    let res = match command {
-        Mode::Text(t) => app.text(&t),
-        Mode::Audio => app.audio(),
+    let conversation = cmd.conversation();
+    let format = cmd.response_format();
+    let ticket = match command {
+        Mode::Text(t) => app.schedule(
+            cmd.id(),
+            cmd.priority(),
+            &[ModelKind::Llm],
+            move |app, cancel| app.text(&t, conversation.as_deref(), format.as_ref(), cancel),
+        ),
+        Mode::Audio => {
+            // the recorded audio is taken right away, a new recording may begin while this request waits in the queue
+            let pcm = match app.take_audio() {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("ask: error reading audio: {e:?}");
+                    return Err("no audio recorded");
+                }
+            };
+
+            app.schedule(
+                cmd.id(),
+                cmd.priority(),
+                &[ModelKind::Asr, ModelKind::Llm],
+                move |app, cancel| {
+                    app.voice(&pcm[..], conversation.as_deref(), format.as_ref(), cancel)
+                },
+            )
+        }
+    };
+
+    let ticket = match ticket {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("ask: rejected: {e}");
+            return Err(rejected(&e));
+        }
     };
 
-    match res {
+    match ticket.await {
         Ok(r) => Ok(r),
         Err(e) => {
             error!("ask: error during inference: {e:?}");
@@ -86,6 +89,273 @@ pub fn ask(app: tauri::State<'_, Arc<Instruct>>, cmd: Command) -> Result<Respons
     }
 }
 
+/// Transcribes the audio recorded so far, e.g. a meeting, labelling the speakers
+/// Pass the number of `speakers` if known, and `summarize` for a summary with the action items
+#[tauri::command]
+pub async fn diarize(
+    app: tauri::State<'_, Arc<Instruct>>,
+    speakers: Option<usize>,
+    summarize: bool,
+) -> Result<Diarization, &'static str> {
+    let pcm = app.take_audio().map_err(|e| {
+        error!("diarize: error reading audio: {e:?}");
+        "no audio recorded"
+    })?;
+
+    // the language model is needed only for the summary
+    let models: &'static [ModelKind] = if summarize {
+        &[ModelKind::Asr, ModelKind::Llm]
+    } else {
+        &[ModelKind::Asr]
+    };
+    let ticket = app
+        .schedule(None, Priority::Interactive, models, move |app, cancel| {
+            app.diarize(&pcm[..], speakers, summarize, cancel)
+        })
+        .map_err(|e| {
+            warn!("diarize: rejected: {e}");
+            rejected(&e)
+        })?;
+
+    ticket.await.map_err(|e| {
+        error!("diarize: error: {e:?}");
+        "error transcribing speakers"
+    })
+}
+
+/// Cancels a queued or running request by its `id`
+#[tauri::command]
+pub fn cancel(app: tauri::State<'_, Arc<Instruct>>, id: String) -> bool {
+    app.cancel(&id)
+}
+
+/// Shares a file the user picked with the model's `read_file` tool
+#[tauri::command]
+pub fn share_file(app: tauri::State<'_, Arc<Instruct>>, path: PathBuf) -> Result<(), &'static str> {
+    app.tools().share_file(&path).map_err(|e| {
+        error!("share_file: error: {e:?}");
+        "error sharing file"
+    })
+}
+
+/// Stops speaking the answers, the frontend stops playing what it already received
+#[tauri::command]
+pub fn tts_stop(app: tauri::State<'_, Arc<Instruct>>) {
+    app.stop_speech();
+}
+
+/// Lists the voices the answers can be spoken in
+#[tauri::command]
+pub fn tts_voices() -> Vec<&'static str> {
+    VOICES.to_vec()
+}
+
+/// Selects the voice and pace of the spoken answers
+#[tauri::command]
+pub fn tts_select(
+    app: tauri::State<'_, Arc<Instruct>>,
+    voice: String,
+    speed: Speed,
+) -> Result<(), &'static str> {
+    app.select_voice(&voice, speed).map_err(|e| {
+        error!("tts_select: error: {e:?}");
+        "error selecting voice"
+    })
+}
+
+/// Records the wake phrase for the always-listening mode from the audio recorded so far
+/// Returns the number of recordings of the phrase so far
+#[tauri::command]
+pub fn wake_enroll(app: tauri::State<'_, Arc<Instruct>>) -> Result<usize, &'static str> {
+    app.enroll_wake_phrase().map_err(|e| {
+        error!("wake_enroll: error: {e:?}");
+        "error recording wake phrase"
+    })
+}
+
+/// Forgets the recordings of the wake phrase
+#[tauri::command]
+pub fn wake_clear(app: tauri::State<'_, Arc<Instruct>>) -> Result<(), &'static str> {
+    app.clear_wake_phrase().map_err(|e| {
+        error!("wake_clear: error: {e:?}");
+        "error clearing wake phrase"
+    })
+}
+
+/// Lists the persona profiles and the `name` of the active one
+#[tauri::command]
+pub fn profiles(app: tauri::State<'_, Arc<Instruct>>) -> (Vec<Profile>, String) {
+    let config = app.config();
+    (config.profiles, config.profile)
+}
+
+/// Creates a profile, or updates an existing profile with the same `name`
+#[tauri::command]
+pub fn save_profile(
+    app: tauri::State<'_, Arc<Instruct>>,
+    profile: Profile,
+) -> Result<(), &'static str> {
+    app.save_profile(profile).map_err(|e| {
+        error!("save_profile: error: {e:?}");
+        "error saving profile"
+    })
+}
+
+/// Makes the profile `name` the active one
+#[tauri::command]
+pub fn select_profile(
+    app: tauri::State<'_, Arc<Instruct>>,
+    name: String,
+) -> Result<(), &'static str> {
+    app.select_profile(&name).map_err(|e| {
+        error!("select_profile: error: {e:?}");
+        "error selecting profile"
+    })
+}
+
+/// Loads the language model in `llm` in the background and switches to it once it's ready
+/// The progress is reported with `model` events
+#[tauri::command]
+pub fn switch_llm(
+    app: tauri::State<'_, Arc<Instruct>>,
+    llm: LlmConfig,
+) -> Result<(), &'static str> {
+    app.switch_llm(llm).map_err(|e| {
+        error!("switch_llm: error: {e:?}");
+        "error switching language model"
+    })
+}
+
+/// Loads the `whisper` variant in `asr` in the background and switches to it once it's ready
+/// The progress is reported with `model` events
+#[tauri::command]
+pub fn switch_asr(
+    app: tauri::State<'_, Arc<Instruct>>,
+    asr: AsrConfig,
+) -> Result<(), &'static str> {
+    app.switch_asr(asr).map_err(|e| {
+        error!("switch_asr: error: {e:?}");
+        "error switching transcription model"
+    })
+}
+
+/// The device, threads and precision the models run with, and which models are loaded
+#[tauri::command]
+pub fn system_info(app: tauri::State<'_, Arc<Instruct>>) -> Result<SystemInfo, &'static str> {
+    app.system_info().map_err(|e| {
+        error!("system_info: error: {e:?}");
+        "error reading system info"
+    })
+}
+
+/// Lists the conversations in the history, newest first, represented by their latest entry
+#[tauri::command]
+pub fn history_list(
+    app: tauri::State<'_, Arc<Instruct>>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Vec<HistoryEntry>, &'static str> {
+    app.history()
+        .list(offset.unwrap_or(0), limit.unwrap_or(50))
+        .map_err(|e| {
+            error!("history_list: error: {e:?}");
+            "error reading history"
+        })
+}
+
+/// Returns all the entries of a conversation, to reload it
+#[tauri::command]
+pub fn history_conversation(
+    app: tauri::State<'_, Arc<Instruct>>,
+    id: String,
+) -> Result<Vec<HistoryEntry>, &'static str> {
+    app.history().conversation(&id).map_err(|e| {
+        error!("history_conversation: error: {e:?}");
+        "error reading history"
+    })
+}
+
+/// Searches the history for `query`
+#[tauri::command]
+pub fn history_search(
+    app: tauri::State<'_, Arc<Instruct>>,
+    query: String,
+) -> Result<Vec<HistoryEntry>, &'static str> {
+    app.history().search(&query).map_err(|e| {
+        error!("history_search: error: {e:?}");
+        "error searching history"
+    })
+}
+
+/// Deletes history entries, `ids` may hold entry or conversation ids
+#[tauri::command]
+pub fn history_delete(
+    app: tauri::State<'_, Arc<Instruct>>,
+    ids: Vec<String>,
+) -> Result<usize, &'static str> {
+    app.history().delete(&ids[..]).map_err(|e| {
+        error!("history_delete: error: {e:?}");
+        "error deleting history"
+    })
+}
+
+/// Exports the history, or a single `conversation`, to `path` - typically picked with the save dialog
+#[tauri::command]
+pub fn history_export(
+    app: tauri::State<'_, Arc<Instruct>>,
+    path: String,
+    format: ExportFormat,
+    conversation: Option<String>,
+) -> Result<(), &'static str> {
+    app.history()
+        .export(Path::new(&path), format, conversation.as_deref())
+        .map_err(|e| {
+            error!("history_export: error: {e:?}");
+            "error exporting history"
+        })
+}
+
+/// Indexes the documents in `paths`, typically files and folders picked with the open dialog
+/// Indexing runs on the scheduler in the background, returns the documents indexed
+#[tauri::command]
+pub async fn documents_ingest(
+    app: tauri::State<'_, Arc<Instruct>>,
+    paths: Vec<PathBuf>,
+) -> Result<Vec<PathBuf>, &'static str> {
+    let ticket = app
+        // only the embedding model, which isn't shared with the other requests
+        .schedule(None, Priority::Background, &[], move |app, cancel| {
+            app.documents().ingest(&paths[..], cancel)
+        })
+        .map_err(|e| {
+            warn!("documents_ingest: rejected: {e}");
+            rejected(&e)
+        })?;
+
+    ticket.await.map_err(|e| {
+        error!("documents_ingest: error: {e:?}");
+        "error indexing documents"
+    })
+}
+
+/// Lists the indexed documents along with their number of chunks
+#[tauri::command]
+pub fn documents_list(app: tauri::State<'_, Arc<Instruct>>) -> Vec<(PathBuf, usize)> {
+    app.documents().index().documents()
+}
+
+/// Removes a document from the index
+#[tauri::command]
+pub fn documents_remove(
+    app: tauri::State<'_, Arc<Instruct>>,
+    path: PathBuf,
+) -> Result<bool, &'static str> {
+    app.documents().index().remove(&path).map_err(|e| {
+        error!("documents_remove: error: {e:?}");
+        "error removing document"
+    })
+}
+
 /// This tauri command would receive a Vec<f32> which represents a chunk of audio being recorded
 /// The chunk will be forwarded through the MPSC channel
 #[tauri::command]
@@ -105,3 +375,56 @@ pub fn audio_chunk(
     }
     Ok(())
 }
+
+/// Holds the embedded HTTP server, if running
+#[derive(Default)]
+pub struct ServerState(pub Mutex<Option<Server>>);
+
+/// Returns the current config
+#[tauri::command]
+pub fn config(app: tauri::State<'_, Arc<Instruct>>) -> Config {
+    app.config()
+}
+
+/// Updates and persists the config, the HTTP server is started or stopped as required
+#[tauri::command]
+pub fn set_config(
+    app: tauri::State<'_, Arc<Instruct>>,
+    server: tauri::State<'_, ServerState>,
+    config: Config,
+) -> Result<(), &'static str> {
+    let prev = app.config();
+
+    if let Err(e) = app.set_config(config.clone()) {
+        error!("set_config: error: {e:?}");
+        return Err("error saving config");
+    }
+
+    if prev.server == config.server {
+        return Ok(());
+    }
+
+    let mut srv = match server.0.lock() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("set_config: error acquiring server lock: {e:?}");
+            return Err("error updating server");
+        }
+    };
+
+    if let Some(s) = srv.take() {
+        s.stop();
+    }
+
+    if config.server.enabled {
+        match Server::start(Arc::clone(&app), &config.server) {
+            Ok(s) => *srv = Some(s),
+            Err(e) => {
+                error!("set_config: error starting server: {e:?}");
+                return Err("error starting server");
+            }
+        }
+    }
+
+    Ok(())
+}
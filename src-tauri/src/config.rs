@@ -0,0 +1,498 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_FILE: &str = "config.json";
+
+/// The user configurable settings of the app, persisted as `config.json` in the app data directory
+/// Every field has a default, so a missing or partial `config.json` is valid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// the persona profiles
+    pub profiles: Vec<Profile>,
+    /// the `name` of the active profile
+    pub profile: String,
+    /// settings for the language model
+    pub llm: LlmConfig,
+    /// settings for the speech recognition model
+    pub asr: AsrConfig,
+    /// how the models use the CPU
+    pub cpu: CpuConfig,
+    /// what to do when a conversation doesn't fit the model's context window
+    pub context: ContextConfig,
+    /// settings for the embedded OpenAI compatible HTTP server
+    pub server: ServerConfig,
+    /// settings for the inference queue
+    pub scheduler: SchedulerConfig,
+    /// settings for the conversation history
+    pub history: HistoryConfig,
+    /// settings for the tools the model can call
+    pub tools: ToolsConfig,
+    /// settings for answering from the user's documents
+    pub documents: DocumentsConfig,
+    /// settings for speaking the answers
+    pub tts: TtsConfig,
+    /// settings for the always-listening mode
+    pub wake: WakeConfig,
+    /// settings for telling the speakers of a recording apart
+    pub diarization: DiarizationConfig,
+    /// the clean up of the recorded audio before transcription
+    pub dsp: DspConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let profiles = Profile::builtin();
+
+        Self {
+            profile: profiles[0].name.clone(),
+            profiles,
+            llm: LlmConfig::default(),
+            asr: AsrConfig::default(),
+            cpu: CpuConfig::default(),
+            context: ContextConfig::default(),
+            server: ServerConfig::default(),
+            scheduler: SchedulerConfig::default(),
+            history: HistoryConfig::default(),
+            tools: ToolsConfig::default(),
+            documents: DocumentsConfig::default(),
+            tts: TtsConfig::default(),
+            wake: WakeConfig::default(),
+            diarization: DiarizationConfig::default(),
+            dsp: DspConfig::default(),
+        }
+    }
+}
+
+/// A persona: a system prompt with its own sampling defaults
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    pub system_prompt: String,
+    pub temperature: f64,
+    pub top_p: f64,
+    pub top_k: usize,
+    /// max number of tokens to generate
+    pub max_tokens: usize,
+    /// a fixed seed makes the responses reproducible, a random one is used otherwise
+    pub seed: Option<u64>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: "concise".to_string(),
+            system_prompt: "You are a knowledgeable, efficient, intelligent and direct AI assistant. Provide concise answers, focusing on the key information needed. Respond only with the answer to the instruction based on the given data. Do not add any additional text, introduction, context or explanation. If you are unsure about an answer, truthfully return \"Not Known\".".to_string(),
+            temperature: 0.8,
+            top_p: 0.95,
+            top_k: 40,
+            max_tokens: 2048,
+            seed: None,
+        }
+    }
+}
+
+impl Profile {
+    /// The profiles we ship with, the first one is the default
+    pub fn builtin() -> Vec<Self> {
+        vec![
+            Self::default(),
+            Self {
+                name: "explain".to_string(),
+                system_prompt: "You are a patient and knowledgeable assistant. Explain your answers step by step, with examples where they help. When asked about code, walk through what it does and why.".to_string(),
+                temperature: 0.6,
+                ..Self::default()
+            },
+            Self {
+                name: "writer".to_string(),
+                system_prompt: "You are a skilled writing assistant. Draft clear, well structured text such as emails, messages and summaries in the tone the user asks for. Respond with the draft only.".to_string(),
+                temperature: 0.9,
+                ..Self::default()
+            },
+        ]
+    }
+}
+
+/// Settings for the language model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// the Hugging Face repo of the model
+    pub repo: String,
+    /// the quantized `gguf` in `repo`
+    pub file: String,
+    /// a Hugging Face repo to take `tokenizer.json` from, instead of the tokenizer embedded in the `gguf`
+    /// the override is rejected if its vocab doesn't match the model's
+    pub tokenizer_repo: Option<String>,
+    /// the model is freed after this many seconds without a request, and loaded again on the next one, 0 keeps it loaded
+    pub idle_secs: u64,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            repo: "QuantFactory/Meta-Llama-3-8B-Instruct-GGUF".to_string(),
+            file: "Meta-Llama-3-8B-Instruct.Q8_0.gguf".to_string(),
+            tokenizer_repo: None,
+            idle_secs: 0,
+        }
+    }
+}
+
+/// The `whisper` variants, larger ones are more accurate and slower
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhisperModel {
+    Tiny,
+    Base,
+    Small,
+    Medium,
+    Large,
+    /// `distil-large-v3`, close to `large` at a fraction of the decoding cost
+    #[default]
+    DistilLarge,
+}
+
+/// The floating point precisions a model can run in, half precision halves the memory of the weights
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dtype {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+/// Settings for the speech recognition model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AsrConfig {
+    pub model: WhisperModel,
+    /// use the quantized `gguf` weights of `model`, only published for `tiny`
+    pub quantized: bool,
+    /// the language spoken, a code such as `en` or `de` the model's tokenizer knows
+    pub language: String,
+    /// the precision the model runs in, where the device supports it, the quantized weights always run in `f32`
+    pub dtype: Dtype,
+    /// the model is freed after this many seconds without a recording or transcription, 0 keeps it loaded
+    pub idle_secs: u64,
+}
+
+impl AsrConfig {
+    /// The variant, e.g. `small` or `tiny-q80`
+    pub fn name(&self) -> String {
+        let model = match self.model {
+            WhisperModel::Tiny => "tiny",
+            WhisperModel::Base => "base",
+            WhisperModel::Small => "small",
+            WhisperModel::Medium => "medium",
+            WhisperModel::Large => "large",
+            WhisperModel::DistilLarge => "distil_large",
+        };
+
+        if self.quantized {
+            format!("{model}-q80")
+        } else {
+            model.to_string()
+        }
+    }
+}
+
+impl Default for AsrConfig {
+    fn default() -> Self {
+        Self {
+            model: WhisperModel::default(),
+            quantized: false,
+            language: "en".to_string(),
+            dtype: Dtype::default(),
+            idle_secs: 0,
+        }
+    }
+}
+
+/// Settings for running the models on the CPU
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CpuConfig {
+    /// the number of threads the models compute with, 0 uses every core, applies on the next start
+    pub threads: usize,
+    /// map the model files while the weights are loaded, instead of reading them in memory first
+    pub mmap: bool,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            mmap: true,
+        }
+    }
+}
+
+/// How a conversation is shortened to fit the context window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// drop the earliest turns first
+    #[default]
+    TruncateOldest,
+    /// drop the turns in the middle, keeping how the conversation started and where it is now
+    MiddleOut,
+    /// replace the earlier turns with a summary written by the model
+    Summarize,
+}
+
+/// Settings for fitting conversations in the context window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextConfig {
+    pub strategy: ContextStrategy,
+    /// the generation budget is cut down to this before the conversation is shortened
+    pub min_new_tokens: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            strategy: ContextStrategy::default(),
+            min_new_tokens: 512,
+        }
+    }
+}
+
+/// Settings for the embedded OpenAI compatible HTTP server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// the server is off unless explicitly enabled
+    pub enabled: bool,
+    /// the port to listen on, the server is always bound to `127.0.0.1`
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8765,
+        }
+    }
+}
+
+/// Settings for the inference queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// max number of requests waiting to be processed, new requests are rejected beyond this
+    pub capacity: usize,
+    /// number of dedicated inference threads, e.g. with `2` a transcription can run while text is being generated
+    pub workers: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 8,
+            workers: 2,
+        }
+    }
+}
+
+/// Settings for the conversation history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// record every question and answer to `history.jsonl`
+    pub enabled: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Settings for the tools the model can call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolsConfig {
+    /// offer the tools to the model for `text` and `audio` instructions
+    pub enabled: bool,
+    /// max number of tool calls for a single instruction, the model answers with what it has after that
+    pub max_calls: usize,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_calls: 3,
+        }
+    }
+}
+
+/// Settings for answering from the user's documents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocumentsConfig {
+    /// add the relevant excerpts of the indexed documents to `text` and `audio` instructions
+    pub enabled: bool,
+    /// max number of excerpts per instruction
+    pub top_k: usize,
+    /// excerpts less similar than this to the instruction are left out, between `-1` and `1`
+    pub min_score: f32,
+    /// the embedding model is freed after this many seconds without use, 0 keeps it loaded
+    pub idle_secs: u64,
+}
+
+impl Default for DocumentsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            top_k: 4,
+            min_score: 0.3,
+            idle_secs: 0,
+        }
+    }
+}
+
+/// The pace of the spoken answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speed {
+    Slow,
+    Normal,
+    Fast,
+}
+
+/// Settings for speaking the answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TtsConfig {
+    /// speak the answers to `text` and `audio` instructions, the speech model is downloaded on first use
+    pub enabled: bool,
+    /// one of `tts::VOICES`
+    pub voice: String,
+    pub speed: Speed,
+    /// the model is freed after this many seconds without speaking, and loaded again on the next answer, 0 keeps it loaded
+    pub idle_secs: u64,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            voice: "Jon".to_string(),
+            speed: Speed::Normal,
+            idle_secs: 0,
+        }
+    }
+}
+
+/// Settings for the always-listening mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WakeConfig {
+    /// the recorded audio is only kept once the wake phrase is spotted in it, see `Instruct::enroll_wake_phrase()`
+    pub enabled: bool,
+    /// between `0` and `1`, higher spots the wake phrase more readily at the risk of false alarms
+    pub sensitivity: f32,
+    /// max length of the instruction following the wake phrase
+    pub max_secs: usize,
+}
+
+impl Default for WakeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sensitivity: 0.5,
+            max_secs: 15,
+        }
+    }
+}
+
+/// Settings for telling the speakers of a recording apart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiarizationConfig {
+    /// segments whose voices are closer than this cosine distance are taken for the same speaker
+    /// used when the number of speakers isn't known, raise it if one speaker is split in several
+    pub threshold: f32,
+    /// the speaker embedding model is freed after this many seconds without use, 0 keeps it loaded
+    pub idle_secs: u64,
+}
+
+impl Default for DiarizationConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.7,
+            idle_secs: 0,
+        }
+    }
+}
+
+/// The stages of the clean up of the recorded audio before transcription, see `dsp::process()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DspConfig {
+    /// remove the DC offset and the rumble below 80 Hz
+    pub highpass: bool,
+    /// bring quiet recordings up to a consistent level
+    /// runs once the recording is over, which turns off computing its features while recording, see `ahead()`
+    pub normalize: bool,
+    /// subtract the steady noise heard in the pauses of the recording, once it's over as well
+    pub denoise: bool,
+}
+
+impl DspConfig {
+    /// Whether the recording reaches `whisper` as it was streamed in, so that its features can be computed ahead
+    pub fn ahead(&self) -> bool {
+        !self.normalize && !self.denoise
+    }
+}
+
+impl Default for DspConfig {
+    fn default() -> Self {
+        Self {
+            highpass: true,
+            normalize: false,
+            denoise: false,
+        }
+    }
+}
+
+impl Config {
+    fn path(dir: &Path) -> PathBuf {
+        dir.join(CONFIG_FILE)
+    }
+
+    /// Loads the config from `dir`, falls back to the defaults if none exists yet
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// The active profile, falls back to the default profile if the active one is missing
+    pub fn active_profile(&self) -> Profile {
+        self.profiles
+            .iter()
+            .find(|p| p.name == self.profile)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Persists the config to `dir`
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(Self::path(dir), serde_json::to_string_pretty(self)?)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! Speaker diarization: who spoke when in a recording with several speakers
+//! The speech is split at the pauses, every segment is embedded with the `ecapa` speaker model and the embeddings are
+//! clustered by speaker, the transcripts of the segments are then labelled `Speaker 1`, `Speaker 2` ...
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::SAMPLE_RATE;
+
+/// The instruction to summarize a labelled transcript with
+pub const SUMMARY_PROMPT: &str = "Below is the transcript of a conversation between several speakers. Summarize it in a few sentences, then list the action items, if any, along with the speaker responsible for each.";
+
+/// The loudness is measured over frames of 30 ms
+const FRAME: usize = SAMPLE_RATE as usize * 3 / 100;
+/// A pause of 0.5 s ends a segment
+const PAUSE_FRAMES: usize = 17;
+/// Segments shorter than 0.5 s are too short to tell the speaker
+const MIN_SEGMENT_FRAMES: usize = 17;
+/// Longer stretches of speech are split into segments of at most 10 s, the speaker may change without a pause
+const MAX_SEGMENT_FRAMES: usize = 333;
+/// Frames quieter than this (RMS) are always silence, quieter than a few times the noise floor otherwise
+const SILENCE_RMS: f32 = 0.01;
+const NOISE_FLOOR_FACTOR: f32 = 3.;
+
+/// The speaker and transcript of a stretch of speech
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Turn {
+    /// 1 based, in order of first appearance
+    pub speaker: usize,
+    /// in seconds from the start of the recording
+    pub start: f32,
+    pub end: f32,
+    pub text: String,
+}
+
+/// A transcript labelled by speaker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diarization {
+    pub turns: Vec<Turn>,
+    /// the number of speakers told apart
+    pub speakers: usize,
+    /// a summary and the action items, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+impl Diarization {
+    /// Merges consecutive segments of the same speaker into turns
+    /// `speakers` are the 0 based cluster of each of `segments` of a recording, `texts` their transcripts
+    pub fn new(segments: &[Range<usize>], speakers: &[usize], texts: Vec<String>) -> Self {
+        let secs = |s: usize| s as f32 / SAMPLE_RATE as f32;
+
+        let mut turns: Vec<Turn> = vec![];
+        for ((seg, &speaker), text) in segments.iter().zip(speakers.iter()).zip(texts) {
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            match turns.last_mut() {
+                Some(t) if t.speaker == speaker + 1 => {
+                    t.end = secs(seg.end);
+                    t.text = format!("{} {text}", t.text);
+                }
+                _ => turns.push(Turn {
+                    speaker: speaker + 1,
+                    start: secs(seg.start),
+                    end: secs(seg.end),
+                    text: text.to_string(),
+                }),
+            }
+        }
+
+        Self {
+            speakers: speakers.iter().max().map_or(0, |s| s + 1),
+            turns,
+            summary: None,
+        }
+    }
+
+    /// The transcript with a `Speaker N:` label for every turn
+    pub fn transcript(&self) -> String {
+        self.turns
+            .iter()
+            .map(|t| format!("Speaker {}: {}", t.speaker, t.text))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The ranges of `pcm` with speech, mono @ 16 kHz, split at the pauses
+pub fn segments(pcm: &[f32]) -> Vec<Range<usize>> {
+    let rms = pcm
+        .chunks(FRAME)
+        .map(|f| (f.iter().map(|s| s * s).sum::<f32>() / f.len() as f32).sqrt())
+        .collect::<Vec<_>>();
+
+    // the quietest tenth of the recording is taken for the noise
+    let mut sorted = rms.clone();
+    sorted.sort_by(f32::total_cmp);
+    let floor = sorted.get(sorted.len() / 10).copied().unwrap_or_default();
+    let threshold = SILENCE_RMS.max(floor * NOISE_FLOOR_FACTOR);
+
+    // runs of voiced frames, bridging pauses shorter than `PAUSE_FRAMES`
+    let mut runs: Vec<Range<usize>> = vec![];
+    for (i, _) in rms.iter().enumerate().filter(|(_, r)| **r >= threshold) {
+        match runs.last_mut() {
+            Some(r) if i - r.end < PAUSE_FRAMES => r.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+
+    runs.into_iter()
+        .filter(|r| r.len() >= MIN_SEGMENT_FRAMES)
+        .flat_map(|r| {
+            // evenly, so that the last piece isn't too short
+            let n = r.len().div_ceil(MAX_SEGMENT_FRAMES);
+            let len = r.len().div_ceil(n);
+            (0..n).map(move |i| r.start + i * len..(r.start + (i + 1) * len).min(r.end))
+        })
+        .map(|r| r.start * FRAME..(r.end * FRAME).min(pcm.len()))
+        .collect()
+}
+
+/// Groups `embeddings` by speaker with agglomerative clustering, average linkage on the cosine distance
+/// With a known number of `speakers` the closest clusters are merged down to that, clusters closer than `threshold` otherwise
+/// Returns the 0 based speaker of every embedding, numbered in order of first appearance
+pub fn cluster(embeddings: &[Vec<f32>], threshold: f32, speakers: Option<usize>) -> Vec<usize> {
+    let unit = embeddings
+        .iter()
+        .map(|e| {
+            let norm = e
+                .iter()
+                .map(|v| v * v)
+                .sum::<f32>()
+                .sqrt()
+                .max(f32::EPSILON);
+            e.iter().map(|v| v / norm).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let distance = |a: usize, b: usize| {
+        1. - unit[a]
+            .iter()
+            .zip(unit[b].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>()
+    };
+
+    let mut clusters = (0..unit.len()).map(|i| vec![i]).collect::<Vec<_>>();
+    while clusters.len() > 1 {
+        // the closest pair of clusters
+        let mut closest = (f32::INFINITY, 0, 0);
+        for a in 0..clusters.len() {
+            for b in a + 1..clusters.len() {
+                let d = clusters[a]
+                    .iter()
+                    .flat_map(|&i| clusters[b].iter().map(move |&j| (i, j)))
+                    .map(|(i, j)| distance(i, j))
+                    .sum::<f32>()
+                    / (clusters[a].len() * clusters[b].len()) as f32;
+                if d < closest.0 {
+                    closest = (d, a, b);
+                }
+            }
+        }
+
+        let (d, a, b) = closest;
+        let merge = match speakers {
+            Some(n) => clusters.len() > n.max(1),
+            None => d <= threshold,
+        };
+        if !merge {
+            break;
+        }
+        let b = clusters.remove(b);
+        clusters[a].extend(b);
+    }
+
+    // number the speakers in order of first appearance
+    clusters.sort_by_key(|c| c.iter().min().copied());
+    let mut labels = vec![0; unit.len()];
+    for (speaker, c) in clusters.iter().enumerate() {
+        c.iter().for_each(|&i| labels[i] = speaker);
+    }
+
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cluster, segments, Diarization, FRAME};
+
+    #[test]
+    fn segments_at_pauses() {
+        let tone = |secs: f32| {
+            (0..(secs * 16_000.) as usize)
+                .map(|i| (i as f32 * 0.1).sin() * 0.2)
+                .collect::<Vec<_>>()
+        };
+        let silence = |secs: f32| vec![0.; (secs * 16_000.) as usize];
+
+        let pcm = [
+            silence(1.),
+            tone(2.),
+            // too short a pause to split at
+            silence(0.2),
+            tone(1.),
+            silence(1.),
+            // too short to be a segment
+            tone(0.2),
+            silence(1.),
+            tone(25.),
+        ]
+        .concat();
+        let segs = segments(&pcm);
+
+        assert_eq!(segs.len(), 4);
+        let secs = |s: usize| s as f32 / 16_000.;
+        assert!((secs(segs[0].start) - 1.).abs() < 0.05);
+        assert!((secs(segs[0].end) - 4.2).abs() < 0.05);
+        // 25 s is split in 3 even pieces
+        assert!(segs[1..]
+            .iter()
+            .all(|s| (s.len() as f32 / 16_000. - 25. / 3.).abs() < 0.1));
+        assert_eq!(segs[1].end, segs[2].start);
+        assert!(segs.iter().all(|s| s.start % FRAME == 0));
+    }
+
+    #[test]
+    fn clusters() {
+        let embeddings = vec![
+            vec![1., 0.1, 0.],
+            vec![0., 1., 0.1],
+            vec![0.9, 0.2, 0.],
+            vec![0.1, 0.9, 0.],
+            vec![0., 0.6, 1.],
+        ];
+
+        assert_eq!(cluster(&embeddings, 0.1, None), [0, 1, 0, 1, 2]);
+        assert_eq!(cluster(&embeddings, 2., None), [0, 0, 0, 0, 0]);
+        assert_eq!(cluster(&embeddings, 0., Some(2)), [0, 1, 0, 1, 1]);
+        assert!(cluster(&[], 0.1, None).is_empty());
+    }
+
+    #[test]
+    fn clusters_voices() {
+        // speaker embeddings are 192-d, the segments of a speaker spread around their voice
+        let random = || {
+            (0..192)
+                .map(|_| rand::random::<f32>() - 0.5)
+                .collect::<Vec<_>>()
+        };
+        let voices = [random(), random(), random()];
+        let order = [0, 1, 0, 2, 1, 1, 0, 2];
+        let embeddings = order
+            .iter()
+            .map(|&v| {
+                voices[v]
+                    .iter()
+                    .zip(random())
+                    .map(|(v, n)| v + n * 0.7)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let threshold = crate::config::DiarizationConfig::default().threshold;
+        assert_eq!(cluster(&embeddings, threshold, None), order);
+        assert_eq!(cluster(&embeddings, threshold, Some(3)), order);
+        assert_eq!(
+            cluster(&embeddings, threshold, Some(2))
+                .iter()
+                .filter(|&&s| s > 1)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn turns() {
+        let segments = [0..16_000, 16_000..32_000, 48_000..64_000, 64_000..80_000];
+        let texts = ["Hi.", " How are you?", "Good.", ""]
+            .map(String::from)
+            .to_vec();
+        let d = Diarization::new(&segments, &[0, 0, 1, 0], texts);
+
+        assert_eq!(d.speakers, 2);
+        assert_eq!(d.turns.len(), 2);
+        assert_eq!(d.turns[0].end, 2.);
+        assert_eq!(
+            d.transcript(),
+            "Speaker 1: Hi. How are you?\nSpeaker 2: Good."
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! Clean up of the recorded audio before it reaches `whisper`
+//! Laptop mics give a DC offset and low frequency rumble, are often too quiet and pick up the fans and the office
+//! Each stage is toggled in `config.json`, `process()` runs the enabled ones in order and times them
+//! The high-pass also runs on the recording as it streams in, `process_recorded()` runs the rest afterwards
+
+use std::{
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{config::DspConfig, utils::SAMPLE_RATE};
+
+/// Rumble, handling noise and mains hum are below this, speech is above it
+const HIGHPASS_HZ: f32 = 80.;
+/// Speech is normalized to an RMS of -20 dBFS, unless that pushes the peak over -0.5 dBFS
+const TARGET_RMS: f32 = 0.1;
+const MAX_PEAK: f32 = 0.944;
+/// Quieter audio is left alone rather than amplifying the noise
+const MIN_RMS: f32 = 1e-4;
+/// Spectral subtraction works on frames of 32 ms, overlapping by half
+const FRAME: usize = 512;
+const HOP: usize = FRAME / 2;
+/// The noise is estimated from the frames at most 3 dB louder than the quietest tenth of the recording
+const NOISE_MARGIN: f32 = 2.;
+/// There's no telling the noise from the speech unless the loudest tenth is 10 dB louder than the quietest
+const MIN_SNR: f32 = 10.;
+/// At least 250 ms of noise are needed for an estimate, the audio is left as is otherwise
+const MIN_NOISE_FRAMES: usize = 15;
+/// The noise estimate is over subtracted, leaving at least a fraction of the original magnitude to avoid musical noise
+const OVER_SUBTRACTION: f32 = 2.;
+const SPECTRAL_FLOOR: f32 = 0.05;
+
+/// The time each stage of `process()` took, `None` for the disabled ones
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Timings {
+    pub highpass: Option<Duration>,
+    pub normalize: Option<Duration>,
+    pub denoise: Option<Duration>,
+}
+
+/// Runs the stages enabled in `config` on `pcm`, mono @ 16 kHz
+/// Returns the processed audio and the time taken by each stage
+pub fn process(pcm: &[f32], config: &DspConfig) -> (Vec<f32>, Timings) {
+    let mut pcm = pcm.to_vec();
+    let mut highpass = None;
+    if config.highpass {
+        highpass = timed(&mut || Highpass::new(HIGHPASS_HZ).process(&mut pcm[..]));
+    }
+
+    let (pcm, mut timings) = process_recorded(&pcm[..], config);
+    timings.highpass = highpass;
+
+    (pcm, timings)
+}
+
+/// Runs the stages enabled in `config` that need the whole of `pcm`, the high-pass was applied while recording it
+pub fn process_recorded(pcm: &[f32], config: &DspConfig) -> (Vec<f32>, Timings) {
+    let mut pcm = pcm.to_vec();
+    let mut timings = Timings::default();
+
+    // noise is removed before normalizing, so that it isn't amplified along with the speech
+    if config.denoise {
+        timings.denoise = timed(&mut || pcm = denoise(&pcm[..]));
+    }
+    if config.normalize {
+        timings.normalize = timed(&mut || normalize(&mut pcm[..]));
+    }
+
+    (pcm, timings)
+}
+
+fn timed(f: &mut dyn FnMut()) -> Option<Duration> {
+    let start = Instant::now();
+    f();
+    Some(start.elapsed())
+}
+
+/// A 2nd order Butterworth high-pass filter, the biquad from the Audio EQ Cookbook
+/// It keeps its state between calls to `process()`, filtering a stream chunk by chunk is the same as filtering it whole
+/// The DC offset is removed along with the rumble, the filter doesn't pass 0 Hz
+pub struct Highpass {
+    b: [f32; 3],
+    a: [f32; 2],
+    // the last two inputs and outputs
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Highpass {
+    /// A filter at `cutoff` Hz
+    pub fn new(cutoff: f32) -> Self {
+        let w0 = 2. * PI * cutoff / SAMPLE_RATE as f32;
+        let alpha = w0.sin() / 2_f32.sqrt();
+        let cos = w0.cos();
+
+        let a0 = 1. + alpha;
+        Self {
+            b: [(1. + cos) / 2. / a0, -(1. + cos) / a0, (1. + cos) / 2. / a0],
+            a: [-2. * cos / a0, (1. - alpha) / a0],
+            x: [0.; 2],
+            y: [0.; 2],
+        }
+    }
+
+    /// Filters `pcm` in place, continuing from the previous chunk
+    pub fn process(&mut self, pcm: &mut [f32]) {
+        let ([b0, b1, b2], [a1, a2]) = (self.b, self.a);
+        let ([mut x1, mut x2], [mut y1, mut y2]) = (self.x, self.y);
+        for s in pcm.iter_mut() {
+            let x = *s;
+            let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+            (x2, x1, y2, y1) = (x1, x, y1, y);
+            *s = y;
+        }
+        (self.x, self.y) = ([x1, x2], [y1, y2]);
+    }
+}
+
+impl Default for Highpass {
+    fn default() -> Self {
+        Self::new(HIGHPASS_HZ)
+    }
+}
+
+/// Scales `pcm` to an RMS of `TARGET_RMS`, less if that would push the peak over `MAX_PEAK`
+pub fn normalize(pcm: &mut [f32]) {
+    let rms = (pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len().max(1) as f32).sqrt();
+    let peak = pcm.iter().fold(0f32, |m, s| m.max(s.abs()));
+    if rms < MIN_RMS {
+        return;
+    }
+
+    let gain = (TARGET_RMS / rms).min(MAX_PEAK / peak);
+    pcm.iter_mut().for_each(|s| *s *= gain);
+}
+
+/// Spectral subtraction: the average magnitude spectrum of the quiet frames, wherever they are, is subtracted from every frame
+/// The audio is returned as is if there are too few quiet frames to estimate the noise from
+pub fn denoise(pcm: &[f32]) -> Vec<f32> {
+    if pcm.len() < (MIN_NOISE_FRAMES + 1) * HOP + FRAME {
+        return pcm.to_vec();
+    }
+
+    // a periodic Hann window at 50% overlap adds up to 1, no synthesis window is needed
+    let window = (0..FRAME)
+        .map(|i| 0.5 * (1. - (2. * PI * i as f32 / FRAME as f32).cos()))
+        .collect::<Vec<_>>();
+    // the last frame is zero padded
+    let n_frames = (pcm.len() - FRAME).div_ceil(HOP) + 1;
+    let spectrum = |i: usize| {
+        let mut buf = (0..FRAME)
+            .map(|j| {
+                (
+                    pcm.get(i * HOP + j).copied().unwrap_or_default() * window[j],
+                    0.,
+                )
+            })
+            .collect::<Vec<_>>();
+        fft(&mut buf[..], false);
+        buf
+    };
+
+    let energy = (0..n_frames)
+        .map(|i| {
+            (0..FRAME)
+                .map(|j| (pcm.get(i * HOP + j).copied().unwrap_or_default() * window[j]).powi(2))
+                .sum::<f32>()
+        })
+        .collect::<Vec<_>>();
+    let mut sorted = energy.clone();
+    sorted.sort_by(f32::total_cmp);
+    let (quiet, loud) = (sorted[n_frames / 10], sorted[n_frames * 9 / 10]);
+    if loud < quiet * 10f32.powf(MIN_SNR / 10.) {
+        info!("denoise: no pauses to estimate the noise from, skipped");
+        return pcm.to_vec();
+    }
+
+    let quiet = (0..n_frames)
+        .filter(|&i| energy[i] <= quiet * NOISE_MARGIN)
+        .collect::<Vec<_>>();
+    if quiet.len() < MIN_NOISE_FRAMES {
+        info!(
+            "denoise: {} quiet frames are too few to estimate the noise from, skipped",
+            quiet.len()
+        );
+        return pcm.to_vec();
+    }
+
+    let mut noise = vec![0f32; FRAME];
+    for &i in quiet.iter() {
+        for (n, (re, im)) in noise.iter_mut().zip(spectrum(i)) {
+            *n += (re * re + im * im).sqrt() / quiet.len() as f32;
+        }
+    }
+
+    let mut out = vec![0f32; (n_frames - 1) * HOP + FRAME];
+    for i in 0..n_frames {
+        let mut buf = spectrum(i);
+        for ((re, im), n) in buf.iter_mut().zip(noise.iter()) {
+            let mag = (*re * *re + *im * *im).sqrt();
+            if mag > f32::EPSILON {
+                // the phase is kept, only the magnitude is reduced
+                let gain = (mag - OVER_SUBTRACTION * n).max(SPECTRAL_FLOOR * mag) / mag;
+                *re *= gain;
+                *im *= gain;
+            }
+        }
+        fft(&mut buf[..], true);
+
+        out[i * HOP..i * HOP + FRAME]
+            .iter_mut()
+            .zip(buf.iter())
+            .for_each(|(o, (re, _))| *o += re);
+    }
+    out.truncate(pcm.len());
+
+    out
+}
+
+// an in place iterative radix 2 FFT of (real, imaginary) pairs, `buf.len()` must be a power of 2
+fn fft(buf: &mut [(f32, f32)], inverse: bool) {
+    let n = buf.len();
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1. } else { -1. };
+    let mut len = 2;
+    while len <= n {
+        let theta = sign * 2. * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (wr, wi) = ((theta * k as f32).cos(), (theta * k as f32).sin());
+                let (ar, ai) = buf[start + k];
+                let (br, bi) = buf[start + k + len / 2];
+                let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                buf[start + k] = (ar + tr, ai + ti);
+                buf[start + k + len / 2] = (ar - tr, ai - ti);
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        buf.iter_mut().for_each(|(re, im)| {
+            *re /= n as f32;
+            *im /= n as f32;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{denoise, normalize, Highpass, MAX_PEAK, TARGET_RMS};
+
+    fn tone(hz: f32, amp: f32, n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| (2. * PI * hz * i as f32 / 16_000.).sin() * amp)
+            .collect()
+    }
+
+    fn rms(pcm: &[f32]) -> f32 {
+        (pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32).sqrt()
+    }
+
+    // a deterministic stand in for white noise
+    fn noise(amp: f32, n: usize) -> Vec<f32> {
+        let mut x = 12345u32;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                (x as f32 / u32::MAX as f32 * 2. - 1.) * amp
+            })
+            .collect()
+    }
+
+    #[test]
+    fn highpass_filter() {
+        let mut pcm = tone(20., 0.3, 16_000)
+            .iter()
+            .zip(tone(1000., 0.3, 16_000))
+            .map(|(a, b)| a + b + 0.2)
+            .collect::<Vec<_>>();
+        let mut streamed = pcm.clone();
+        Highpass::new(80.).process(&mut pcm[..]);
+
+        // what's left after the filter settles is the 1 kHz tone
+        let settled = &pcm[4_000..];
+        assert!(settled.iter().sum::<f32>().abs() / (settled.len() as f32) < 1e-3);
+        assert!((rms(settled) - 0.3 / 2_f32.sqrt()).abs() < 0.01);
+
+        // chunk by chunk, as it's recorded
+        let mut filter = Highpass::new(80.);
+        streamed.chunks_mut(1_234).for_each(|c| filter.process(c));
+        assert_eq!(streamed, pcm);
+    }
+
+    #[test]
+    fn normalization() {
+        let mut quiet = tone(440., 0.01, 16_000);
+        normalize(&mut quiet[..]);
+        assert!((rms(&quiet) - TARGET_RMS).abs() < 1e-3);
+
+        // a loud click limits the gain
+        let mut clicky = tone(440., 0.01, 16_000);
+        clicky[100] = 0.5;
+        normalize(&mut clicky[..]);
+        assert!((clicky[100] - MAX_PEAK).abs() < 1e-4);
+
+        let mut silence = vec![0.; 1_000];
+        normalize(&mut silence[..]);
+        assert!(silence.iter().all(|s| *s == 0.));
+    }
+
+    #[test]
+    fn noise_from_the_pauses() {
+        let n = 48_000;
+        let hiss = noise(0.02, n);
+        // the user speaks right away, pausing in the middle
+        let signal = (0..n)
+            .map(|i| match (16_000..24_000).contains(&i) {
+                true => 0.,
+                false => (2. * PI * 440. * i as f32 / 16_000.).sin() * 0.3,
+            })
+            .collect::<Vec<_>>();
+        let pcm = hiss
+            .iter()
+            .zip(signal.iter())
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+
+        let out = denoise(&pcm[..]);
+        assert!(rms(&out[17_000..23_000]) < rms(&pcm[17_000..23_000]) * 0.3);
+        // the start of the speech isn't taken for noise and subtracted
+        assert!((rms(&out[1_000..15_000]) - rms(&signal[1_000..15_000])).abs() < 0.02);
+
+        // without a pause there's nothing to estimate the noise from
+        let speech = tone(440., 0.3, n)
+            .iter()
+            .zip(hiss.iter())
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+        assert_eq!(denoise(&speech[..]), speech);
+    }
+
+    #[test]
+    fn spectral_subtraction() {
+        let n = 32_000;
+        let hiss = noise(0.05, n);
+        // half a second of noise, then the tone over it
+        let signal = (0..n)
+            .map(|i| {
+                if i < 8_000 {
+                    0.
+                } else {
+                    (2. * PI * 440. * i as f32 / 16_000.).sin() * 0.2
+                }
+            })
+            .collect::<Vec<_>>();
+        let pcm = hiss
+            .iter()
+            .zip(signal.iter())
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+
+        let out = denoise(&pcm[..]);
+        assert_eq!(out.len(), pcm.len());
+
+        // the noise is much quieter, the tone is still there
+        assert!(rms(&out[1_000..7_000]) < rms(&pcm[1_000..7_000]) * 0.3);
+        let err = out[10_000..30_000]
+            .iter()
+            .zip(signal[10_000..30_000].iter())
+            .map(|(o, s)| o - s)
+            .collect::<Vec<_>>();
+        assert!(rms(&err) < rms(&hiss[10_000..30_000]) * 0.6);
+    }
+}
@@ -0,0 +1,539 @@
+//! Speaker embeddings with ECAPA-TDNN, as trained on VoxCeleb by `speechbrain/spkrec-ecapa-voxceleb`
+//! A recording is turned into log mel filterbank features and encoded to a single vector, close for the same voice
+
+use std::{f32::consts::PI, path::Path};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{batch_norm, conv1d, ops, BatchNorm, Conv1d, Conv1dConfig, VarBuilder};
+
+use crate::{
+    mel::fft,
+    utils::{device, hf_download, SAMPLE_RATE},
+};
+
+pub const MODEL_REPO: &str = "speechbrain/spkrec-ecapa-voxceleb";
+const MODEL_FILE: &str = "embedding_model.ckpt";
+const LOCAL_MODEL: &str = "ecapa-voxceleb.ckpt";
+
+/// 25 ms windows every 10 ms
+const N_FFT: usize = 400;
+const HOP: usize = 160;
+/// The loudness range kept, in dB below the loudest bin of the recording
+const TOP_DB: f32 = 80.;
+const AMIN: f32 = 1e-10;
+/// The kernel size and dilation of the `SE-Res2Net` blocks
+const BLOCKS: [(usize, usize); 3] = [(3, 2), (3, 3), (3, 4)];
+const BN_EPS: f64 = 1e-5;
+
+/// The sizes of the layers, `VOXCELEB` is the released model
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub n_mels: usize,
+    /// the channels of the `TDNN` and `SE-Res2Net` blocks
+    pub channels: usize,
+    /// the channels the outputs of the blocks are aggregated to
+    pub mfa_channels: usize,
+    /// the number of groups a `Res2Net` block splits its channels in
+    pub scale: usize,
+    pub se_channels: usize,
+    pub attention_channels: usize,
+    /// the length of an embedding
+    pub embedding: usize,
+}
+
+pub const VOXCELEB: Config = Config {
+    n_mels: 80,
+    channels: 1024,
+    mfa_channels: 3072,
+    scale: 8,
+    se_channels: 128,
+    attention_channels: 128,
+    embedding: 192,
+};
+
+// pads the last dim by mirroring `pad` values on either side, the edges left out as `torch` does
+fn reflect(x: &Tensor, pad: usize) -> Result<Tensor> {
+    if pad == 0 {
+        return Ok(x.clone());
+    }
+    let len = x.dim(D::Minus1)?;
+    if len <= pad {
+        anyhow::bail!("{len} frames are too few to pad by {pad}");
+    }
+
+    let idx = (1..=pad)
+        .rev()
+        .chain(0..len)
+        .chain((len - 1 - pad..len - 1).rev())
+        .map(|i| i as u32)
+        .collect::<Vec<_>>();
+    let idx = Tensor::new(idx, x.device())?;
+
+    Ok(x.contiguous()?.index_select(&idx, x.rank() - 1)?)
+}
+
+// a dilated convolution keeping the length, followed by a ReLU and a batch norm
+struct Tdnn {
+    conv: Conv1d,
+    norm: BatchNorm,
+    pad: usize,
+}
+
+impl Tdnn {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel: usize,
+        dilation: usize,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let cfg = Conv1dConfig {
+            dilation,
+            ..Default::default()
+        };
+
+        Ok(Self {
+            conv: conv1d(in_channels, out_channels, kernel, cfg, vb.pp("conv.conv"))?,
+            norm: batch_norm(out_channels, BN_EPS, vb.pp("norm.norm"))?,
+            pad: dilation * (kernel - 1) / 2,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        Ok(reflect(x, self.pad)?
+            .apply(&self.conv)?
+            .relu()?
+            .apply_t(&self.norm, false)?)
+    }
+}
+
+// the channels are split in `scale` groups, every group but the first is convolved along with the output of the previous one
+struct Res2Net {
+    blocks: Vec<Tdnn>,
+}
+
+impl Res2Net {
+    fn new(cfg: &Config, kernel: usize, dilation: usize, vb: VarBuilder) -> Result<Self> {
+        let width = cfg.channels / cfg.scale;
+        let blocks = (0..cfg.scale - 1)
+            .map(|i| Tdnn::new(width, width, kernel, dilation, vb.pp(format!("blocks.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { blocks })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let scale = self.blocks.len() + 1;
+        let width = x.dim(1)? / scale;
+
+        let mut out: Vec<Tensor> = Vec::with_capacity(scale);
+        for i in 0..scale {
+            let group = x.narrow(1, i * width, width)?;
+            let y = match i {
+                0 => group,
+                1 => self.blocks[0].forward(&group)?,
+                _ => self.blocks[i - 1].forward(&(group + &out[i - 1])?)?,
+            };
+            out.push(y);
+        }
+
+        Ok(Tensor::cat(&out[..], 1)?)
+    }
+}
+
+// squeeze-excitation: scales the channels by weights computed from their average over time
+struct SqueezeExcite {
+    conv1: Conv1d,
+    conv2: Conv1d,
+}
+
+impl SqueezeExcite {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            conv1: conv1d(
+                cfg.channels,
+                cfg.se_channels,
+                1,
+                Default::default(),
+                vb.pp("conv1.conv"),
+            )?,
+            conv2: conv1d(
+                cfg.se_channels,
+                cfg.channels,
+                1,
+                Default::default(),
+                vb.pp("conv2.conv"),
+            )?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let s = x
+            .mean_keepdim(2)?
+            .apply(&self.conv1)?
+            .relu()?
+            .apply(&self.conv2)?;
+
+        Ok(x.broadcast_mul(&ops::sigmoid(&s)?)?)
+    }
+}
+
+struct SeRes2Net {
+    tdnn1: Tdnn,
+    res2net: Res2Net,
+    tdnn2: Tdnn,
+    se: SqueezeExcite,
+}
+
+impl SeRes2Net {
+    fn new(cfg: &Config, kernel: usize, dilation: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            tdnn1: Tdnn::new(cfg.channels, cfg.channels, 1, 1, vb.pp("tdnn1"))?,
+            res2net: Res2Net::new(cfg, kernel, dilation, vb.pp("res2net_block"))?,
+            tdnn2: Tdnn::new(cfg.channels, cfg.channels, 1, 1, vb.pp("tdnn2"))?,
+            se: SqueezeExcite::new(cfg, vb.pp("se_block"))?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let y = self.tdnn1.forward(x)?;
+        let y = self.res2net.forward(&y)?;
+        let y = self.tdnn2.forward(&y)?;
+        let y = self.se.forward(&y)?;
+
+        Ok((y + x)?)
+    }
+}
+
+// the mean and standard deviation over time of `x`, weighted by `w` summing to 1 over time
+fn statistics(x: &Tensor, w: &Tensor) -> Result<(Tensor, Tensor)> {
+    let mean = x.broadcast_mul(w)?.sum_keepdim(2)?;
+    let std = x
+        .broadcast_sub(&mean)?
+        .sqr()?
+        .broadcast_mul(w)?
+        .sum_keepdim(2)?
+        .clamp(1e-12, f32::MAX)?
+        .sqrt()?;
+
+    Ok((mean, std))
+}
+
+// attentive statistics pooling: the frames are weighted by how much they tell about the speaker
+struct Pooling {
+    tdnn: Tdnn,
+    conv: Conv1d,
+}
+
+impl Pooling {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            tdnn: Tdnn::new(
+                cfg.mfa_channels * 3,
+                cfg.attention_channels,
+                1,
+                1,
+                vb.pp("tdnn"),
+            )?,
+            conv: conv1d(
+                cfg.attention_channels,
+                cfg.mfa_channels,
+                1,
+                Default::default(),
+                vb.pp("conv.conv"),
+            )?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let (b, c, t) = x.dims3()?;
+
+        // the attention sees the statistics of the whole recording along with every frame
+        let uniform = Tensor::full(1. / t as f32, (b, 1, t), x.device())?.to_dtype(x.dtype())?;
+        let (mean, std) = statistics(x, &uniform)?;
+        let context = Tensor::cat(
+            &[
+                x.clone(),
+                mean.broadcast_as((b, c, t))?.contiguous()?,
+                std.broadcast_as((b, c, t))?.contiguous()?,
+            ],
+            1,
+        )?;
+        let attention = self.tdnn.forward(&context)?.tanh()?.apply(&self.conv)?;
+        let attention = ops::softmax(&attention, D::Minus1)?;
+
+        let (mean, std) = statistics(x, &attention)?;
+        Ok(Tensor::cat(&[mean, std], 1)?)
+    }
+}
+
+/// ECAPA-TDNN, log mel features `(batch, n_mels, frames)` in, an embedding `(batch, embedding)` out
+pub struct Ecapa {
+    first: Tdnn,
+    blocks: Vec<SeRes2Net>,
+    mfa: Tdnn,
+    pooling: Pooling,
+    pooling_norm: BatchNorm,
+    fc: Conv1d,
+}
+
+impl Ecapa {
+    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let blocks = BLOCKS
+            .iter()
+            .enumerate()
+            .map(|(i, &(kernel, dilation))| {
+                SeRes2Net::new(cfg, kernel, dilation, vb.pp(format!("blocks.{}", i + 1)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            first: Tdnn::new(cfg.n_mels, cfg.channels, 5, 1, vb.pp("blocks.0"))?,
+            blocks,
+            mfa: Tdnn::new(
+                cfg.channels * BLOCKS.len(),
+                cfg.mfa_channels,
+                1,
+                1,
+                vb.pp("mfa"),
+            )?,
+            pooling: Pooling::new(cfg, vb.pp("asp"))?,
+            pooling_norm: batch_norm(cfg.mfa_channels * 2, BN_EPS, vb.pp("asp_bn.norm"))?,
+            fc: conv1d(
+                cfg.mfa_channels * 2,
+                cfg.embedding,
+                1,
+                Default::default(),
+                vb.pp("fc.conv"),
+            )?,
+        })
+    }
+
+    pub fn forward(&self, features: &Tensor) -> Result<Tensor> {
+        let mut x = self.first.forward(features)?;
+
+        // the outputs of all the `SE-Res2Net` blocks are aggregated
+        let mut outs = Vec::with_capacity(self.blocks.len());
+        for block in self.blocks.iter() {
+            x = block.forward(&x)?;
+            outs.push(x.clone());
+        }
+        let x = self.mfa.forward(&Tensor::cat(&outs[..], 1)?)?;
+
+        let x = self
+            .pooling
+            .forward(&x)?
+            .apply_t(&self.pooling_norm, false)?
+            .apply(&self.fc)?;
+
+        Ok(x.squeeze(2)?)
+    }
+}
+
+/// The mel filter bank `speechbrain` computes: `n_mels` triangles from 0 to 8 kHz on the HTK mel scale
+/// Row major, `n_mels` rows of `N_FFT / 2 + 1` frequency bins
+pub fn filters(n_mels: usize) -> Vec<f32> {
+    let to_mel = |hz: f32| 2595. * (1. + hz / 700.).log10();
+    let to_hz = |mel: f32| 700. * (10f32.powf(mel / 2595.) - 1.);
+
+    let nyquist = (SAMPLE_RATE / 2) as f32;
+    let top = to_mel(nyquist);
+    let hz = (0..n_mels + 2)
+        .map(|i| to_hz(top * i as f32 / (n_mels + 1) as f32))
+        .collect::<Vec<_>>();
+
+    let n_bins = N_FFT / 2 + 1;
+    (0..n_mels)
+        .flat_map(|m| {
+            // symmetric triangles, as wide on either side as the gap to the lower neighbour
+            let (center, band) = (hz[m + 1], hz[m + 1] - hz[m]);
+            (0..n_bins).map(move |k| {
+                let slope = (nyquist * k as f32 / (n_bins - 1) as f32 - center) / band;
+                (1. + slope).min(1. - slope).max(0.)
+            })
+        })
+        .collect()
+}
+
+/// The log mel filterbank features of `pcm`, mono @ 16 kHz, as the model was trained on
+/// Row major, `n_mels` rows of one value every 10 ms, less the mean of the row so that the channel is left out
+pub fn fbank(pcm: &[f32], filters: &[f32], n_mels: usize) -> Vec<f32> {
+    let n_bins = N_FFT / 2 + 1;
+    let hamming = (0..N_FFT)
+        .map(|i| 0.54 - 0.46 * (2. * PI * i as f32 / N_FFT as f32).cos())
+        .collect::<Vec<_>>();
+
+    // centered windows, zero padded at the ends
+    let n_frames = 1 + pcm.len() / HOP;
+    let sample = |i: usize| {
+        i.checked_sub(N_FFT / 2)
+            .and_then(|i| pcm.get(i))
+            .copied()
+            .unwrap_or_default()
+    };
+
+    let mut db = vec![0f32; n_mels * n_frames];
+    for f in 0..n_frames {
+        let window = (0..N_FFT)
+            .map(|i| sample(f * HOP + i) * hamming[i])
+            .collect::<Vec<_>>();
+        let out = fft(&window);
+        let power = (0..n_bins)
+            .map(|k| out[2 * k].powi(2) + out[2 * k + 1].powi(2))
+            .collect::<Vec<_>>();
+
+        for (m, filter) in filters.chunks_exact(n_bins).enumerate() {
+            let mel = filter
+                .iter()
+                .zip(power.iter())
+                .map(|(w, p)| w * p)
+                .sum::<f32>();
+            db[m * n_frames + f] = 10. * mel.max(AMIN).log10();
+        }
+    }
+
+    let floor = db.iter().copied().fold(f32::MIN, f32::max) - TOP_DB;
+    for row in db.chunks_exact_mut(n_frames) {
+        row.iter_mut().for_each(|v| *v = v.max(floor));
+        let mean = row.iter().sum::<f32>() / n_frames as f32;
+        row.iter_mut().for_each(|v| *v -= mean);
+    }
+
+    db
+}
+
+/// Embeds the voice in a recording with the `VOXCELEB` model, loaded from the app data directory
+pub struct SpeakerEncoder {
+    model: Ecapa,
+    filters: Vec<f32>,
+    device: Device,
+}
+
+impl SpeakerEncoder {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let device = device()?;
+        Self::download(dir)?;
+
+        info!("Loading speaker embedding model");
+        let vb = VarBuilder::from_pth(dir.join(LOCAL_MODEL), DType::F32, &device)?;
+        let model = Ecapa::load(vb, &VOXCELEB)?;
+
+        info!("Speaker embedding model ready!");
+        Ok(Self {
+            model,
+            filters: filters(VOXCELEB.n_mels),
+            device,
+        })
+    }
+
+    /// Downloads the model to `dir` if it isn't already present
+    pub fn download(dir: &Path) -> Result<()> {
+        if !dir.join(LOCAL_MODEL).is_file() {
+            hf_download(dir, MODEL_REPO, MODEL_FILE, Some(LOCAL_MODEL))?;
+        }
+
+        Ok(())
+    }
+
+    /// The embedding of the voice in `pcm`, mono @ 16 kHz, unit length so that a dot product is the cosine similarity
+    pub fn embed(&self, pcm: &[f32]) -> Result<Vec<f32>> {
+        // the widest convolution looks 4 frames either side
+        if pcm.len() < HOP * 8 {
+            anyhow::bail!("Not enough audio data to embed!");
+        }
+
+        let n_mels = VOXCELEB.n_mels;
+        let features = fbank(pcm, &self.filters[..], n_mels);
+        let n_frames = features.len() / n_mels;
+        let features = Tensor::from_vec(features, (1, n_mels, n_frames), &self.device)?;
+
+        let embedding = self.model.forward(&features)?.squeeze(0)?;
+        let norm = embedding.sqr()?.sum_all()?.sqrt()?;
+
+        Ok(embedding.broadcast_div(&norm)?.to_vec1::<f32>()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{VarBuilder, VarMap};
+
+    use super::{fbank, filters, reflect, Config, Ecapa, N_FFT};
+
+    #[test]
+    fn reflects() -> anyhow::Result<()> {
+        let x = Tensor::new(&[[[0f32, 1., 2., 3., 4.]]], &Device::Cpu)?;
+
+        assert_eq!(
+            reflect(&x, 2)?.flatten_all()?.to_vec1::<f32>()?,
+            [2., 1., 0., 1., 2., 3., 4., 3., 2.]
+        );
+        assert!(reflect(&x, 5).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filterbank() {
+        // a second of a 1 kHz tone, then a second of silence
+        let pcm = (0..32_000)
+            .map(|i| match i < 16_000 {
+                true => (2. * std::f32::consts::PI * 1_000. * i as f32 / 16_000.).sin() * 0.5,
+                false => 0.,
+            })
+            .collect::<Vec<_>>();
+        let filters = filters(80);
+        assert_eq!(filters.len(), 80 * (N_FFT / 2 + 1));
+
+        let db = fbank(&pcm, &filters, 80);
+        let n_frames = db.len() / 80;
+        assert_eq!(n_frames, 201);
+
+        // every row is centered
+        assert!(db
+            .chunks_exact(n_frames)
+            .all(|r| (r.iter().sum::<f32>() / n_frames as f32).abs() < 1e-3));
+        // the tone drops the most in the bins around 1 kHz, that is 1000 mel, the 80 bins are 35 mel apart
+        let drop = |m: usize| {
+            let row = &db[m * n_frames..(m + 1) * n_frames];
+            row[10..90].iter().sum::<f32>() - row[110..190].iter().sum::<f32>()
+        };
+        let tone = (0..80)
+            .max_by(|&a, &b| drop(a).total_cmp(&drop(b)))
+            .unwrap_or_default();
+        assert!((27..=28).contains(&tone), "{tone}");
+    }
+
+    #[test]
+    fn embeds() -> anyhow::Result<()> {
+        let cfg = Config {
+            n_mels: 80,
+            channels: 32,
+            mfa_channels: 48,
+            scale: 4,
+            se_channels: 8,
+            attention_channels: 8,
+            embedding: 16,
+        };
+        let varmap = VarMap::new();
+        let model = Ecapa::load(
+            VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu),
+            &cfg,
+        )?;
+
+        for n_frames in [20, 301] {
+            let features = Tensor::randn(0f32, 1., (2, 80, n_frames), &Device::Cpu)?;
+            let embedding = model.forward(&features)?;
+            assert_eq!(embedding.dims(), [2, 16]);
+            assert!(embedding
+                .flatten_all()?
+                .to_vec1::<f32>()?
+                .iter()
+                .all(|v| v.is_finite()));
+        }
+
+        Ok(())
+    }
+}
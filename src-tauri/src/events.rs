@@ -0,0 +1,167 @@
+use std::sync::RwLock;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Serialize, Serializer};
+
+use crate::scheduler::JobState;
+
+/// Events emitted by `Instruct`, the desktop app forwards them to the frontend as tauri events named `Event::name()`
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// the state of a request in the inference queue changed
+    Queue(QueueEvent),
+    /// a sentence of an answer was spoken
+    Speech(SpeechEvent),
+    /// the wake phrase was spotted, or the instruction following it ended
+    Wake(WakeEvent),
+    /// a model is being loaded, swapped in or freed
+    Model(ModelEvent),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEvent {
+    pub id: String,
+    pub state: JobState,
+    /// 1 based position in the queue, `0` once the request is no longer waiting
+    pub position: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeechEvent {
+    /// the answer being spoken, sentences of a stopped utterance are no longer emitted
+    pub utterance: u64,
+    /// 0 based position of the sentence in the utterance
+    pub seq: usize,
+    pub text: String,
+    pub sample_rate: u32,
+    /// mono PCM, empty if the sentence couldn't be synthesized
+    /// sent as base64 of 16 bit little endian samples, a JSON array of floats is several times the size
+    #[serde(serialize_with = "pcm16")]
+    pub pcm: Vec<f32>,
+    /// no more sentences follow in this utterance
+    pub last: bool,
+}
+
+// the samples in [-1, 1] as base64 of 16 bit little endian integers
+fn pcm16<S: Serializer>(pcm: &[f32], s: S) -> Result<S::Ok, S::Error> {
+    let bytes = pcm
+        .iter()
+        .flat_map(|v| ((v.clamp(-1., 1.) * i16::MAX as f32).round() as i16).to_le_bytes())
+        .collect::<Vec<_>>();
+
+    s.serialize_str(&BASE64_STANDARD.encode(bytes))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WakeState {
+    /// the wake phrase was spotted, the audio is being recorded
+    Triggered,
+    /// the instruction was recorded, ready for an `audio` request
+    Captured,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WakeEvent {
+    pub state: WakeState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    /// the language model
+    Llm,
+    /// the speech recognition model
+    Asr,
+    /// the text to speech model
+    Tts,
+    /// the embedding model of the user's documents
+    Embedding,
+    /// the speaker embedding model of diarization
+    Speakers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+    /// being downloaded, if needed, and loaded
+    Loading,
+    /// new requests use it
+    Ready,
+    /// replaced, the requests that started on it are finishing
+    Draining,
+    /// freed
+    Unloaded,
+    /// couldn't be loaded, the previous model is still in use
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelEvent {
+    pub kind: ModelKind,
+    /// the model, as passed to `switch_llm` or `switch_asr`, the hub repo for the others
+    pub name: String,
+    pub state: ModelState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Queue(_) => "queue",
+            Self::Speech(_) => "speech",
+            Self::Wake(_) => "wake",
+            Self::Model(_) => "model",
+        }
+    }
+}
+
+type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// A minimal pub-sub to decouple the core from whoever is interested in the events, the tauri app or a cli
+#[derive(Default)]
+pub struct EventBus {
+    listeners: RwLock<Vec<Listener>>,
+}
+
+impl EventBus {
+    /// Registers a listener, listeners are called on the emitting thread and should return quickly
+    pub fn subscribe<F: Fn(&Event) + Send + Sync + 'static>(&self, f: F) {
+        match self.listeners.write() {
+            Ok(mut l) => l.push(Box::new(f)),
+            Err(e) => error!("subscribe: error acquiring lock: {e:?}"),
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        match self.listeners.read() {
+            Ok(l) => l.iter().for_each(|f| f(&event)),
+            Err(e) => error!("emit: error acquiring lock: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpeechEvent;
+
+    #[test]
+    fn pcm_as_base64() -> anyhow::Result<()> {
+        let event = SpeechEvent {
+            utterance: 1,
+            seq: 0,
+            text: "Hi.".to_string(),
+            sample_rate: 44_100,
+            pcm: vec![0., 1., -1., 2., 0.5],
+            last: true,
+        };
+        let json = serde_json::to_value(&event)?;
+
+        // 0, 32767, -32767, 32767 (clamped), 16384
+        assert_eq!(json["pcm"], "AAD/fwGA/38AQA==");
+
+        Ok(())
+    }
+}
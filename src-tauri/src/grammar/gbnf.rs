@@ -0,0 +1,401 @@
+//! Parses llama.cpp style GBNF grammars, e.g.
+//!   root   ::= answer "."?
+//!   answer ::= "yes" | "no"
+//! Supports literals with escapes, character classes (`[a-z]`, `[^"]`), `.` for any character, groups,
+//! the `*`, `+`, `?` and `{m,n}` repetitions and `#` comments
+//! A rule continues on the next line only after a `|` or inside a group, the grammar starts at `root`
+
+use anyhow::Result;
+
+use super::{Builder, CharClass, Element, Grammar, Sequence};
+
+/// Compiles a GBNF grammar
+pub fn parse(src: &str) -> Result<Grammar> {
+    let mut p = Parser {
+        src: src.chars().collect(),
+        pos: 0,
+        b: Builder::default(),
+    };
+
+    p.space(true);
+    while p.pos < p.src.len() {
+        p.rule()?;
+        p.space(true);
+    }
+
+    if !p.b.is_defined("root") {
+        anyhow::bail!("gbnf: grammar has no `root` rule");
+    }
+
+    let grammar = p.b.build("root")?;
+    if grammar.is_empty() {
+        anyhow::bail!("gbnf: grammar can't match any text");
+    }
+
+    Ok(grammar)
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    b: Builder,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn line(&self) -> usize {
+        self.src[..self.pos.min(self.src.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        anyhow::bail!("gbnf: line {}: {msg}", self.line())
+    }
+
+    // skips whitespace and comments, newlines only if `newlines`
+    fn space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\r' | '\n' if !newlines => return,
+                c if c.is_whitespace() => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                return self.error(&format!("expected `{s}`"));
+            }
+            self.pos += 1;
+        }
+
+        Ok(())
+    }
+
+    // name ::= alternatives
+    fn rule(&mut self) -> Result<()> {
+        let name = self.name()?;
+        self.space(false);
+        self.expect("::=")?;
+        self.space(true);
+
+        if self.b.is_defined(&name) {
+            return self.error(&format!("rule `{name}` is defined twice"));
+        }
+        let alts = self.alternatives(false)?;
+        self.b.define(&name, alts);
+
+        match self.peek() {
+            None | Some('\n' | '\r') => Ok(()),
+            Some(c) => self.error(&format!("unexpected `{c}`")),
+        }
+    }
+
+    // sequences separated by `|`, `nested` inside a group where newlines don't end the rule
+    fn alternatives(&mut self, nested: bool) -> Result<Vec<Sequence>> {
+        let mut alts = vec![self.sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.space(true);
+            alts.push(self.sequence(nested)?);
+        }
+
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, nested: bool) -> Result<Sequence> {
+        let mut seq = vec![];
+
+        loop {
+            let mut item = match self.peek() {
+                Some('"') => self.literal()?,
+                Some('[') => vec![self.class()?],
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::Chars(CharClass::new(vec![], true))]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.space(true);
+                    let alts = self.alternatives(true)?;
+                    self.expect(")")?;
+                    vec![self.b.define_new("group", alts)]
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.name()?;
+                    vec![Element::Rule(self.b.rule(&name))]
+                }
+                _ => return Ok(seq),
+            };
+
+            if let Some((min, max)) = self.repetition()? {
+                // a literal of several characters is repeated as a whole
+                let el = match &item[..] {
+                    [el] => el.clone(),
+                    _ => self.b.define_new("literal", vec![item]),
+                };
+                item = self.b.repeat(el, min, max);
+            }
+
+            seq.extend(item);
+            self.space(nested);
+        }
+    }
+
+    // the `*`, `+`, `?` or `{m,n}` following an element, if any
+    fn repetition(&mut self) -> Result<Option<(usize, Option<usize>)>> {
+        let rep = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                self.space(false);
+                let min = self.number()?;
+                self.space(false);
+
+                let max = if self.peek() == Some(',') {
+                    self.pos += 1;
+                    self.space(false);
+                    match self.peek() {
+                        Some('}') => None,
+                        _ => Some(self.number()?),
+                    }
+                } else {
+                    Some(min)
+                };
+                self.space(false);
+                if self.peek() != Some('}') {
+                    return self.error("expected `}`");
+                }
+                if max.is_some_and(|m| m < min) {
+                    return self.error(&format!("invalid repetition `{{{min},{max:?}}}`"));
+                }
+
+                (min, max)
+            }
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+
+        Ok(Some(rep))
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+
+        match self.src[start..self.pos].iter().collect::<String>().parse() {
+            Ok(n) => Ok(n),
+            Err(_) => self.error("expected a number"),
+        }
+    }
+
+    // "..."
+    fn literal(&mut self) -> Result<Sequence> {
+        self.expect("\"")?;
+
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => break,
+                Some('\\') => text.push(self.escape()?),
+                Some('\n') | None => return self.error("unterminated literal"),
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos += 1;
+
+        Ok(Element::literal(&text))
+    }
+
+    // [...] or [^...]
+    fn class(&mut self) -> Result<Element> {
+        self.expect("[")?;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut ranges = vec![];
+        loop {
+            let lo = match self.peek() {
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some('\n') | None => return self.error("unterminated character class"),
+                Some(c) => {
+                    self.pos += 1;
+                    c
+                }
+            };
+
+            let hi = match (self.peek(), self.src.get(self.pos + 1)) {
+                // a `-` right before the `]` is a literal
+                (Some('-'), Some(c)) if *c != ']' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\\') => self.escape()?,
+                        Some(c) => {
+                            self.pos += 1;
+                            c
+                        }
+                        None => return self.error("unterminated character class"),
+                    }
+                }
+                _ => lo,
+            };
+            if hi < lo {
+                return self.error(&format!("invalid range `{lo}-{hi}`"));
+            }
+
+            ranges.push((lo, hi));
+        }
+        self.pos += 1;
+
+        Ok(Element::class(&ranges[..], negated))
+    }
+
+    // \n, \t, \r, \xHH, \uHHHH, \UHHHHHHHH or an escaped character
+    fn escape(&mut self) -> Result<char> {
+        self.expect("\\")?;
+        let Some(c) = self.peek() else {
+            return self.error("unterminated escape");
+        };
+        self.pos += 1;
+
+        let digits = match c {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            c => return Ok(c),
+        };
+
+        let hex = self
+            .src
+            .get(self.pos..self.pos + digits)
+            .map(|h| h.iter().collect::<String>())
+            .unwrap_or_default();
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) => {
+                self.pos += digits;
+                Ok(c)
+            }
+            None => self.error(&format!("invalid escape `\\{c}{hex}`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn yes_no() -> anyhow::Result<()> {
+        let g = parse(r#"root ::= ("yes" | "no") "."?"#)?;
+
+        assert!(g.matches("yes"));
+        assert!(g.matches("no."));
+        assert!(!g.matches("maybe"));
+        assert!(!g.matches("yes.."));
+
+        Ok(())
+    }
+
+    #[test]
+    fn date() -> anyhow::Result<()> {
+        let g = parse(
+            r#"
+            # an ISO 8601 date
+            root  ::= year "-" month "-" day
+            year  ::= [0-9]{4}
+            month ::= "0" [1-9] | "1" [0-2]
+            day   ::= "0" [1-9] | [12] [0-9] | "3" [01]
+            "#,
+        )?;
+
+        assert!(g.matches("2024-05-31"));
+        assert!(!g.matches("2024-13-01"));
+        assert!(!g.matches("24-05-31"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bullet_list() -> anyhow::Result<()> {
+        let g = parse(
+            r#"
+            root ::= item{1,3}
+            item ::= "- " [^\n]+ "\n"
+            "#,
+        )?;
+
+        assert!(g.matches("- milk\n- eggs\n"));
+        assert!(!g.matches("milk\n"));
+        assert!(!g.matches("- a\n- b\n- c\n- d\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn literals_and_classes() -> anyhow::Result<()> {
+        let g = parse(
+            r#"root ::= "\"\x41é\"" ("ab")+ [\]a-] .
+            "#,
+        )?;
+
+        assert!(g.matches("\"Aé\"abab]!"));
+        assert!(g.matches("\"Aé\"ab-\n"));
+        assert!(!g.matches("\"Aé\"aab]!"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src: &str| parse(src).unwrap_err().to_string();
+
+        assert!(err("answer ::= \"yes\"").contains("no `root`"));
+        assert!(err("root ::= answer").contains("`answer` is not defined"));
+        assert!(err("root ::= \"a\"\nroot ::= \"b\"").contains("line 2"));
+        assert!(err("root ::= [a-").contains("unterminated"));
+        assert!(err("root ::= \"a\"{3,1}").contains("invalid repetition"));
+        assert!(err("root ::= root \"a\"").contains("left recursive"));
+    }
+}
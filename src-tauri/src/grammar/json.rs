@@ -0,0 +1,571 @@
+//! Compiles a JSON Schema to a grammar matching the JSON documents valid under it
+//! Supports a practical subset: `type` (including lists of types), `properties`/`required`, `items`, `minItems`/`maxItems`,
+//! `minLength`/`maxLength`, `enum`, `const`, `anyOf`/`oneOf`, `allOf` with a single schema and local `$ref`s
+//! Other keywords are ignored, objects only take the properties listed in the schema, the required ones first and then the rest in the listed order
+
+use anyhow::Result;
+use serde_json::Value;
+
+use super::{Builder, Element, Grammar, Sequence};
+
+/// Max number of digits in an integer, keeps the model from generating digits forever
+const MAX_INT_DIGITS: usize = 16;
+/// Max indentation after a newline
+const MAX_INDENT: usize = 20;
+
+/// A grammar matching `schema`
+pub fn from_schema(schema: &Value) -> Result<Grammar> {
+    let mut conv = Converter {
+        b: Builder::default(),
+        root: schema,
+    };
+    conv.primitives();
+
+    let value = conv.visit(schema, "root-value")?;
+    conv.b.define("root", vec![vec![value]]);
+
+    conv.b.build("root")
+}
+
+/// A grammar matching any JSON object
+pub fn any_object() -> Result<Grammar> {
+    from_schema(&serde_json::json!({ "type": "object" }))
+}
+
+struct Converter<'s> {
+    b: Builder,
+    root: &'s Value,
+}
+
+impl Converter<'_> {
+    fn rule(&mut self, name: &str) -> Element {
+        Element::Rule(self.b.rule(name))
+    }
+
+    // the building blocks every schema uses
+    fn primitives(&mut self) {
+        // space ::= "" | " " | "\n" [ \t]{0,20}
+        let indent = self.b.repeat(
+            Element::class(&[(' ', ' '), ('\t', '\t')], false),
+            0,
+            Some(MAX_INDENT),
+        );
+        self.b.define(
+            "space",
+            vec![
+                vec![],
+                Element::literal(" "),
+                [Element::literal("\n"), indent].concat(),
+            ],
+        );
+
+        // char ::= [^"\\\x00-\x1F\x7F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} )
+        let hex = Element::class(&[('0', '9'), ('a', 'f'), ('A', 'F')], false);
+        let escape = self.b.define_new(
+            "escape",
+            vec![
+                vec![Element::class(
+                    &[
+                        ('"', '"'),
+                        ('\\', '\\'),
+                        ('/', '/'),
+                        ('b', 'b'),
+                        ('f', 'f'),
+                        ('n', 'n'),
+                        ('r', 'r'),
+                        ('t', 't'),
+                    ],
+                    false,
+                )],
+                [Element::literal("u"), vec![hex; 4]].concat(),
+            ],
+        );
+        self.b.define(
+            "char",
+            vec![
+                vec![Element::class(
+                    &[
+                        ('"', '"'),
+                        ('\\', '\\'),
+                        ('\u{0}', '\u{1F}'),
+                        ('\u{7F}', '\u{7F}'),
+                    ],
+                    true,
+                )],
+                [Element::literal("\\"), vec![escape]].concat(),
+            ],
+        );
+
+        // string ::= "\"" char* "\""
+        let char = self.rule("char");
+        let chars = self.b.repeat(char, 0, None);
+        self.b.define(
+            "string",
+            vec![[Element::literal("\""), chars, Element::literal("\"")].concat()],
+        );
+
+        // integer ::= "-"? ( [0-9] | [1-9] [0-9]{1,15} )
+        let digit = Element::class(&[('0', '9')], false);
+        let minus = self
+            .b
+            .define_new("minus", vec![Element::literal("-"), vec![]]);
+        let more = self.b.repeat(digit.clone(), 1, Some(MAX_INT_DIGITS - 1));
+        let magnitude = self.b.define_new(
+            "magnitude",
+            vec![
+                vec![digit.clone()],
+                [vec![Element::class(&[('1', '9')], false)], more].concat(),
+            ],
+        );
+        self.b.define("integer", vec![vec![minus, magnitude]]);
+
+        // number ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+        let frac_digits = self.b.repeat(digit.clone(), 1, None);
+        let frac = self.b.define_new(
+            "fraction",
+            vec![[Element::literal("."), frac_digits].concat(), vec![]],
+        );
+        let sign = self.b.define_new(
+            "sign",
+            vec![
+                vec![Element::class(&[('-', '-'), ('+', '+')], false)],
+                vec![],
+            ],
+        );
+        let exp_digits = self.b.repeat(digit, 1, None);
+        let exp = self.b.define_new(
+            "exponent",
+            vec![
+                [
+                    vec![Element::class(&[('e', 'e'), ('E', 'E')], false), sign],
+                    exp_digits,
+                ]
+                .concat(),
+                vec![],
+            ],
+        );
+        let integer = self.rule("integer");
+        self.b.define("number", vec![vec![integer, frac, exp]]);
+
+        self.b.define(
+            "boolean",
+            vec![Element::literal("true"), Element::literal("false")],
+        );
+        self.b.define("null", vec![Element::literal("null")]);
+
+        // value ::= object | array | string | number | boolean | null
+        let any = ["object", "array", "string", "number", "boolean", "null"]
+            .iter()
+            .map(|t| vec![self.rule(t)])
+            .collect();
+        self.b.define("value", any);
+
+        let value = self.rule("value");
+        let string = self.rule("string");
+        let object = self.map(string, value.clone());
+        self.b.define("object", vec![object]);
+        let array = self.array(value, 0, None);
+        self.b.define("array", vec![array]);
+    }
+
+    // `{ key: value, ... }` with any keys
+    fn map(&mut self, key: Element, value: Element) -> Sequence {
+        let space = self.rule("space");
+        let kv = self.b.define_new(
+            "kv",
+            vec![[
+                vec![key, space.clone()],
+                Element::literal(":"),
+                vec![space.clone(), value, space.clone()],
+            ]
+            .concat()],
+        );
+        let next = self.b.define_new(
+            "kv-next",
+            vec![[Element::literal(","), vec![space.clone(), kv.clone()]].concat()],
+        );
+        let rest = self.b.repeat(next, 0, None);
+        let body = self
+            .b
+            .define_new("kv-list", vec![[vec![kv], rest].concat(), vec![]]);
+
+        [
+            Element::literal("{"),
+            vec![space, body],
+            Element::literal("}"),
+        ]
+        .concat()
+    }
+
+    // `[ item, ... ]` with `min` to `max` items
+    fn array(&mut self, item: Element, min: usize, max: Option<usize>) -> Sequence {
+        let space = self.rule("space");
+        let item_sp = self.b.define_new("item", vec![vec![item, space.clone()]]);
+        let next = self.b.define_new(
+            "item-next",
+            vec![[Element::literal(","), vec![space.clone(), item_sp.clone()]].concat()],
+        );
+
+        let body = match (min, max) {
+            (_, Some(0)) => vec![],
+            (0, max) => {
+                let rest = self.b.repeat(next, 0, max.map(|m| m - 1));
+                vec![self
+                    .b
+                    .define_new("items", vec![[vec![item_sp], rest].concat(), vec![]])]
+            }
+            (min, max) => {
+                let rest = self.b.repeat(next, min - 1, max.map(|m| m - 1));
+                [vec![item_sp], rest].concat()
+            }
+        };
+
+        [
+            Element::literal("["),
+            vec![space],
+            body,
+            Element::literal("]"),
+        ]
+        .concat()
+    }
+
+    fn literal(value: &Value) -> Result<Sequence> {
+        Ok(Element::literal(&serde_json::to_string(value)?))
+    }
+
+    // the element matching `schema`, `name` is a hint for the rule names
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<Element> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(self.rule("value")),
+            Value::Bool(false) => anyhow::bail!("schema `{name}` can never be satisfied"),
+            Value::Object(o) if o.is_empty() => return Ok(self.rule("value")),
+            Value::Object(o) => o,
+            _ => anyhow::bail!("schema `{name}` is not an object"),
+        };
+
+        if let Some(r) = obj.get("$ref").and_then(|r| r.as_str()) {
+            return self.reference(r);
+        }
+
+        if let Some(c) = obj.get("const") {
+            return Ok(self.b.define_new(name, vec![Self::literal(c)?]));
+        }
+
+        if let Some(e) = obj.get("enum").and_then(|e| e.as_array()) {
+            let alts = e.iter().map(Self::literal).collect::<Result<Vec<_>>>()?;
+            return Ok(self.b.define_new(name, alts));
+        }
+
+        if let Some(any) = obj
+            .get("anyOf")
+            .or_else(|| obj.get("oneOf"))
+            .and_then(|a| a.as_array())
+        {
+            let alts = any
+                .iter()
+                .enumerate()
+                .map(|(i, s)| Ok(vec![self.visit(s, &format!("{name}-{i}"))?]))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(self.b.define_new(name, alts));
+        }
+
+        if let Some(all) = obj.get("allOf").and_then(|a| a.as_array()) {
+            match &all[..] {
+                [s] => return self.visit(s, name),
+                _ => anyhow::bail!("`allOf` is only supported with a single schema"),
+            }
+        }
+
+        match obj.get("type") {
+            Some(Value::String(t)) => self.typed(obj, t, name),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .filter_map(|t| t.as_str())
+                    .map(|t| Ok(vec![self.typed(obj, t, name)?]))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.b.define_new(name, alts))
+            }
+            // no `type`, but the shape gives it away
+            _ if obj.contains_key("properties") => self.typed(obj, "object", name),
+            _ if obj.contains_key("items") => self.typed(obj, "array", name),
+            _ => Ok(self.rule("value")),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        obj: &serde_json::Map<String, Value>,
+        ty: &str,
+        name: &str,
+    ) -> Result<Element> {
+        let usize_of = |k: &str| obj.get(k).and_then(|v| v.as_u64()).map(|v| v as usize);
+
+        match ty {
+            "object" => self.object(obj, name),
+            "array" => {
+                let item = match obj.get("items") {
+                    Some(items) => self.visit(items, &format!("{name}-item"))?,
+                    None => self.rule("value"),
+                };
+                let (min, max) = (usize_of("minItems").unwrap_or(0), usize_of("maxItems"));
+                if max.is_some_and(|m| m < min) {
+                    anyhow::bail!("schema `{name}` has `maxItems` < `minItems`");
+                }
+
+                let seq = self.array(item, min, max);
+                Ok(self.b.define_new(name, vec![seq]))
+            }
+            "string" => {
+                let (min, max) = (usize_of("minLength"), usize_of("maxLength"));
+                if min.is_none() && max.is_none() {
+                    return Ok(self.rule("string"));
+                }
+
+                let chars = self.rule("char");
+                let chars = self.b.repeat(chars, min.unwrap_or(0), max);
+                Ok(self.b.define_new(
+                    name,
+                    vec![[Element::literal("\""), chars, Element::literal("\"")].concat()],
+                ))
+            }
+            "integer" | "number" | "boolean" | "null" => Ok(self.rule(ty)),
+            t => anyhow::bail!("schema `{name}` has unsupported type `{t}`"),
+        }
+    }
+
+    fn object(&mut self, obj: &serde_json::Map<String, Value>, name: &str) -> Result<Element> {
+        let props = match obj.get("properties").and_then(|p| p.as_object()) {
+            Some(p) if !p.is_empty() => p,
+            // a map, values constrained by `additionalProperties` if it's a schema
+            _ => {
+                let value = match obj.get("additionalProperties") {
+                    Some(v @ Value::Object(_)) => self.visit(v, &format!("{name}-value"))?,
+                    _ => self.rule("value"),
+                };
+                let string = self.rule("string");
+                let seq = self.map(string, value);
+                return Ok(self.b.define_new(name, vec![seq]));
+            }
+        };
+
+        let required = obj
+            .get("required")
+            .and_then(|r| r.as_array())
+            .map(|r| r.iter().filter_map(|r| r.as_str()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let space = self.rule("space");
+        let (mut req, mut opt) = (vec![], vec![]);
+        for (key, schema) in props.iter() {
+            let value = self.visit(schema, &format!("{name}-{key}"))?;
+            let kv = [
+                Self::literal(&Value::String(key.clone()))?,
+                vec![space.clone()],
+                Element::literal(":"),
+                vec![space.clone(), value, space.clone()],
+            ]
+            .concat();
+            let kv = self.b.define_new(&format!("{name}-{key}-kv"), vec![kv]);
+
+            if required.contains(&key.as_str()) {
+                req.push(kv);
+            } else {
+                opt.push(kv);
+            }
+        }
+
+        // `, kv` for every optional property, in order
+        let comma = |b: &mut Builder, kv: &Element| {
+            b.define_new(
+                "opt-kv",
+                vec![
+                    [Element::literal(","), vec![space.clone(), kv.clone()]].concat(),
+                    vec![],
+                ],
+            )
+        };
+
+        let mut body = vec![];
+        if !req.is_empty() {
+            for (i, kv) in req.into_iter().enumerate() {
+                if i > 0 {
+                    body.extend(Element::literal(","));
+                    body.push(space.clone());
+                }
+                body.push(kv);
+            }
+            for kv in opt.iter() {
+                body.push(comma(&mut self.b, kv));
+            }
+        } else {
+            // no property is required: nothing, or any of the optional ones followed by any of the ones after it
+            let mut alts = vec![vec![]];
+            for (i, kv) in opt.iter().enumerate() {
+                let mut alt = vec![kv.clone()];
+                for later in opt[i + 1..].iter() {
+                    alt.push(comma(&mut self.b, later));
+                }
+                alts.push(alt);
+            }
+            body.push(self.b.define_new(&format!("{name}-props"), alts));
+        }
+
+        let seq = [
+            Element::literal("{"),
+            vec![space],
+            body,
+            Element::literal("}"),
+        ]
+        .concat();
+        Ok(self.b.define_new(name, vec![seq]))
+    }
+
+    // a local reference `#/$defs/name` or `#/definitions/name`
+    fn reference(&mut self, r: &str) -> Result<Element> {
+        let Some(pointer) = r.strip_prefix('#') else {
+            anyhow::bail!("only local `$ref`s are supported, got `{r}`");
+        };
+
+        let name = format!("ref{}", pointer.replace('/', "-"));
+        // defined, or being defined further up for a recursive schema
+        if self.b.is_defined(&name) {
+            return Ok(self.rule(&name));
+        }
+
+        let id = self.b.rule(&name);
+        let Some(schema) = self.root.pointer(pointer) else {
+            anyhow::bail!("`$ref` `{r}` not found");
+        };
+
+        // reserve the name first, so that recursive references resolve to it
+        self.b.define(&name, vec![]);
+        let el = self.visit(schema, &name)?;
+        self.b.define(&name, vec![vec![el]]);
+
+        Ok(Element::Rule(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{any_object, from_schema};
+
+    #[test]
+    fn nested_objects() -> anyhow::Result<()> {
+        let g = from_schema(&json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "address": {
+                    "type": "object",
+                    "properties": {
+                        "city": { "type": "string" },
+                        "zip": { "type": "integer" }
+                    },
+                    "required": ["city", "zip"]
+                },
+                "nickname": { "type": "string" }
+            },
+            "required": ["name", "address"]
+        }))?;
+
+        assert!(g.matches(r#"{"name": "Ada", "address": {"city": "London", "zip": 1815}}"#));
+        assert!(g.matches(
+            "{\n  \"name\": \"Ada\",\n  \"address\": {\n    \"city\": \"London\",\n    \"zip\": -12\n  },\n  \"nickname\": \"\\\"A\\\"\"\n}"
+        ));
+        // missing a required property
+        assert!(!g.matches(r#"{"name": "Ada", "address": {"city": "London"}}"#));
+        // wrong type
+        assert!(!g.matches(r#"{"name": "Ada", "address": {"city": "London", "zip": "1815"}}"#));
+        // not in the schema
+        assert!(
+            !g.matches(r#"{"name": "Ada", "address": {"city": "London", "zip": 1}, "age": 36}"#)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn enums() -> anyhow::Result<()> {
+        let g = from_schema(&json!({
+            "type": "object",
+            "properties": {
+                "color": { "enum": ["red", "green", 3, null] },
+                "unit": { "const": "cm" }
+            },
+            "required": ["color", "unit"]
+        }))?;
+
+        assert!(g.matches(r#"{"color": "red", "unit": "cm"}"#));
+        assert!(g.matches(r#"{"color": 3, "unit": "cm"}"#));
+        assert!(g.matches(r#"{"color": null, "unit": "cm"}"#));
+        assert!(!g.matches(r#"{"color": "blue", "unit": "cm"}"#));
+        assert!(!g.matches(r#"{"color": "red", "unit": "mm"}"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn arrays() -> anyhow::Result<()> {
+        let g = from_schema(&json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "tags": { "type": "array", "items": { "type": "string" } },
+                    "score": { "type": "number" }
+                },
+                "required": ["score"]
+            },
+            "minItems": 1,
+            "maxItems": 2
+        }))?;
+
+        assert!(g.matches(r#"[{"score": 1.5}]"#));
+        assert!(g.matches(r#"[{"score": 1e3, "tags": []}, {"score": 0, "tags": ["a", "b"]}]"#));
+        assert!(!g.matches("[]"));
+        assert!(!g.matches(r#"[{"score": 1}, {"score": 2}, {"score": 3}]"#));
+        assert!(!g.matches(r#"[{"score": 1, "tags": [1]}]"#));
+        assert!(!g.matches(r#"[{"score": 01}]"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn optional_properties_and_refs() -> anyhow::Result<()> {
+        let g = from_schema(&json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": ["integer", "null"] },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    }
+                }
+            },
+            "$ref": "#/$defs/node"
+        }))?;
+
+        assert!(g.matches("{}"));
+        assert!(g.matches(r#"{"children": []}"#));
+        assert!(g.matches(r#"{"value": null, "children": [{"value": 1}, {"children": [{}]}]}"#));
+        // out of order
+        assert!(!g.matches(r#"{"children": [], "value": 1}"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn any_json_object() -> anyhow::Result<()> {
+        let g = any_object()?;
+
+        assert!(g.matches(r#"{"a": [1, {"b": null}], "c": true}"#));
+        assert!(!g.matches("[1]"));
+        assert!(!g.matches(r#"{"a": }"#));
+
+        Ok(())
+    }
+}
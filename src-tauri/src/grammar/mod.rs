@@ -0,0 +1,656 @@
+//! Constrains generation to a formal grammar, so that the output is guaranteed to parse
+//! Works like llama.cpp's grammar sampling: the parse state is a set of stacks of positions in the grammar's rules,
+//! the bytes of a candidate token are fed through the state and the token is allowed if any stack survives
+//! A token may end in the middle of a character, the bytes of it are held till the character is complete
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+pub mod gbnf;
+pub mod json;
+
+/// Past this depth the expansion of a rule is abandoned, it's a left recursive or an empty loop
+const MAX_DEPTH: usize = 512;
+
+/// A set of characters e.g. `[a-z0-9]`, `[^"]` or a single literal character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    pub fn new(ranges: Vec<(char, char)>, negated: bool) -> Self {
+        Self { ranges, negated }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != self.negated
+    }
+
+    // whether any character in `lo..=hi` matches, for the characters a partial utf-8 sequence can still become
+    fn matches_any(&self, lo: u32, hi: u32) -> bool {
+        if !self.negated {
+            return self
+                .ranges
+                .iter()
+                .any(|(a, b)| *a as u32 <= hi && lo <= *b as u32);
+        }
+
+        // some character isn't covered by the ranges
+        let mut ranges = self.ranges.clone();
+        ranges.sort();
+        let mut next = lo;
+        for (a, b) in ranges {
+            if a as u32 > next {
+                break;
+            }
+            next = next.max(b as u32 + 1);
+        }
+
+        next <= hi
+    }
+}
+
+/// An element of a sequence, either a character or a reference to a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Chars(CharClass),
+    Rule(usize),
+}
+
+impl Element {
+    /// Elements matching the literal `s`
+    pub fn literal(s: &str) -> Vec<Self> {
+        s.chars()
+            .map(|c| Self::Chars(CharClass::new(vec![(c, c)], false)))
+            .collect()
+    }
+
+    pub fn class(ranges: &[(char, char)], negated: bool) -> Self {
+        Self::Chars(CharClass::new(ranges.to_vec(), negated))
+    }
+}
+
+/// A sequence of elements, a rule is a list of alternative sequences
+pub type Sequence = Vec<Element>;
+
+/// A compiled grammar
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    rules: Vec<Vec<Sequence>>,
+    root: usize,
+}
+
+/// Builds a grammar rule by rule, rules can be referenced before they are defined
+#[derive(Debug, Default)]
+pub struct Builder {
+    rules: Vec<Option<Vec<Sequence>>>,
+    names: HashMap<String, usize>,
+}
+
+impl Builder {
+    /// The id of the rule `name`, reserved if it isn't defined yet
+    pub fn rule(&mut self, name: &str) -> usize {
+        if let Some(id) = self.names.get(name) {
+            return *id;
+        }
+
+        self.rules.push(None);
+        self.names.insert(name.to_string(), self.rules.len() - 1);
+
+        self.rules.len() - 1
+    }
+
+    /// Whether the rule `name` has a definition
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.names
+            .get(name)
+            .is_some_and(|id| self.rules[*id].is_some())
+    }
+
+    /// Defines the rule `name` as a choice between `alternatives`
+    pub fn define(&mut self, name: &str, alternatives: Vec<Sequence>) -> usize {
+        let id = self.rule(name);
+        self.rules[id] = Some(alternatives);
+
+        id
+    }
+
+    /// Defines a new rule with a name derived from `prefix`
+    pub fn define_new(&mut self, prefix: &str, alternatives: Vec<Sequence>) -> Element {
+        let name = format!("{prefix}-{}", self.rules.len());
+        Element::Rule(self.define(&name, alternatives))
+    }
+
+    /// `el` repeated between `min` and `max` times, unbounded if `max` is `None`
+    pub fn repeat(&mut self, el: Element, min: usize, max: Option<usize>) -> Sequence {
+        let mut seq = vec![el.clone(); min];
+
+        match max {
+            // x* ::= x x* | ""
+            None => {
+                let name = format!("repeat-{}", self.rules.len());
+                let id = self.rule(&name);
+                self.define(&name, vec![vec![el, Element::Rule(id)], vec![]]);
+                seq.push(Element::Rule(id));
+            }
+            // the optional ones nested, x{0,2} ::= ( x ( x )? )?
+            Some(max) if max > min => {
+                let mut tail = self.define_new("repeat", vec![vec![el.clone()], vec![]]);
+                for _ in min + 1..max {
+                    tail = self.define_new("repeat", vec![vec![el.clone(), tail], vec![]]);
+                }
+                seq.push(tail);
+            }
+            Some(_) => {}
+        }
+
+        seq
+    }
+
+    /// The grammar starting at `root`, fails if a referenced rule was never defined or is left recursive
+    pub fn build(self, root: &str) -> Result<Grammar> {
+        let Some(root) = self.names.get(root).copied() else {
+            anyhow::bail!("grammar has no `{root}` rule");
+        };
+        let name = |id: usize| {
+            self.names
+                .iter()
+                .find_map(|(n, i)| (*i == id).then_some(n.as_str()))
+                .unwrap_or_default()
+        };
+
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (id, rule) in self.rules.iter().enumerate() {
+            match rule {
+                Some(r) => rules.push(r.clone()),
+                None => anyhow::bail!("grammar rule `{}` is not defined", name(id)),
+            }
+        }
+
+        let grammar = Grammar { rules, root };
+        // a left recursive rule would be expanded forever, before matching a single character
+        if let Some(id) = grammar.left_recursive() {
+            anyhow::bail!("grammar rule `{}` is left recursive", name(id));
+        }
+
+        Ok(grammar)
+    }
+}
+
+/// A position in the grammar, the element at `idx` of the alternative `alt` of `rule` is next to be matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    idx: usize,
+}
+
+type Stack = Vec<Pos>;
+
+/// The state of a parse, advanced a character or a byte at a time
+/// Every stack has a character class on top, an empty stack is a complete parse
+#[derive(Debug, Clone)]
+pub struct Matcher<'g> {
+    grammar: &'g Grammar,
+    stacks: Vec<Stack>,
+    // the bytes of a character that isn't complete yet
+    partial: Vec<u8>,
+}
+
+impl<'g> Matcher<'g> {
+    pub fn new(grammar: &'g Grammar) -> Self {
+        let mut stacks = vec![];
+        (0..grammar.rules[grammar.root].len()).for_each(|alt| {
+            grammar.expand(
+                vec![Pos {
+                    rule: grammar.root,
+                    alt,
+                    idx: 0,
+                }],
+                &mut stacks,
+                0,
+            )
+        });
+        stacks.sort();
+        stacks.dedup();
+
+        Self {
+            grammar,
+            stacks,
+            partial: vec![],
+        }
+    }
+
+    /// Advances the parse by `c`, returns `false` if the grammar doesn't allow it
+    pub fn accept(&mut self, c: char) -> bool {
+        // the character before it was left incomplete
+        if !self.partial.is_empty() {
+            self.stacks.clear();
+            return false;
+        }
+
+        let mut next = vec![];
+        for stack in self.stacks.iter() {
+            let Some(top) = stack.last() else {
+                continue;
+            };
+            let Element::Chars(class) = &self.grammar.rules[top.rule][top.alt][top.idx] else {
+                continue;
+            };
+            if !class.matches(c) {
+                continue;
+            }
+
+            let mut stack = stack.clone();
+            if let Some(top) = stack.last_mut() {
+                top.idx += 1;
+            }
+            self.grammar.expand(stack, &mut next, 0);
+        }
+        next.sort();
+        next.dedup();
+
+        self.stacks = next;
+        !self.stacks.is_empty()
+    }
+
+    /// Advances the parse by a byte of utf-8, returns `false` if the grammar doesn't allow it
+    /// The character is matched once its last byte is in, till then the bytes so far must be able to become one that matches
+    pub fn accept_byte(&mut self, b: u8) -> bool {
+        self.partial.push(b);
+        let (lo, hi) = match std::str::from_utf8(&self.partial) {
+            Ok(s) => {
+                let c = s.chars().next();
+                self.partial.clear();
+                return c.is_some_and(|c| self.accept(c));
+            }
+            Err(e) if e.error_len().is_none() => utf8_bounds(&self.partial),
+            // not utf-8
+            Err(_) => {
+                self.stacks.clear();
+                return false;
+            }
+        };
+
+        self.stacks.retain(|stack| match stack.last() {
+            Some(top) => matches!(
+                &self.grammar.rules[top.rule][top.alt][top.idx],
+                Element::Chars(class) if class.matches_any(lo, hi)
+            ),
+            None => false,
+        });
+        !self.stacks.is_empty()
+    }
+
+    /// Advances the parse by every character of `s`
+    pub fn accept_str(&mut self, s: &str) -> bool {
+        s.chars().all(|c| self.accept(c))
+    }
+
+    /// Advances the parse by every byte of `bytes`, which may start or end in the middle of a character
+    pub fn accept_bytes(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|b| self.accept_byte(*b))
+    }
+
+    /// The text so far is a complete parse
+    pub fn is_accepting(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// The text so far is a complete parse and nothing more can follow
+    pub fn is_done(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().all(|s| s.is_empty())
+    }
+
+    /// No text can complete the parse
+    pub fn is_dead(&self) -> bool {
+        self.stacks.is_empty()
+    }
+}
+
+// the lowest and highest characters that the first bytes of a utf-8 sequence can still become
+fn utf8_bounds(partial: &[u8]) -> (u32, u32) {
+    let (len, lead, min) = match partial[0] {
+        0xC0..=0xDF => (2, partial[0] as u32 & 0x1F, 0x80),
+        0xE0..=0xEF => (3, partial[0] as u32 & 0x0F, 0x800),
+        _ => (4, partial[0] as u32 & 0x07, 0x10000),
+    };
+
+    let (mut lo, mut hi) = (lead, lead);
+    for i in 1..len {
+        let (l, h) = match partial.get(i) {
+            Some(b) => (*b as u32 & 0x3F, *b as u32 & 0x3F),
+            None => (0, 0x3F),
+        };
+        lo = lo << 6 | l;
+        hi = hi << 6 | h;
+    }
+
+    (lo.max(min), hi.min(char::MAX as u32))
+}
+
+impl Grammar {
+    /// Whether `text` is a complete parse
+    pub fn matches(&self, text: &str) -> bool {
+        let mut m = Matcher::new(self);
+        m.accept_str(text) && m.is_accepting()
+    }
+
+    /// The grammar can't match any text, not even an empty one
+    pub fn is_empty(&self) -> bool {
+        Matcher::new(self).is_dead()
+    }
+
+    // a rule that can reach itself without matching a character
+    fn left_recursive(&self) -> Option<usize> {
+        // the rules that can match an empty text
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, alts) in self.rules.iter().enumerate() {
+                if nullable[id] {
+                    continue;
+                }
+                nullable[id] = alts.iter().any(|seq| {
+                    seq.iter()
+                        .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                });
+                changed |= nullable[id];
+            }
+        }
+
+        // the rules that may be expanded before a character is matched
+        let firsts = self
+            .rules
+            .iter()
+            .map(|alts| {
+                alts.iter()
+                    .flat_map(|seq| {
+                        let n = seq
+                            .iter()
+                            .position(|e| !matches!(e, Element::Rule(r) if nullable[*r]))
+                            .map_or(seq.len(), |i| i + 1);
+                        seq[..n].iter().filter_map(|e| match e {
+                            Element::Rule(r) => Some(*r),
+                            Element::Chars(_) => None,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // a cycle in `firsts`, depth first with the rules on the current path marked `1` and the finished ones `2`
+        let mut state = vec![0u8; self.rules.len()];
+        for start in 0..self.rules.len() {
+            if state[start] != 0 {
+                continue;
+            }
+
+            let mut path = vec![(start, 0)];
+            state[start] = 1;
+            while let Some((id, next)) = path.last_mut() {
+                let Some(r) = firsts[*id].get(*next).copied() else {
+                    state[*id] = 2;
+                    path.pop();
+                    continue;
+                };
+                *next += 1;
+
+                match state[r] {
+                    1 => return Some(r),
+                    0 => {
+                        state[r] = 1;
+                        path.push((r, 0));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        None
+    }
+
+    // resolves rule references till every stack has a character class on top, or is empty
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>, depth: usize) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+
+        loop {
+            let Some(top) = stack.last().copied() else {
+                out.push(stack);
+                return;
+            };
+
+            let seq = &self.rules[top.rule][top.alt];
+            // the sequence is complete, continue with the rule that referenced it
+            if top.idx >= seq.len() {
+                stack.pop();
+                continue;
+            }
+
+            match &seq[top.idx] {
+                Element::Chars(_) => {
+                    out.push(stack);
+                    return;
+                }
+                Element::Rule(r) => {
+                    // the referencing position moves past the rule, dropped altogether if that completes it
+                    if top.idx + 1 < seq.len() {
+                        if let Some(t) = stack.last_mut() {
+                            t.idx += 1;
+                        }
+                    } else {
+                        stack.pop();
+                    }
+
+                    for alt in 0..self.rules[*r].len() {
+                        let mut s = stack.clone();
+                        s.push(Pos {
+                            rule: *r,
+                            alt,
+                            idx: 0,
+                        });
+                        self.expand(s, out, depth + 1);
+                    }
+
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The vocabulary as a trie of bytes, tokens sharing a prefix are matched together
+/// Tokens may hold part of a character, e.g. byte fallback tokens, special tokens are left out, they can't be generated under a grammar
+pub struct TokenTrie {
+    nodes: Vec<Node>,
+    texts: Vec<Option<Vec<u8>>>,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<u8, usize>,
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    /// `texts` is the utf-8 of every token id in the vocabulary
+    pub fn new(texts: Vec<Option<Vec<u8>>>) -> Self {
+        let mut nodes = vec![Node::default()];
+
+        for (id, text) in texts.iter().enumerate() {
+            let Some(text) = text.as_ref().filter(|t| !t.is_empty()) else {
+                continue;
+            };
+
+            let mut node = 0;
+            for c in text.iter().copied() {
+                node = match nodes[node].children.get(&c) {
+                    Some(n) => *n,
+                    None => {
+                        nodes.push(Node::default());
+                        let n = nodes.len() - 1;
+                        nodes[node].children.insert(c, n);
+                        n
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+
+        Self { nodes, texts }
+    }
+
+    /// Whether `token` can follow the parse so far
+    pub fn allows(&self, matcher: &Matcher, token: u32) -> bool {
+        match self.texts.get(token as usize) {
+            Some(Some(t)) if !t.is_empty() => {
+                let mut m = matcher.clone();
+                m.accept_bytes(t)
+            }
+            _ => false,
+        }
+    }
+
+    /// The tokens that can follow the parse so far
+    pub fn allowed(&self, matcher: &Matcher) -> Vec<u32> {
+        let mut allowed = vec![];
+        self.walk(0, matcher, &mut allowed);
+
+        allowed
+    }
+
+    fn walk(&self, node: usize, matcher: &Matcher, allowed: &mut Vec<u32>) {
+        for (b, child) in self.nodes[node].children.iter() {
+            let mut m = matcher.clone();
+            if !m.accept_byte(*b) {
+                continue;
+            }
+
+            allowed.extend_from_slice(&self.nodes[*child].tokens[..]);
+            self.walk(*child, &m, allowed);
+        }
+    }
+
+    /// The utf-8 of `token`, not necessarily whole characters
+    pub fn text(&self, token: u32) -> Option<&[u8]> {
+        self.texts.get(token as usize)?.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Builder, Element, Matcher, TokenTrie};
+
+    #[test]
+    fn token_mask() -> anyhow::Result<()> {
+        // root ::= "yes" | "no"
+        let mut b = Builder::default();
+        b.define(
+            "root",
+            vec![Element::literal("yes"), Element::literal("no")],
+        );
+        let g = b.build("root")?;
+
+        let trie = TokenTrie::new(
+            ["y", "ye", "yes", "n", "no", "nope", "maybe"]
+                .iter()
+                .map(|t| Some(t.as_bytes().to_vec()))
+                .chain([None])
+                .collect(),
+        );
+
+        let mut m = Matcher::new(&g);
+        let mut allowed = trie.allowed(&m);
+        allowed.sort();
+        assert_eq!(allowed, vec![0, 1, 2, 3, 4]);
+        assert!(!trie.allows(&m, 7));
+
+        assert!(m.accept_str("ye"));
+        assert_eq!(trie.allowed(&m), Vec::<u32>::new());
+        assert!(!m.is_accepting());
+
+        assert!(m.accept('s'));
+        assert!(m.is_done());
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_characters() -> anyhow::Result<()> {
+        // root ::= "é!" | [^a-zé]
+        let mut b = Builder::default();
+        b.define(
+            "root",
+            vec![
+                Element::literal("é!"),
+                vec![Element::class(&[('a', 'z'), ('é', 'é')], true)],
+            ],
+        );
+        let g = b.build("root")?;
+
+        // `é` is `0xC3 0xA9`, `ü` is `0xC3 0xBC`, `0xFF` is never utf-8
+        let trie = TokenTrie::new(vec![
+            Some(vec![0xC3]),
+            Some(vec![0xA9]),
+            Some(vec![0xBC]),
+            Some(vec![0xC3, 0xA9]),
+            Some(vec![0xA9, b'!']),
+            Some(vec![0xFF]),
+            Some(b"!".to_vec()),
+            Some(b"e".to_vec()),
+        ]);
+
+        let mut m = Matcher::new(&g);
+        let mut allowed = trie.allowed(&m);
+        allowed.sort();
+        assert_eq!(allowed, vec![0, 3, 6]);
+
+        // half of a character is never a complete parse
+        assert!(m.accept_byte(0xC3));
+        assert!(!m.is_accepting());
+        let mut allowed = trie.allowed(&m);
+        allowed.sort();
+        assert_eq!(allowed, vec![1, 2, 4]);
+
+        // `ü` is the negated class, `é` goes on with the literal
+        let mut u = m.clone();
+        assert!(u.accept_byte(0xBC));
+        assert!(u.is_done());
+        assert!(m.accept_bytes(&[0xA9, b'!']));
+        assert!(m.is_done());
+
+        Ok(())
+    }
+
+    #[test]
+    fn repeat() -> anyhow::Result<()> {
+        // root ::= [0-9]{2,4} "!"*
+        let mut b = Builder::default();
+        let digits = b.repeat(Element::class(&[('0', '9')], false), 2, Some(4));
+        let bang = b.repeat(Element::literal("!").remove(0), 0, None);
+        b.define("root", vec![[digits, bang].concat()]);
+        let g = b.build("root")?;
+
+        assert!(g.matches("12"));
+        assert!(g.matches("1234!!!"));
+        assert!(!g.matches("1"));
+        assert!(!g.matches("12345"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn undefined_rule() {
+        let mut b = Builder::default();
+        let item = b.rule("item");
+        b.define("root", vec![vec![Element::Rule(item)]]);
+
+        assert!(b.build("root").is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Meta, Response};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// How the instruction came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryMode {
+    Text,
+    Audio,
+}
+
+/// A single question - answer pair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    /// entries of the same conversation share this
+    pub conversation: String,
+    /// milliseconds since unix epoch
+    pub ts: u64,
+    pub mode: HistoryMode,
+    pub instruct: String,
+    pub answer: String,
+    pub meta: Meta,
+}
+
+/// The formats `History::export()` supports
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Jsonl,
+    Markdown,
+}
+
+/// An append-only `jsonl` log of every question and answer, in the app data directory
+/// Deletes rewrite the file, everything else is an append or a sequential read
+pub struct History {
+    path: PathBuf,
+    // serializes writers, a delete rewriting the file must not race an append
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(HISTORY_FILE),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Records a response, returns the new entry
+    pub fn record(
+        &self,
+        conversation: Option<&str>,
+        mode: HistoryMode,
+        res: &Response,
+    ) -> Result<HistoryEntry> {
+        let ts = now();
+        // unique even if two conversations start within the same millisecond
+        let id = || format!("{ts}-{:08x}", rand::random::<u32>());
+        let entry = HistoryEntry {
+            id: id(),
+            conversation: conversation.map_or_else(id, |c| c.to_string()),
+            ts,
+            mode,
+            instruct: res.instruct().to_string(),
+            answer: res.text().to_string(),
+            meta: res.meta().clone(),
+        };
+
+        let _guard = match self.lock.lock() {
+            Ok(g) => g,
+            Err(e) => {
+                error!("history: error acquiring lock: {e:?}");
+                anyhow::bail!("error recording history");
+            }
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+        Ok(entry)
+    }
+
+    /// All the entries, oldest first
+    pub fn all(&self) -> Result<Vec<HistoryEntry>> {
+        if !self.path.is_file() {
+            return Ok(vec![]);
+        }
+
+        let file = BufReader::new(File::open(&self.path)?);
+        let entries = file
+            .lines()
+            .filter_map(|l| {
+                let l = l.ok()?;
+                match serde_json::from_str::<HistoryEntry>(&l) {
+                    Ok(e) => Some(e),
+                    Err(e) => {
+                        // a torn write shouldn't make the rest of the history unreadable
+                        warn!("history: skipping malformed entry: {e:?}");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    /// The latest entry of each conversation, newest first, paginated
+    pub fn list(&self, offset: usize, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let mut seen = HashSet::new();
+        let latest = self
+            .all()?
+            .into_iter()
+            .rev()
+            .filter(|e| seen.insert(e.conversation.clone()));
+
+        Ok(latest.skip(offset).take(limit).collect())
+    }
+
+    /// All the entries of a conversation, oldest first, to reload it
+    pub fn conversation(&self, id: &str) -> Result<Vec<HistoryEntry>> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|e| e.conversation == id)
+            .collect())
+    }
+
+    /// Case insensitive search over the instructions and answers, newest first
+    pub fn search(&self, query: &str) -> Result<Vec<HistoryEntry>> {
+        let query = query.to_lowercase();
+
+        Ok(self
+            .all()?
+            .into_iter()
+            .rev()
+            .filter(|e| {
+                e.instruct.to_lowercase().contains(&query)
+                    || e.answer.to_lowercase().contains(&query)
+            })
+            .collect())
+    }
+
+    /// Deletes the entries whose `id` or `conversation` is in `ids`, returns the number of entries deleted
+    pub fn delete(&self, ids: &[String]) -> Result<usize> {
+        let _guard = match self.lock.lock() {
+            Ok(g) => g,
+            Err(e) => {
+                error!("history: error acquiring lock: {e:?}");
+                anyhow::bail!("error deleting history");
+            }
+        };
+
+        let all = self.all()?;
+        let total = all.len();
+        let keep = all
+            .into_iter()
+            .filter(|e| !ids.contains(&e.id) && !ids.contains(&e.conversation))
+            .collect::<Vec<_>>();
+
+        let deleted = total - keep.len();
+        if deleted == 0 {
+            return Ok(0);
+        }
+
+        // write to a temp file and swap it in, a crash midway leaves the old history intact
+        let tmp = self.path.with_extension("jsonl.tmp");
+        {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            for e in keep.iter() {
+                writeln!(w, "{}", serde_json::to_string(e)?)?;
+            }
+            w.flush()?;
+        }
+        std::fs::rename(tmp, &self.path)?;
+
+        Ok(deleted)
+    }
+
+    /// Exports the history, or just the given `conversation`, to `path`
+    pub fn export(
+        &self,
+        path: &Path,
+        format: ExportFormat,
+        conversation: Option<&str>,
+    ) -> Result<()> {
+        let mut entries = match conversation {
+            Some(c) => self.conversation(c)?,
+            None => self.all()?,
+        };
+
+        let mut w = BufWriter::new(File::create(path)?);
+        match format {
+            ExportFormat::Json => serde_json::to_writer_pretty(&mut w, &entries)?,
+            ExportFormat::Jsonl => {
+                for e in entries.iter() {
+                    writeln!(w, "{}", serde_json::to_string(e)?)?;
+                }
+            }
+            ExportFormat::Markdown => {
+                // conversations may interleave in the log, group them
+                entries.sort_by(|a, b| a.conversation.cmp(&b.conversation).then(a.ts.cmp(&b.ts)));
+
+                let mut current = "";
+                for e in entries.iter() {
+                    if e.conversation != current {
+                        writeln!(w, "## Conversation {}\n", e.conversation)?;
+                        current = &e.conversation;
+                    }
+                    writeln!(w, "**Q** ({:?}): {}\n", e.mode, e.instruct.trim())?;
+                    writeln!(w, "**A**: {}\n", e.answer.trim())?;
+                }
+            }
+        }
+        w.flush()?;
+
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportFormat, History, HistoryMode};
+    use crate::types::Response;
+
+    #[test]
+    fn record_list_delete() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("audio-instruct-{:08x}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+
+        let history = History::new(&dir);
+        let res = |q: &str, a: &str| Response::new(q, a, "Default", 1, 0);
+
+        let first = history.record(None, HistoryMode::Text, &res("Hi", "Hello"))?;
+        let second = history.record(None, HistoryMode::Audio, &res("Weather?", "Sunny"))?;
+        // new conversations started in the same millisecond still differ
+        assert_ne!(first.conversation, second.conversation);
+        let third = history.record(
+            Some(&first.conversation),
+            HistoryMode::Text,
+            &res("How are you?", "Fine, thanks"),
+        )?;
+
+        // the history is reloaded from disk
+        let history = History::new(&dir);
+        let latest = history.list(0, 10)?;
+        assert_eq!(
+            latest.iter().map(|e| e.id.as_str()).collect::<Vec<_>>(),
+            [third.id.as_str(), second.id.as_str()]
+        );
+        assert_eq!(history.list(1, 10)?[0].id, second.id);
+        assert!(history.list(2, 10)?.is_empty());
+
+        let conversation = history.conversation(&first.conversation)?;
+        assert_eq!(
+            conversation
+                .iter()
+                .map(|e| e.instruct.as_str())
+                .collect::<Vec<_>>(),
+            ["Hi", "How are you?"]
+        );
+
+        let found = history.search("SUNNY")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].mode, HistoryMode::Audio);
+
+        let md = dir.join("export.md");
+        history.export(&md, ExportFormat::Markdown, Some(&first.conversation))?;
+        let md = std::fs::read_to_string(md)?;
+        assert!(md.starts_with(&format!("## Conversation {}", first.conversation)));
+        assert!(md.contains("**A**: Fine, thanks") && !md.contains("Sunny"));
+
+        let json = dir.join("export.json");
+        history.export(&json, ExportFormat::Json, None)?;
+        let all: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(json)?)?;
+        assert_eq!(all.len(), 3);
+
+        // an entry by its id, then a whole conversation
+        assert_eq!(history.delete(std::slice::from_ref(&first.id))?, 1);
+        assert_eq!(history.conversation(&first.conversation)?.len(), 1);
+        assert_eq!(
+            history.delete(std::slice::from_ref(&second.conversation))?,
+            1
+        );
+        assert_eq!(history.delete(&["missing".to_string()])?, 0);
+        assert_eq!(history.all()?.len(), 1);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
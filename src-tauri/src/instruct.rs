@@ -1,81 +1,982 @@
 use std::{
     path::PathBuf,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc,
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
 
-use crate::{commands::Response, llama::LlamaWrap, whisper::WhisperWrap};
+use crate::{
+    config::{AsrConfig, Config, LlmConfig, Profile, Speed},
+    diarize::{self, Diarization},
+    dsp::{self, Highpass},
+    ecapa::{self, SpeakerEncoder},
+    events::{Event, EventBus, ModelKind, WakeEvent, WakeState},
+    grammar::Grammar,
+    history::{History, HistoryMode},
+    llama::{ChatOptions, LlamaWrap},
+    rag::{self, Documents, Embedder, Source},
+    scheduler::{Cancel, Priority, ScheduleError, Scheduler, Ticket},
+    slot::Slot,
+    tools::{ToolCall, Tools},
+    tts::{Speaker, Tts, VOICES},
+    types::{
+        Message, ModelInfo, PrefixCacheStats, Response, ResponseFormat, SystemInfo, Transcription,
+    },
+    utils::{self, SAMPLE_RATE},
+    wake::{Heard, Spotter},
+    whisper::WhisperWrap,
+};
+
+/// How often the listener checks for models to unload
+const IDLE_POLL: Duration = Duration::from_secs(5);
+
+/// Messages for our listener thread
+enum Signal {
+    /// a chunk of recorded audio
+    Chunk(Vec<f32>),
+    /// process whatever is pending and exit
+    Stop,
+}
 
 /// A struct to maintain our app state
 pub struct Instruct {
-    /// holds the instantiated `Llama` quantized `gguf` model and associated methods
-    llama: LlamaWrap,
-    /// holds the `distil-whisper` model and the associated methods
-    whisper: WhisperWrap,
+    /// holds the instantiated `Llama` quantized `gguf` model and associated methods, swapped by `switch_llm()`
+    llama: Arc<Slot<LlamaWrap>>,
+    /// holds the `whisper` model and the associated methods, swapped by `switch_asr()`
+    whisper: Arc<Slot<WhisperWrap>>,
     /// a channel for triggering Instruct methods through events
-    send: Sender<Vec<f32>>,
+    send: Sender<Signal>,
+    /// the listener thread consuming `send`, taken on `shutdown()`
+    listener: Mutex<Option<JoinHandle<()>>>,
+    /// the app data directory, where our models and `config.json` live
+    datadir: PathBuf,
+    /// the user configurable settings
+    config: RwLock<Config>,
+    /// events for the outside world, e.g. the position of a request in the queue
+    events: Arc<EventBus>,
+    /// the inference queue, all requests from the app and the HTTP server go through here
+    scheduler: Scheduler,
+    /// the log of every question and answer
+    history: History,
+    /// the tools the model can call
+    tools: Tools,
+    /// the user's documents to answer from
+    documents: Documents,
+    /// tells the speakers of a recording apart, loaded on the first diarization
+    voices: Slot<SpeakerEncoder>,
+    /// speaks the answers, if enabled
+    speaker: Speaker,
+    /// spots the wake phrase in the always-listening mode
+    wake: Mutex<Spotter>,
+    /// filters the recording as it streams in, if enabled
+    highpass: Mutex<Highpass>,
+}
+
+/// The answer to a conversation, along with the tools called for it
+struct Answer {
+    text: String,
+    n_tokens: usize,
+    elapsed: Duration,
+    prefix: PrefixCacheStats,
+    tools: Vec<ToolCall>,
 }
 
 impl Instruct {
+    /// Downloads all the models required by the app to `datadir`
+    pub fn download(datadir: PathBuf) -> Result<()> {
+        let config = Config::load(datadir.as_path())?;
+        LlamaWrap::download(datadir.as_path(), &config.llm)?;
+        WhisperWrap::download(datadir.as_path(), &config.asr)?;
+        Embedder::download(datadir.as_path())?;
+        SpeakerEncoder::download(datadir.as_path())?;
+        if config.tts.enabled {
+            Tts::download(datadir.as_path())?;
+        }
+
+        Ok(())
+    }
+
     pub fn new(datadir: PathBuf) -> Result<Arc<Self>> {
-        let llama = LlamaWrap::new(datadir.as_path())?;
-        let whisper = WhisperWrap::new(datadir.as_path())?;
+        let config = Config::load(datadir.as_path())?;
+        utils::init_threads(config.cpu.threads);
 
         let (send, recv) = channel();
 
+        let events = Arc::new(EventBus::default());
+        // the models are loaded the first time they are needed
+        let llama = {
+            let datadir = datadir.clone();
+            let llm = config.llm.clone();
+            let cpu = config.cpu.clone();
+            Arc::new(Slot::new(
+                ModelKind::Llm,
+                llm.file.clone(),
+                move || LlamaWrap::new(datadir.as_path(), &llm, &cpu),
+                Arc::clone(&events),
+            ))
+        };
+        let whisper = {
+            let datadir = datadir.clone();
+            let asr = config.asr.clone();
+            let cpu = config.cpu.clone();
+            Arc::new(Slot::new(
+                ModelKind::Asr,
+                asr.name(),
+                move || WhisperWrap::new(datadir.as_path(), &asr, &cpu),
+                Arc::clone(&events),
+            ))
+        };
+        let scheduler = Scheduler::new(
+            config.scheduler.capacity,
+            config.scheduler.workers,
+            Arc::clone(&events),
+        );
+        let voices = {
+            let datadir = datadir.clone();
+            Slot::new(
+                ModelKind::Speakers,
+                ecapa::MODEL_REPO.to_string(),
+                move || SpeakerEncoder::new(datadir.as_path()),
+                Arc::clone(&events),
+            )
+        };
+        let speaker = Speaker::new(datadir.clone(), Arc::clone(&events))?;
+        let wake = Mutex::new(Spotter::new(datadir.as_path())?);
+
         let app = Arc::new(Self {
             llama,
             whisper,
             send: send.clone(),
+            listener: Mutex::new(None),
+            history: History::new(datadir.as_path()),
+            documents: Documents::new(datadir.as_path(), Arc::clone(&events)),
+            voices,
+            datadir,
+            config: RwLock::new(config),
+            events,
+            scheduler,
+            tools: Tools::default(),
+            speaker,
+            wake,
+            highpass: Mutex::new(Highpass::default()),
         });
 
         // spawn a listner to receive incoming events
         let appclone = Arc::clone(&app);
-        thread::spawn(move || Self::listen(appclone, recv));
+        let listener = thread::Builder::new()
+            .name("listener".to_string())
+            .spawn(move || Self::listen(appclone, recv))?;
+        let failed = match app.listener.lock() {
+            Ok(mut l) => {
+                *l = Some(listener);
+                None
+            }
+            Err(e) => {
+                error!("new: error acquiring listener lock: {e:?}");
+                Some(listener)
+            }
+        };
+        // `shutdown()` couldn't join it, so it's stopped here along with the rest
+        if let Some(listener) = failed {
+            if let Err(e) = send.send(Signal::Stop) {
+                error!("new: error signalling listener: {e:?}");
+            }
+            if let Err(e) = listener.join() {
+                error!("new: error joining listener: {e:?}");
+            }
+            app.shutdown();
+            anyhow::bail!("error starting the listener");
+        }
 
         Ok(app)
     }
 
     /// Exposes an API to send data into our MPSC channel
     pub fn send(&self, data: Vec<f32>) -> Result<()> {
-        self.send.send(data)?;
+        self.send.send(Signal::Chunk(data))?;
+
+        Ok(())
+    }
+
+    /// Stops the listener and the inference workers and waits for them to exit
+    /// Pending audio chunks are drained, queued requests are cancelled and running ones signalled to stop
+    pub fn shutdown(&self) {
+        let listener = match self.listener.lock() {
+            Ok(mut l) => l.take(),
+            Err(e) => {
+                error!("shutdown: error acquiring listener lock: {e:?}");
+                None
+            }
+        };
+
+        if let Some(l) = listener {
+            // `Stop` is queued behind any pending chunks, so those are processed first
+            if let Err(e) = self.send.send(Signal::Stop) {
+                error!("shutdown: error signalling listener: {e:?}");
+            }
+            if let Err(e) = l.join() {
+                error!("shutdown: error joining listener: {e:?}");
+            }
+        }
+
+        self.scheduler.shutdown();
+        self.speaker.shutdown();
+    }
+
+    /// Registers a listener for the events emitted by the app
+    pub fn subscribe<F: Fn(&Event) + Send + Sync + 'static>(&self, f: F) {
+        self.events.subscribe(f);
+    }
+
+    /// Queues `f`, running on `models`, on the inference scheduler, `id` is generated if not provided
+    pub fn schedule<T, F>(
+        self: &Arc<Self>,
+        id: Option<String>,
+        priority: Priority,
+        models: &'static [ModelKind],
+        f: F,
+    ) -> Result<Ticket<T>, ScheduleError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Instruct, &Cancel) -> Result<T> + Send + 'static,
+    {
+        let app = Arc::clone(self);
+        self.scheduler
+            .submit(id, priority, models, move |cancel| f(&app, cancel))
+    }
+
+    /// Cancels a queued or running request, returns `false` if no such request is pending
+    pub fn cancel(&self, id: &str) -> bool {
+        self.scheduler.cancel(id)
+    }
+
+    /// Drains the audio recorded so far, to be used with `voice()`
+    pub fn take_audio(&self) -> Result<Vec<f32>> {
+        match self.whisper.loaded() {
+            Some(w) => w.take_data(),
+            // nothing was recorded
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The conversation history
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// The user's documents, to index or remove them
+    pub fn documents(&self) -> &Documents {
+        &self.documents
+    }
+
+    /// The tools the model can call, to register more or share files with `read_file`
+    pub fn tools(&self) -> &Tools {
+        &self.tools
+    }
+
+    // records the response in the history, unless disabled
+    fn record(&self, conversation: Option<&str>, mode: HistoryMode, res: &mut Response) {
+        if !self.config().history.enabled {
+            return;
+        }
+
+        match self.history.record(conversation, mode, res) {
+            Ok(e) => res.set_history(&e.id, &e.conversation),
+            Err(e) => error!("error recording history: {e:?}"),
+        }
+    }
+
+    /// Returns a copy of the current config
+    pub fn config(&self) -> Config {
+        match self.config.read() {
+            Ok(c) => c.clone(),
+            Err(e) => {
+                error!("config: error acquiring lock: {e:?}");
+                Config::default()
+            }
+        }
+    }
+
+    /// Updates and persists the config
+    pub fn set_config(&self, config: Config) -> Result<()> {
+        config.save(self.datadir.as_path())?;
+
+        let mut c = match self.config.write() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("set_config: error acquiring lock: {e:?}");
+                anyhow::bail!("error updating config");
+            }
+        };
+        *c = config;
 
         Ok(())
     }
 
-    fn listen(app: Arc<Instruct>, recv: Receiver<Vec<f32>>) {
-        while let Ok(next) = recv.recv() {
-            app.whisper.chunk(next);
+    /// The active persona profile
+    pub fn profile(&self) -> Profile {
+        match self.config.read() {
+            Ok(c) => c.active_profile(),
+            Err(e) => {
+                error!("profile: error acquiring lock: {e:?}");
+                Profile::default()
+            }
+        }
+    }
+
+    /// Creates a profile, or updates the profile with the same `name`
+    pub fn save_profile(&self, profile: Profile) -> Result<()> {
+        if profile.name.trim().is_empty() {
+            anyhow::bail!("profile name can't be empty");
+        }
+
+        let mut config = self.config();
+        match config.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => config.profiles.push(profile),
+        }
+
+        self.set_config(config)?;
+        // the active profile's system prompt may have changed
+        if let Some(llama) = self.llama.loaded() {
+            llama.clear_prefix_cache();
+        }
+
+        Ok(())
+    }
+
+    /// Makes the profile `name` the active one
+    pub fn select_profile(&self, name: &str) -> Result<()> {
+        let mut config = self.config();
+        if !config.profiles.iter().any(|p| p.name == name) {
+            anyhow::bail!("no profile named `{name}`");
+        }
+        config.profile = name.to_string();
+
+        self.set_config(config)?;
+        if let Some(llama) = self.llama.loaded() {
+            llama.clear_prefix_cache();
+        }
+
+        Ok(())
+    }
+
+    /// Loads the language model in `llm` in the background and switches to it once it's ready
+    /// Requests already running finish on the current model, which is freed after them
+    pub fn switch_llm(self: &Arc<Self>, llm: LlmConfig) -> Result<()> {
+        let instruct = Arc::clone(self);
+        let datadir = self.datadir.clone();
+        let load = {
+            let llm = llm.clone();
+            let cpu = self.config().cpu;
+            move || LlamaWrap::new(datadir.as_path(), &llm, &cpu)
+        };
+
+        self.llama.switch(llm.file.clone(), load, move |_, _| {
+            instruct.save_models(Some(llm), None)
+        })
+    }
+
+    /// Loads the `whisper` variant in `asr` in the background and switches to it once it's ready
+    /// The audio recorded so far is carried over to the new model
+    pub fn switch_asr(self: &Arc<Self>, asr: AsrConfig) -> Result<()> {
+        let instruct = Arc::clone(self);
+        let datadir = self.datadir.clone();
+        let load = {
+            let asr = asr.clone();
+            let cpu = self.config().cpu;
+            move || WhisperWrap::new(datadir.as_path(), &asr, &cpu)
+        };
+
+        self.whisper.switch(asr.name(), load, move |old, new| {
+            match old.map(|o| o.take_data()) {
+                Some(Ok(pcm)) => new.chunk(pcm, instruct.config().dsp.ahead()),
+                Some(Err(e)) => error!("switch_asr: error carrying over the recording: {e:?}"),
+                None => {}
+            }
+            instruct.save_models(None, Some(asr));
+        })
+    }
+
+    // persists the models switched to, so that they are loaded on the next start
+    fn save_models(&self, llm: Option<LlmConfig>, asr: Option<AsrConfig>) {
+        let mut config = self.config();
+        if let Some(llm) = llm {
+            config.llm = llm;
+        }
+        if let Some(asr) = asr {
+            config.asr = asr;
+        }
+
+        if let Err(e) = self.set_config(config) {
+            error!("save_models: {e:?}");
+        }
+    }
+
+    /// The device, threads and precision the models run with, and which models are loaded
+    pub fn system_info(&self) -> Result<SystemInfo> {
+        let config = self.config();
+        let device = utils::device()?;
+
+        let cpu_features = [
+            ("avx", candle_core::utils::with_avx()),
+            ("f16c", candle_core::utils::with_f16c()),
+            ("neon", candle_core::utils::with_neon()),
+            ("simd128", candle_core::utils::with_simd128()),
+            ("mkl", candle_core::utils::has_mkl()),
+            ("accelerate", candle_core::utils::has_accelerate()),
+        ]
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+        Ok(SystemInfo {
+            device: format!("{:?}", device.location()),
+            threads: rayon::current_num_threads(),
+            cores: thread::available_parallelism().map_or(1, |n| n.get()),
+            mmap: config.cpu.mmap,
+            asr_dtype: WhisperWrap::dtype(&config.asr, &device)
+                .as_str()
+                .to_string(),
+            llm: ModelInfo {
+                name: self.llama.name(),
+                loaded: self.llama.loaded().is_some(),
+            },
+            asr: ModelInfo {
+                name: self.whisper.name(),
+                loaded: self.whisper.loaded().is_some(),
+            },
+            cpu_features,
+        })
+    }
+
+    /// The names of the language and speech models in use, the `gguf` file and the `whisper` variant
+    pub fn models(&self) -> (String, String) {
+        (self.llama.name(), self.whisper.name())
+    }
+
+    /// Stops speaking, the sentences of the answers so far that weren't emitted yet are dropped
+    pub fn stop_speech(&self) {
+        self.speaker.stop();
+    }
+
+    /// Selects the voice and pace of the spoken answers
+    pub fn select_voice(&self, voice: &str, speed: Speed) -> Result<()> {
+        if !VOICES.contains(&voice) {
+            anyhow::bail!("no voice named `{voice}`");
+        }
+
+        let mut config = self.config();
+        config.tts.voice = voice.to_string();
+        config.tts.speed = speed;
+
+        self.set_config(config)
+    }
+
+    /// Records the wake phrase for the always-listening mode from the audio recorded so far
+    /// A few recordings make it more reliable, returns the number of recordings so far
+    pub fn enroll_wake_phrase(&self) -> Result<usize> {
+        if self.config().wake.enabled {
+            anyhow::bail!("turn off the always-listening mode to record the wake phrase");
+        }
+
+        let pcm = self.whisper.get()?.take_data()?;
+        match self.wake.lock() {
+            Ok(mut w) => w.enroll(&pcm[..]),
+            Err(e) => {
+                error!("enroll_wake_phrase: error acquiring lock: {e:?}");
+                anyhow::bail!("error recording wake phrase");
+            }
+        }
+    }
+
+    /// Forgets the recordings of the wake phrase
+    pub fn clear_wake_phrase(&self) -> Result<()> {
+        match self.wake.lock() {
+            Ok(mut w) => w.clear(),
+            Err(e) => {
+                error!("clear_wake_phrase: error acquiring lock: {e:?}");
+                anyhow::bail!("error clearing wake phrase");
+            }
+        }
+    }
+
+    // buffers `pcm` for `whisper`, loading it on the first recording
+    fn buffer(&self, pcm: Vec<f32>) {
+        match self.whisper.get() {
+            Ok(w) => w.chunk(pcm, self.config().dsp.ahead()),
+            Err(e) => error!("buffer: {e:?}"),
+        }
+    }
+
+    // buffers a recorded `chunk` for `whisper`
+    // in the always-listening mode only the audio following the wake phrase is buffered
+    fn hear(&self, mut chunk: Vec<f32>) {
+        let Config {
+            wake: config, dsp, ..
+        } = self.config();
+        if dsp.highpass {
+            match self.highpass.lock() {
+                Ok(mut h) => h.process(&mut chunk[..]),
+                Err(e) => error!("hear: error acquiring highpass lock: {e:?}"),
+            }
+        }
+
+        if !config.enabled {
+            self.buffer(chunk);
+            return;
+        }
+
+        let heard = match self.wake.lock() {
+            Ok(mut w) => w.feed(&chunk[..], &config),
+            Err(e) => {
+                error!("hear: error acquiring lock: {e:?}");
+                return;
+            }
+        };
+
+        for h in heard {
+            match h {
+                Heard::Wake(distance) => {
+                    info!("wake phrase spotted, distance: {distance:.3}");
+                    // whatever was buffered before isn't part of the instruction
+                    if let Some(Err(e)) = self.whisper.loaded().map(|w| w.take_data()) {
+                        error!("hear: error clearing audio: {e:?}");
+                    }
+                    self.events.emit(Event::Wake(WakeEvent {
+                        state: WakeState::Triggered,
+                    }));
+                }
+                Heard::Speech(pcm) => self.buffer(pcm),
+                Heard::End => self.events.emit(Event::Wake(WakeEvent {
+                    state: WakeState::Captured,
+                })),
+            }
+        }
+    }
+
+    fn listen(app: Arc<Instruct>, recv: Receiver<Signal>) {
+        let mut checked = Instant::now();
+        loop {
+            match recv.recv_timeout(IDLE_POLL) {
+                Ok(Signal::Chunk(c)) => app.hear(c),
+                Ok(Signal::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            // chunks keep coming while recording, the idle models are checked in between
+            if checked.elapsed() >= IDLE_POLL {
+                app.unload_idle();
+                checked = Instant::now();
+            }
+        }
+
+        info!("listener: stopped");
+    }
+
+    // frees the models that weren't used for their configured idle time
+    fn unload_idle(&self) {
+        let Config {
+            llm,
+            asr,
+            tts,
+            documents,
+            diarization,
+            ..
+        } = self.config();
+        self.llama
+            .unload_idle(Duration::from_secs(llm.idle_secs), |_| false);
+        // the recording so far would go with it
+        self.whisper
+            .unload_idle(Duration::from_secs(asr.idle_secs), |w| w.has_data());
+        self.speaker.unload_idle(Duration::from_secs(tts.idle_secs));
+        self.documents
+            .unload_idle(Duration::from_secs(documents.idle_secs));
+        self.voices
+            .unload_idle(Duration::from_secs(diarization.idle_secs), |_| false);
+    }
+
+    // the grammar for the requested `format`, `None` for free text
+    fn grammar(format: Option<&ResponseFormat>) -> Result<Option<Grammar>> {
+        match format {
+            Some(f) => f.grammar(),
+            None => Ok(None),
+        }
+    }
+
+    // the earlier turns of `conversation` followed by the new `instruct`
+    fn messages(&self, instruct: &str, conversation: Option<&str>) -> Vec<Message> {
+        let earlier = match conversation {
+            Some(c) if self.config().history.enabled => {
+                self.history.conversation(c).unwrap_or_else(|e| {
+                    error!("error reading conversation history: {e:?}");
+                    vec![]
+                })
+            }
+            _ => vec![],
+        };
+
+        let mut messages = earlier
+            .into_iter()
+            .flat_map(|e| {
+                [
+                    Message {
+                        role: "user".to_string(),
+                        content: e.instruct,
+                    },
+                    Message {
+                        role: "assistant".to_string(),
+                        content: e.answer,
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
+        messages.push(Message {
+            role: "user".to_string(),
+            content: instruct.to_string(),
+        });
+
+        messages
+    }
+
+    // adds the excerpts of the user's documents relevant to the latest message to it
+    // returns the excerpts as the sources to cite
+    fn with_documents(&self, messages: &mut [Message]) -> Vec<Source> {
+        let config = self.config().documents;
+        let Some(last) = messages.last_mut().filter(|_| config.enabled) else {
+            return vec![];
+        };
+
+        let hits = match self
+            .documents
+            .retrieve(&last.content, config.top_k, config.min_score)
+        {
+            Ok(h) if !h.is_empty() => h,
+            Ok(_) => return vec![],
+            Err(e) => {
+                error!("error searching documents: {e:?}");
+                return vec![];
+            }
+        };
+
+        let (content, sources) = rag::augment(&last.content, hits);
+        last.content = content;
+
+        sources
+    }
+
+    // generates the answer to `messages`, calling tools on the way if they are enabled
+    // the tools aren't offered with a `grammar`, the model has to answer in that format right away
+    // free text answers are spoken as they are generated, if speech is enabled
+    fn answer(
+        &self,
+        mut messages: Vec<Message>,
+        profile: &Profile,
+        grammar: Option<&Grammar>,
+        cancel: &Cancel,
+    ) -> Result<Answer> {
+        let config = self.config();
+        let tools = config.tools.enabled && grammar.is_none();
+        if tools {
+            messages.insert(
+                0,
+                Message {
+                    role: "system".to_string(),
+                    content: format!(
+                        "{}\n\n{}",
+                        profile.system_prompt.trim(),
+                        self.tools.prompt()
+                    ),
+                },
+            );
         }
+
+        let mut speech =
+            (config.tts.enabled && grammar.is_none()).then(|| self.speaker.utterance(&config.tts));
+
+        // the whole answer comes from the same model, even if it's switched meanwhile
+        let llama = self.llama.get()?;
+        let mut answer = Answer {
+            text: String::new(),
+            n_tokens: 0,
+            elapsed: Duration::ZERO,
+            prefix: PrefixCacheStats::default(),
+            tools: vec![],
+        };
+        loop {
+            let mut generated = String::new();
+            let mut spoken = 0;
+            let (text, n_tokens, elapsed, prefix) = llama.chat(
+                &messages[..],
+                profile,
+                &config.context,
+                ChatOptions {
+                    grammar,
+                    ..Default::default()
+                },
+                cancel,
+                |t| {
+                    let Some(s) = speech.as_mut() else {
+                        return;
+                    };
+                    generated.push_str(t);
+                    // a tool call isn't spoken, hold back an answer that may turn out to be one
+                    let head = generated.trim_start();
+                    if head.is_empty() || (tools && head.starts_with(['{', '<'])) {
+                        return;
+                    }
+                    s.push(&generated[spoken..]);
+                    spoken = generated.len();
+                },
+            )?;
+            answer.n_tokens += n_tokens;
+            answer.elapsed += elapsed;
+            // the first pass is the one that may reuse the cached system prompt
+            if answer.tools.is_empty() {
+                answer.prefix = prefix;
+            }
+
+            let call = if tools && answer.tools.len() < config.tools.max_calls {
+                self.tools.parse_call(&text)
+            } else {
+                None
+            };
+            let Some((name, parameters)) = call else {
+                if let Some(s) = speech {
+                    s.finish();
+                }
+                answer.text = text;
+                return Ok(answer);
+            };
+
+            info!("calling tool `{name}` with {parameters}");
+            let call = self.tools.call(&name, &parameters);
+            // the result goes back to the model for the final answer, or for another call
+            messages.push(Message {
+                role: "assistant".to_string(),
+                content: text.trim().to_string(),
+            });
+            messages.push(Message {
+                role: "ipython".to_string(),
+                content: call.result.to_string(),
+            });
+            answer.tools.push(call);
+        }
+    }
+
+    /// Public API to call text inference, the earlier turns of `conversation` are part of the prompt
+    /// The answer is constrained to `format` if provided, the response is recorded in the history under `conversation`
+    pub fn text(
+        &self,
+        instruct: &str,
+        conversation: Option<&str>,
+        format: Option<&ResponseFormat>,
+        cancel: &Cancel,
+    ) -> Result<Response> {
+        let profile = self.profile();
+        let grammar = Self::grammar(format)?;
+        let mut messages = self.messages(instruct, conversation);
+        let sources = self.with_documents(&mut messages[..]);
+        let answer = self.answer(messages, &profile, grammar.as_ref(), cancel)?;
+
+        let mut res = Response::new(
+            instruct,
+            &answer.text,
+            &profile.name,
+            answer.n_tokens as u32,
+            answer.elapsed.as_secs(),
+        );
+        res.set_prefix_cache(answer.prefix);
+        res.set_tools(answer.tools);
+        res.set_sources(sources);
+        res.parse_json(format)?;
+        self.record(conversation, HistoryMode::Text, &mut res);
+
+        Ok(res)
     }
 
-    /// Public API to call text inference
-    pub fn text(&self, instruct: &str) -> Result<Response> {
-        let (txt, n_tokens, elapsed) = self.llama.infer(instruct)?;
+    /// Public API to generate the next message of a chat conversation, `on_token` receives the text as it is generated
+    pub fn chat<F: FnMut(&str)>(
+        &self,
+        messages: &[Message],
+        max_new_tokens: Option<usize>,
+        format: Option<&ResponseFormat>,
+        cancel: &Cancel,
+        on_token: F,
+    ) -> Result<Response> {
+        let instruct = messages
+            .iter()
+            .rev()
+            .find(|m| m.role == "user")
+            .map_or("", |m| m.content.as_str());
+        let profile = self.profile();
+        let grammar = Self::grammar(format)?;
+        let (txt, n_tokens, elapsed, prefix) = self.llama.get()?.chat(
+            messages,
+            &profile,
+            &self.config().context,
+            ChatOptions {
+                max_new_tokens,
+                grammar: grammar.as_ref(),
+            },
+            cancel,
+            on_token,
+        )?;
 
-        Ok(Response::new(
+        let mut res = Response::new(
             instruct,
             &txt,
+            &profile.name,
             n_tokens as u32,
             elapsed.as_secs(),
-        ))
+        );
+        res.set_prefix_cache(prefix);
+        res.parse_json(format)?;
+
+        Ok(res)
+    }
+
+    /// Public API to trigger audio inference on the audio recorded so far
+    pub fn audio(&self, conversation: Option<&str>, cancel: &Cancel) -> Result<Response> {
+        let pcm = self.whisper.get()?.take_data()?;
+        self.voice(&pcm[..], conversation, None, cancel)
     }
 
-    /// Public API to trigger audio inference
-    pub fn audio(&self) -> Result<Response> {
-        let (transcript, n_tokens, elapsed) = self.whisper.infer()?;
-        let (generated, n_txt_tok, txt_elapsed) = self.llama.infer(&transcript)?;
+    // runs the clean up stages enabled in the config on `pcm`
+    // the recording was high-passed as it streamed in, and is left as is unless other stages are enabled
+    fn clean(&self, whisper: &WhisperWrap, pcm: &[f32]) -> Vec<f32> {
+        let config = self.config().dsp;
+        let (pcm, timings) = if whisper.is_recorded(pcm) {
+            if !config.ahead() {
+                info!("audio clean up: the recording is changed, its spectrogram is computed now rather than as it was recorded");
+            }
+            dsp::process_recorded(pcm, &config)
+        } else {
+            dsp::process(pcm, &config)
+        };
+        info!("audio clean up: {timings:?}");
 
-        Ok(Response::new(
+        pcm
+    }
+
+    /// Public API to transcribe the given `pcm` data, mono @ 16 kHz
+    pub fn transcribe(&self, pcm: &[f32], cancel: &Cancel) -> Result<Transcription> {
+        let whisper = self.whisper.get()?;
+        let pcm = self.clean(&whisper, pcm);
+        let (transcript, n_tokens, elapsed) = whisper.infer_pcm(&pcm[..], cancel)?;
+
+        Ok(Transcription::new(
             &transcript,
-            &generated,
-            (n_tokens + n_txt_tok) as u32,
-            (elapsed + txt_elapsed).as_secs(),
+            n_tokens as u32,
+            elapsed.as_secs(),
         ))
     }
+
+    /// Public API to transcribe a recording of several speakers, `pcm` mono @ 16 kHz, labelling who said what
+    /// The `speakers` are told apart by how their voices differ if their number isn't given
+    /// A summary with the action items is generated from the labelled transcript if `summarize`
+    pub fn diarize(
+        &self,
+        pcm: &[f32],
+        speakers: Option<usize>,
+        summarize: bool,
+        cancel: &Cancel,
+    ) -> Result<Diarization> {
+        let whisper = self.whisper.get()?;
+        let pcm = self.clean(&whisper, pcm);
+        let pcm = &pcm[..];
+        let segments = diarize::segments(pcm);
+        if segments.is_empty() {
+            anyhow::bail!("no speech in the recording");
+        }
+
+        let voices = self.voices.get()?;
+        let mut embeddings = Vec::with_capacity(segments.len());
+        let mut texts = Vec::with_capacity(segments.len());
+        for seg in segments.iter() {
+            if cancel.is_cancelled() {
+                anyhow::bail!("diarization cancelled");
+            }
+
+            let segment = &pcm[seg.clone()];
+            embeddings.push(voices.embed(segment)?);
+            // `whisper` wants a second of audio at least
+            let mut padded = segment.to_vec();
+            padded.resize(padded.len().max(SAMPLE_RATE as usize * 11 / 10), 0.);
+            texts.push(whisper.infer_pcm(&padded[..], cancel)?.0);
+        }
+
+        let labels = diarize::cluster(
+            &embeddings[..],
+            self.config().diarization.threshold,
+            speakers,
+        );
+        let mut diarization = Diarization::new(&segments[..], &labels[..], texts);
+        if !summarize || diarization.turns.is_empty() {
+            return Ok(diarization);
+        }
+
+        let config = self.config();
+        let messages = [Message {
+            role: "user".to_string(),
+            content: format!(
+                "{}\n\n{}",
+                diarize::SUMMARY_PROMPT,
+                diarization.transcript()
+            ),
+        }];
+        let (summary, ..) = self.llama.get()?.chat(
+            &messages[..],
+            &config.active_profile(),
+            &config.context,
+            ChatOptions::default(),
+            cancel,
+            |_| {},
+        )?;
+        diarization.summary = Some(summary.trim().to_string());
+
+        Ok(diarization)
+    }
+
+    /// Public API to transcribe the given `pcm` data and respond to the transcript as an instruction
+    /// The answer is constrained to `format` if provided, the response is recorded in the history under `conversation`
+    pub fn voice(
+        &self,
+        pcm: &[f32],
+        conversation: Option<&str>,
+        format: Option<&ResponseFormat>,
+        cancel: &Cancel,
+    ) -> Result<Response> {
+        // a bad format fails before the transcription, not after
+        let grammar = Self::grammar(format)?;
+        let whisper = self.whisper.get()?;
+        let pcm = self.clean(&whisper, pcm);
+        let (transcript, n_tokens, elapsed) = whisper.infer_pcm(&pcm[..], cancel)?;
+        drop(whisper);
+        let profile = self.profile();
+        let mut messages = self.messages(&transcript, conversation);
+        let sources = self.with_documents(&mut messages[..]);
+        let answer = self.answer(messages, &profile, grammar.as_ref(), cancel)?;
+
+        let mut res = Response::new(
+            &transcript,
+            &answer.text,
+            &profile.name,
+            (n_tokens + answer.n_tokens) as u32,
+            (elapsed + answer.elapsed).as_secs(),
+        );
+        res.set_prefix_cache(answer.prefix);
+        res.set_tools(answer.tools);
+        res.set_sources(sources);
+        res.parse_json(format)?;
+        self.record(conversation, HistoryMode::Audio, &mut res);
+
+        Ok(res)
+    }
 }
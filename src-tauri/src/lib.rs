@@ -0,0 +1,29 @@
+//! The core of `audio-instruct`: the `Llama` and `Whisper` wrappers and the `Instruct` pipeline tying them together.
+//! This is shared by the desktop (tauri) app and the headless `audio-instruct-cli`.
+
+#[macro_use]
+extern crate log;
+
+pub mod asr;
+pub mod config;
+pub mod diarize;
+pub mod dsp;
+pub mod ecapa;
+pub mod events;
+pub mod grammar;
+pub mod history;
+pub mod instruct;
+pub mod llama;
+pub mod mel;
+pub mod rag;
+pub mod scheduler;
+pub mod server;
+pub mod slot;
+pub mod template;
+pub mod tokenizer;
+pub mod tools;
+pub mod tts;
+pub mod types;
+pub mod utils;
+pub mod wake;
+pub mod whisper;
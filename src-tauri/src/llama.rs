@@ -1,86 +1,144 @@
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
 };
 
 use anyhow::Result;
-use candle_core::{quantized::gguf_file, Device, Tensor};
+use candle_core::{quantized::gguf_file, DType, Device, Tensor};
 use candle_transformers::{
     generation::{LogitsProcessor, Sampling},
-    models::quantized_llama::ModelWeights,
+    models::quantized_llama::{ModelWeights, MAX_SEQ_LEN},
 };
 use tokenizers::Tokenizer;
 
-use crate::utils::{device, hf_download};
+use crate::{
+    config::{ContextConfig, ContextStrategy, CpuConfig, LlmConfig, Profile},
+    grammar::{Grammar, Matcher, TokenTrie},
+    scheduler::Cancel,
+    template::ChatTemplate,
+    tokenizer,
+    types::{Message, PrefixCacheStats, PrefixOutcome},
+    utils::{device, hf_download, model_bytes},
+};
 
-const MODEL_FILE: &str = "Meta-Llama-3-8B-Instruct.Q8_0.gguf";
-const MODEL_REPO: &str = "QuantFactory/Meta-Llama-3-8B-Instruct-GGUF";
+/// Hard cap on the number of tokens generated, irrespective of the profile
+const MAX_NEW_TOKENS: usize = 2048;
 
-const TOKENIZER_REPO: &str = "unsloth/llama-3-8b";
-const LOCAL_MODEL_TOK: &str = "llama3-tokenizer.json";
+/// Max number of tokens for the summary of the earlier turns of a conversation
+const SUMMARY_TOKENS: usize = 256;
+/// Past this many suffix tokens it's faster to run the whole prompt in one pass than to reuse the cached prefix
+/// `ModelWeights` can't take a batch on top of its kv cache, so the suffix goes in a token at a time
+/// A longer suffix recomputes the prefix along with it, the cache is left as is and the request counted as skipped
+const MAX_SUFFIX_STEPS: usize = 64;
 
-const TEMPERATURE: f64 = 0.8;
-const TOP_P: f64 = 0.95;
-const TOP_K: usize = 40;
+/// Max number of times the latest message is cut before giving up on fitting it
+const MAX_CUTS: usize = 4;
+/// Marks where the middle of a message was cut out
+const CUT: &str = "\n...\n";
 
-const MAX_NEW_TOKENS: usize = 2048;
+const SUMMARY_PROMPT: &str = "Summarize the conversation below in a few sentences. Keep the names, facts, numbers and decisions, leave out pleasantries. Respond with the summary only.";
 
 /// A struct to maintain a initialized Llama quantized `gguf` model and associated methods
 pub struct LlamaWrap {
     device: Device,
     model: Arc<Mutex<ModelWeights>>,
     tokenizer: Tokenizer,
-    sampler: Arc<Mutex<LogitsProcessor>>,
-    stop_tokens: [u32; 2],
+    /// renders conversations to prompts, and knows the tokens that end a turn
+    template: ChatTemplate,
+    /// max number of tokens, prompt and generation together
+    context_length: usize,
+    /// the model state after the system prompt, reused across requests
+    prefix: Mutex<PrefixCache>,
+    /// the vocabulary as a trie, for constrained generation, built on first use
+    vocab: OnceLock<TokenTrie>,
+}
+
+/// Per request options for `chat`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChatOptions<'a> {
+    /// overrides the `profile`'s `max_tokens`
+    pub max_new_tokens: Option<usize>,
+    /// constrains the generated text to a grammar
+    pub grammar: Option<&'a Grammar>,
+}
+
+/// A snapshot of the model after running a prompt prefix through it
+/// The weights are reference counted, so a snapshot costs only its kv cache
+#[derive(Default)]
+struct PrefixCache {
+    entry: Option<(Vec<u32>, ModelWeights)>,
+    hits: u64,
+    misses: u64,
+    skipped: u64,
+}
+
+impl PrefixCache {
+    // counts the `outcome` of a request, `tokens` were restored from the cache
+    fn count(&mut self, outcome: PrefixOutcome, tokens: usize) -> PrefixCacheStats {
+        match outcome {
+            PrefixOutcome::Hit => self.hits += 1,
+            PrefixOutcome::Miss => self.misses += 1,
+            PrefixOutcome::Skipped => self.skipped += 1,
+        }
+
+        PrefixCacheStats {
+            outcome,
+            tokens,
+            hits: self.hits,
+            misses: self.misses,
+            skipped: self.skipped,
+        }
+    }
 }
 
 impl LlamaWrap {
     /// Initializer for new llama manager
-    pub fn new(dir: &Path) -> Result<Self> {
+    pub fn new(dir: &Path, config: &LlmConfig, cpu: &CpuConfig) -> Result<Self> {
         let device = device()?;
 
-        let model_path = Self::model_path(dir)?;
-        let (model, tokenizer) = Self::load_model(model_path.as_path(), &device)?;
-        let stop_tokens = [
-            tokenizer.token_to_id("<|eot_id|>").unwrap(),
-            tokenizer.token_to_id("<|end_of_text|>").unwrap(),
-        ];
-
-        let sampler = Arc::new(Mutex::new(LogitsProcessor::from_sampling(
-            42,
-            Sampling::TopKThenTopP {
-                k: TOP_K,
-                p: TOP_P,
-                temperature: TEMPERATURE,
-            },
-        )));
+        let model_path = Self::model_path(dir, config)?;
+        let (model, tokenizer, template, context_length) =
+            Self::load_model(model_path.as_path(), config, cpu, &device)?;
 
         info!("Llama ready!");
         Ok(Self {
             device,
             model,
             tokenizer,
-            sampler,
-            stop_tokens,
+            template,
+            context_length,
+            prefix: Mutex::new(PrefixCache::default()),
+            vocab: OnceLock::new(),
         })
     }
 
-    fn model_path(base_dir: &Path) -> Result<PathBuf> {
+    /// Downloads the model, and the tokenizer override if any, to `dir` if they are not already present
+    pub fn download(dir: &Path, config: &LlmConfig) -> Result<()> {
+        Self::model_path(dir, config)?;
+
+        Ok(())
+    }
+
+    // the local file name of the `tokenizer.json` override from `repo`
+    fn tokenizer_file(repo: &str) -> String {
+        format!("{}-tokenizer.json", repo.replace('/', "--"))
+    }
+
+    fn model_path(base_dir: &Path, config: &LlmConfig) -> Result<PathBuf> {
         let model_path = base_dir;
 
         // The file doesn't exist, lets download it
-        if !model_path.join(MODEL_FILE).is_file() {
-            hf_download(base_dir, MODEL_REPO, MODEL_FILE, None)?;
+        if !model_path.join(&config.file).is_file() {
+            hf_download(base_dir, &config.repo, &config.file, None)?;
         }
 
-        // Download the tokenizer.json, and rename it, its a separate repo
-        if !model_path.join(LOCAL_MODEL_TOK).is_file() {
-            hf_download(
-                base_dir,
-                TOKENIZER_REPO,
-                "tokenizer.json",
-                Some(LOCAL_MODEL_TOK),
-            )?;
+        // The tokenizer comes with the `gguf`, a `tokenizer.json` is downloaded only if explicitly asked for
+        if let Some(repo) = config.tokenizer_repo.as_deref() {
+            let tok_file = Self::tokenizer_file(repo);
+            if !model_path.join(&tok_file).is_file() {
+                hf_download(base_dir, repo, "tokenizer.json", Some(&tok_file))?;
+            }
         }
 
         Ok(model_path.to_path_buf())
@@ -88,57 +146,407 @@ impl LlamaWrap {
 
     fn load_model(
         model_dir: &Path,
+        config: &LlmConfig,
+        cpu: &CpuConfig,
         device: &Device,
-    ) -> Result<(Arc<Mutex<ModelWeights>>, Tokenizer)> {
-        let model_file = model_dir.join(MODEL_FILE);
-        let tok_file = model_dir.join(LOCAL_MODEL_TOK);
+    ) -> Result<(Arc<Mutex<ModelWeights>>, Tokenizer, ChatTemplate, usize)> {
+        let model_file = model_dir.join(&config.file);
 
-        info!("Loading gguf model @{:?}", model_file);
+        info!("Loading gguf model @{:?}, mmap: {}", model_file, cpu.mmap);
 
-        let mut file = std::fs::File::open(model_file)?;
+        let bytes = model_bytes(&model_file, cpu.mmap)?;
+        let mut file = std::io::Cursor::new(&bytes[..]);
         // reading the params from file
         let model = gguf_file::Content::read(&mut file)?;
 
+        let tokenizer = Self::load_tokenizer(model_dir, &model, config)?;
+
+        // the template is in the metadata, read it before the weights consume `model`
+        let template = ChatTemplate::from_gguf(&model, &tokenizer)?;
+        let context_length = Self::context_length(&model);
+        info!("Context length: {context_length}");
+
         let model = Arc::new(Mutex::new(ModelWeights::from_gguf(
             model, &mut file, device,
         )?));
 
-        info!("Loading tokenizer @{:?}", tok_file);
-        let tokenizer = Tokenizer::from_file(tok_file).unwrap();
+        Ok((model, tokenizer, template, context_length))
+    }
+
+    // the tokenizer embedded in the `gguf`, or the `tokenizer.json` override if one is configured
+    fn load_tokenizer(
+        model_dir: &Path,
+        model: &gguf_file::Content,
+        config: &LlmConfig,
+    ) -> Result<Tokenizer> {
+        let Some(repo) = config.tokenizer_repo.as_deref() else {
+            info!("Loading tokenizer from gguf metadata");
+            return tokenizer::from_gguf(&model.metadata);
+        };
+
+        let tok_file = model_dir.join(Self::tokenizer_file(repo));
+        info!("Loading tokenizer override @{:?}", tok_file);
+        let tokenizer = match Tokenizer::from_file(tok_file) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("error loading tokenizer override: {e:?}");
+                anyhow::bail!("error loading tokenizer from `{repo}`");
+            }
+        };
+
+        // a tokenizer for a different vocab would silently produce garbage
+        let n_vocab = match model.metadata.get("tokenizer.ggml.tokens") {
+            Some(t) => t.to_vec()?.len(),
+            None => anyhow::bail!("gguf has no `tokenizer.ggml.tokens`"),
+        };
+        if tokenizer.get_vocab_size(true) != n_vocab {
+            anyhow::bail!(
+                "tokenizer from `{repo}` has {} tokens, the model expects {n_vocab}",
+                tokenizer.get_vocab_size(true)
+            );
+        }
 
-        Ok((model, tokenizer))
+        Ok(tokenizer)
     }
 
-    /// Helper function to convert incoming `command` to templated prompt
-    /// Prompt template: https://github.com/meta-llama/llama3/blob/main/llama/tokenizer.py#L202
-    pub fn preproc(txt: &str) -> String {
-        format!(
-            "<|start_header_id|>system<|end_header_id|>\n\nYou are a knowledgeable, efficient, intelligent and direct AI assistant. Provide concise answers, focusing on the key information needed. Respond only with the answer to the instruction based on the given data. Do not add any additional text, introduction, context or explanation. If you are unsure about an answer, truthfully return \"Not Known\".<|eot_id|><|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n",
-            txt
-        )
+    // the context length the model was trained with, capped at what `ModelWeights` supports
+    fn context_length(model: &gguf_file::Content) -> usize {
+        let arch = model
+            .metadata
+            .get("general.architecture")
+            .and_then(|a| a.to_string().ok())
+            .map_or("llama", |a| a.as_str());
+
+        model
+            .metadata
+            .get(&format!("{arch}.context_length"))
+            .and_then(|c| c.to_u32().ok())
+            .map_or(MAX_SEQ_LEN, |c| (c as usize).min(MAX_SEQ_LEN))
     }
 
-    // returns the (generated text, number of tokens generated, duration)
-    pub fn infer(&self, instruct: &str) -> Result<(String, usize, std::time::Duration)> {
-        let prompt = Self::preproc(instruct);
-        let prompttokens = match self.tokenizer.encode(prompt, true) {
-            Ok(t) => t,
+    /// Helper function to convert a chat conversation to a prompt with the model's chat template
+    pub fn preproc(&self, messages: &[Message]) -> Result<String> {
+        self.template.render(messages)
+    }
+
+    // the conversation with the `system` prompt prepended, unless it brings its own
+    fn with_system(messages: &[Message], system: &str) -> Vec<Message> {
+        if messages.iter().any(|m| m.role == "system") {
+            return messages.to_vec();
+        }
+
+        let mut msgs = Vec::with_capacity(messages.len() + 1);
+        msgs.push(Message {
+            role: "system".to_string(),
+            content: system.trim().to_string(),
+        });
+        msgs.extend_from_slice(messages);
+
+        msgs
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        // the chat template takes care of the special tokens, including `bos`
+        match self.tokenizer.encode(text, false) {
+            Ok(t) => Ok(t.get_ids().to_vec()),
             Err(e) => {
                 error!("infer: error tokenizing prompt tokens: {e:?}");
                 anyhow::bail!("error tokenizing prompt");
             }
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        match self.tokenizer.decode(tokens, false) {
+            Ok(t) => Ok(t),
+            Err(e) => {
+                error!("Error generating tokens: {e:?}");
+                anyhow::bail!("Error generating tokens")
+            }
+        }
+    }
+
+    /// Drops the cached prefix, e.g. when the system prompt changes
+    pub fn clear_prefix_cache(&self) {
+        match self.prefix.lock() {
+            Ok(mut c) => c.entry = None,
+            Err(e) => error!("clear_prefix_cache: error acquiring lock: {e:?}"),
+        }
+    }
+
+    // the number of leading prompt tokens that depend only on the `system` prompt
+    // found as the common prefix of two conversations that differ only in the user message, so it works with any template
+    fn prefix_len(&self, messages: &[Message], prompt: &[u32]) -> usize {
+        let Some(system) = messages.first().filter(|m| m.role == "system") else {
+            return 0;
         };
 
-        let mut model = match self.model.lock() {
-            Ok(m) => m,
+        let probe = |content: &str| {
+            let msgs = [
+                system.clone(),
+                Message {
+                    role: "user".to_string(),
+                    content: content.to_string(),
+                },
+            ];
+            self.preproc(&msgs).and_then(|p| self.encode(&p)).ok()
+        };
+        let (Some(a), Some(b)) = (probe("a"), probe("b")) else {
+            return 0;
+        };
+
+        let n = a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count();
+        // the prompt must start with the very same tokens, with at least one token after them
+        if prompt.len() > n && prompt[..n] == a[..n] {
+            n
+        } else {
+            0
+        }
+    }
+
+    // returns the (generated text, number of tokens generated, duration, prefix cache stats)
+    pub fn infer(
+        &self,
+        instruct: &str,
+        profile: &Profile,
+        context: &ContextConfig,
+        cancel: &Cancel,
+    ) -> Result<(String, usize, std::time::Duration, PrefixCacheStats)> {
+        let messages = [Message {
+            role: "user".to_string(),
+            content: instruct.to_string(),
+        }];
+
+        self.chat(
+            &messages,
+            profile,
+            context,
+            ChatOptions::default(),
+            cancel,
+            |_| {},
+        )
+    }
+
+    /// Generates the next `assistant` message for the given conversation
+    /// Conversations that don't fit the context window are shortened with the `context` strategy
+    /// `on_token` is called with every new piece of text as it is generated
+    // returns the (generated text, number of tokens generated, duration, prefix cache stats)
+    pub fn chat<F: FnMut(&str)>(
+        &self,
+        messages: &[Message],
+        profile: &Profile,
+        context: &ContextConfig,
+        opts: ChatOptions,
+        cancel: &Cancel,
+        on_token: F,
+    ) -> Result<(String, usize, std::time::Duration, PrefixCacheStats)> {
+        let max_new_tokens = opts
+            .max_new_tokens
+            .unwrap_or(profile.max_tokens)
+            .min(MAX_NEW_TOKENS);
+
+        let messages = Self::with_system(messages, &profile.system_prompt);
+        let (messages, prompt, max_new_tokens) = fit(
+            self,
+            messages,
+            context.strategy,
+            context,
+            max_new_tokens,
+            cancel,
+        )?;
+        let prefix = self.prefix_len(&messages[..], &prompt[..]);
+
+        self.generate(
+            &prompt[..],
+            prefix,
+            self.sampler(profile, opts.grammar),
+            max_new_tokens,
+            cancel,
+            on_token,
+        )
+    }
+
+    // summarizes the given turns with the model itself
+    fn summarize(
+        &self,
+        turns: &[Message],
+        context: &ContextConfig,
+        cancel: &Cancel,
+    ) -> Result<String> {
+        let transcript = turns
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content.trim()))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let messages = [
+            Message {
+                role: "system".to_string(),
+                content: SUMMARY_PROMPT.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+            },
+        ];
+
+        let profile = Profile {
+            temperature: 0.,
+            max_tokens: SUMMARY_TOKENS,
+            ..Profile::default()
+        };
+        // the conversation to summarize may itself be too long, it's shortened without recursing into summarization
+        let (_, prompt, max_new_tokens) = fit(
+            self,
+            messages.to_vec(),
+            ContextStrategy::MiddleOut,
+            context,
+            SUMMARY_TOKENS,
+            cancel,
+        )?;
+        // the summary has a system prompt of its own, it shouldn't evict the cached prefix
+        let (summary, n_tokens, elapsed, _) = self.generate(
+            &prompt[..],
+            0,
+            self.sampler(&profile, None),
+            max_new_tokens,
+            cancel,
+            |_| {},
+        )?;
+
+        info!(
+            "summarized {} turns in {n_tokens} tokens, {:?}",
+            turns.len(),
+            elapsed
+        );
+        Ok(summary)
+    }
+
+    // a new sampler for every request, configured by the profile and constrained to the `grammar` if any
+    fn sampler<'g>(&'g self, profile: &Profile, grammar: Option<&'g Grammar>) -> Sampler<'g> {
+        let sampling = if profile.temperature <= 0. {
+            Sampling::ArgMax
+        } else {
+            Sampling::TopKThenTopP {
+                k: profile.top_k,
+                p: profile.top_p,
+                temperature: profile.temperature,
+            }
+        };
+
+        Sampler {
+            logits: LogitsProcessor::from_sampling(
+                profile.seed.unwrap_or_else(rand::random),
+                sampling,
+            ),
+            grammar: grammar.map(|g| (Matcher::new(g), self.token_trie())),
+        }
+    }
+
+    // the utf-8 of every token, for matching tokens against a grammar
+    // special tokens can't be generated under a grammar
+    fn token_trie(&self) -> &TokenTrie {
+        self.vocab.get_or_init(|| {
+            let start = std::time::Instant::now();
+            let special = self
+                .tokenizer
+                .get_added_tokens_decoder()
+                .into_iter()
+                .filter_map(|(id, t)| t.special.then_some(id))
+                .collect::<HashSet<_>>();
+
+            let texts = (0..self.tokenizer.get_vocab_size(true) as u32)
+                .map(|id| {
+                    if special.contains(&id) {
+                        return None;
+                    }
+                    let text = self.tokenizer.decode(&[id], false).ok()?;
+                    // a piece of a character, the bytes of it are in the token itself
+                    if text.contains('\u{FFFD}') {
+                        return tokenizer::token_bytes(&self.tokenizer.id_to_token(id)?);
+                    }
+
+                    // sentencepiece decoders strip the leading space of the text, the token has it though
+                    match self.tokenizer.id_to_token(id) {
+                        Some(t) if t.starts_with('▁') && !text.starts_with(' ') => {
+                            Some(format!(" {text}").into_bytes())
+                        }
+                        _ => Some(text.into_bytes()),
+                    }
+                })
+                .collect();
+
+            info!("Token trie built in {:?}", start.elapsed());
+            TokenTrie::new(texts)
+        })
+    }
+
+    // runs the prompt through the model, the first `prefix` tokens are restored from the cache if possible
+    // returns the logits for the next token
+    fn prefill(
+        &self,
+        model: &mut ModelWeights,
+        prompt: &[u32],
+        prefix: usize,
+        cancel: &Cancel,
+    ) -> Result<(Tensor, PrefixCacheStats)> {
+        let mut cache = match self.prefix.lock() {
+            Ok(c) => c,
             Err(e) => {
-                error!("infer: acquiring model mutex lock: {e:?}");
-                anyhow::bail!("error acquiring model");
+                error!("prefill: error acquiring prefix cache lock: {e:?}");
+                anyhow::bail!("error acquiring prefix cache");
             }
         };
 
-        let mut sampler = match self.sampler.lock() {
-            Ok(s) => s,
+        if prefix == 0 || prompt.len() - prefix > MAX_SUFFIX_STEPS {
+            let stats = cache.count(PrefixOutcome::Skipped, 0);
+            drop(cache);
+            let input = Tensor::new(prompt, &self.device)?.unsqueeze(0)?;
+            return Ok((model.forward(&input, 0)?, stats));
+        }
+
+        let hit = cache
+            .entry
+            .as_ref()
+            .is_some_and(|(tokens, _)| tokens[..] == prompt[..prefix]);
+        let stats = if let (true, Some((_, snapshot))) = (hit, cache.entry.as_ref()) {
+            *model = snapshot.clone();
+            cache.count(PrefixOutcome::Hit, prefix)
+        } else {
+            let input = Tensor::new(&prompt[..prefix], &self.device)?.unsqueeze(0)?;
+            model.forward(&input, 0)?;
+            cache.entry = Some((prompt[..prefix].to_vec(), model.clone()));
+            cache.count(PrefixOutcome::Miss, 0)
+        };
+        drop(cache);
+
+        let mut logits = None;
+        for (i, &t) in prompt.iter().enumerate().skip(prefix) {
+            if cancel.is_cancelled() {
+                anyhow::bail!("generation cancelled");
+            }
+
+            let input = Tensor::new(&[t], &self.device)?.unsqueeze(0)?;
+            logits = Some(model.forward(&input, i)?);
+        }
+
+        match logits {
+            Some(l) => Ok((l, stats)),
+            None => anyhow::bail!("empty prompt suffix"),
+        }
+    }
+
+    fn generate<F: FnMut(&str)>(
+        &self,
+        prompt: &[u32],
+        prefix: usize,
+        mut sampler: Sampler,
+        max_new_tokens: usize,
+        cancel: &Cancel,
+        mut on_token: F,
+    ) -> Result<(String, usize, std::time::Duration, PrefixCacheStats)> {
+        let mut model = match self.model.lock() {
+            Ok(m) => m,
             Err(e) => {
                 error!("infer: acquiring model mutex lock: {e:?}");
                 anyhow::bail!("error acquiring model");
@@ -146,59 +554,681 @@ impl LlamaWrap {
         };
 
         let mut all_tokens = vec![];
+        let mut stream = TokenStream::default();
         let start_prompt_processing = std::time::Instant::now();
 
-        let mut input = Tensor::new(prompttokens.get_ids(), &self.device)?.unsqueeze(0)?;
-        let mut logits = model.forward(&input, 0)?;
-        let mut next = sampler.sample(&logits.squeeze(0)?)?;
+        let (mut logits, stats) = self.prefill(&mut model, prompt, prefix, cancel)?;
+        let mut index_pos = prompt.len();
 
-        all_tokens.push(next);
-
-        for i in prompttokens.len()..MAX_NEW_TOKENS {
-            input = Tensor::new(&[next], &self.device)?.unsqueeze(0)?;
+        // `max_new_tokens` counts the generated tokens only, the prompt can be longer
+        while all_tokens.len() < max_new_tokens {
+            let next = sampler.sample(&logits.squeeze(0)?, self.template.stop_tokens())?;
+            if self.template.stop_tokens().contains(&next) {
+                break;
+            }
 
-            logits = model.forward(&input, i)?;
-            next = sampler.sample(&logits.squeeze(0)?)?;
+            all_tokens.push(next);
+            if let Some(t) = stream.next(&self.tokenizer, next) {
+                on_token(&t);
+            }
 
-            if self.stop_tokens.contains(&next) {
+            // the grammar is complete, nothing else can follow
+            if sampler.accept(next) {
                 break;
             }
 
-            all_tokens.push(next);
+            if cancel.is_cancelled() {
+                anyhow::bail!("generation cancelled");
+            }
+
+            let input = Tensor::new(&[next], &self.device)?.unsqueeze(0)?;
+            logits = model.forward(&input, index_pos)?;
+            index_pos += 1;
         }
 
-        let tk = match self.tokenizer.decode(&all_tokens[..], false) {
-            Ok(t) => t,
-            Err(e) => {
-                error!("Error generating tokens: {e:?}");
-                anyhow::bail!("Error generating tokens")
-            }
-        };
+        if sampler.is_incomplete() {
+            anyhow::bail!("ran out of tokens before the response was complete");
+        }
 
         Ok((
-            tk,
+            self.decode(&all_tokens[..])?,
             all_tokens.len(),
             std::time::Instant::now().duration_since(start_prompt_processing),
+            stats,
         ))
     }
 }
 
+// what shortening a conversation needs of the model, so that it can be done without one in tests
+trait Shorten {
+    // max number of tokens, prompt and generation together
+    fn window(&self) -> usize;
+    // the prompt of the conversation, with the chat template applied
+    fn prompt(&self, messages: &[Message]) -> Result<Vec<u32>>;
+    fn tokens(&self, text: &str) -> Result<Vec<u32>>;
+    fn text(&self, tokens: &[u32]) -> Result<String>;
+    fn summary(
+        &self,
+        turns: &[Message],
+        context: &ContextConfig,
+        cancel: &Cancel,
+    ) -> Result<String>;
+}
+
+impl Shorten for LlamaWrap {
+    fn window(&self) -> usize {
+        self.context_length
+    }
+
+    fn prompt(&self, messages: &[Message]) -> Result<Vec<u32>> {
+        self.encode(&self.preproc(messages)?)
+    }
+
+    fn tokens(&self, text: &str) -> Result<Vec<u32>> {
+        self.encode(text)
+    }
+
+    fn text(&self, tokens: &[u32]) -> Result<String> {
+        self.decode(tokens)
+    }
+
+    fn summary(
+        &self,
+        turns: &[Message],
+        context: &ContextConfig,
+        cancel: &Cancel,
+    ) -> Result<String> {
+        self.summarize(turns, context, cancel)
+    }
+}
+
+// shortens the conversation till the prompt fits the context window
+// the generation budget gives way first, down to `min_new_tokens`, then the conversation with `strategy`
+// returns the shortened conversation, its prompt tokens and the number of tokens that can be generated
+fn fit(
+    model: &impl Shorten,
+    mut messages: Vec<Message>,
+    strategy: ContextStrategy,
+    context: &ContextConfig,
+    max_new_tokens: usize,
+    cancel: &Cancel,
+) -> Result<(Vec<Message>, Vec<u32>, usize)> {
+    let window = model.window();
+    let limit = window.saturating_sub(context.min_new_tokens.min(max_new_tokens));
+    let mut summarized = false;
+    let mut cuts = 0;
+
+    loop {
+        if cancel.is_cancelled() {
+            anyhow::bail!("generation cancelled");
+        }
+
+        let tokens = model.prompt(&messages[..])?;
+        if tokens.len() <= limit {
+            let budget = max_new_tokens.min(window - tokens.len());
+            return Ok((messages, tokens, budget));
+        }
+
+        info!(
+            "prompt of {} tokens exceeds {limit} tokens, shortening with {strategy:?}",
+            tokens.len()
+        );
+
+        // the earlier turns go first, the `system` prompt and the latest message are always kept
+        let first = messages
+            .iter()
+            .position(|m| m.role != "system")
+            .unwrap_or(messages.len());
+        let last = messages.len().saturating_sub(1);
+
+        if first < last {
+            match strategy {
+                ContextStrategy::TruncateOldest => drop_turn(&mut messages, first),
+                ContextStrategy::MiddleOut => drop_turn(&mut messages, first + (last - first) / 2),
+                ContextStrategy::Summarize if !summarized => {
+                    let summary = model.summary(&messages[first..last], context, cancel)?;
+                    messages.drain(first..last);
+                    add_summary(&mut messages, &summary);
+                    summarized = true;
+                }
+                // the summary alone doesn't leave enough room, we are back to dropping turns
+                ContextStrategy::Summarize => drop_turn(&mut messages, first),
+            }
+
+            continue;
+        }
+
+        // only the latest message is left, so we shorten the message itself
+        // the cut text may not encode back to the same tokens, so it can take another cut or two
+        cuts += 1;
+        let Some(msg) = messages.last_mut().filter(|_| cuts <= MAX_CUTS) else {
+            anyhow::bail!("the prompt doesn't fit the context window of {window} tokens");
+        };
+        let content = model.tokens(&msg.content)?;
+        // the separator marking a cut in the middle takes room of its own
+        let sep = match strategy {
+            ContextStrategy::TruncateOldest => 0,
+            ContextStrategy::MiddleOut | ContextStrategy::Summarize => model.tokens(CUT)?.len(),
+        };
+        let excess = tokens.len() - limit + sep;
+        if excess >= content.len() {
+            anyhow::bail!("the prompt doesn't fit the context window of {window} tokens");
+        }
+
+        let keep = content.len() - excess;
+        msg.content = match strategy {
+            ContextStrategy::TruncateOldest => model.text(&content[excess..])?,
+            // a long dictation is more likely to lose its point in the middle than at either end
+            ContextStrategy::MiddleOut | ContextStrategy::Summarize => {
+                let head = keep / 2;
+                format!(
+                    "{}{CUT}{}",
+                    model.text(&content[..head])?,
+                    model.text(&content[content.len() - (keep - head)..])?
+                )
+            }
+        };
+    }
+}
+
+/// Samples the next token, constrained to a grammar if any
+struct Sampler<'g> {
+    logits: LogitsProcessor,
+    grammar: Option<(Matcher<'g>, &'g TokenTrie)>,
+}
+
+impl Sampler<'_> {
+    // the next token, `stop` tokens are allowed only once the grammar is complete
+    fn sample(&mut self, logits: &Tensor, stop: &[u32]) -> Result<u32> {
+        let Some((matcher, trie)) = self.grammar.as_ref() else {
+            return Ok(self.logits.sample(logits)?);
+        };
+        let allows = |t: u32| {
+            if stop.contains(&t) {
+                matcher.is_accepting()
+            } else {
+                trie.allows(matcher, t)
+            }
+        };
+
+        // the model's own pick is usually allowed, so we try it before masking the whole vocabulary
+        // rejecting it and sampling again from the masked logits is the same as sampling from the masked logits
+        let next = self.logits.sample(logits)?;
+        if allows(next) {
+            return Ok(next);
+        }
+
+        let mut allowed = trie.allowed(matcher);
+        if matcher.is_accepting() {
+            allowed.extend_from_slice(stop);
+        }
+        if allowed.is_empty() {
+            anyhow::bail!("the grammar can't be satisfied, no token can follow the text so far");
+        }
+
+        let values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        for t in allowed {
+            if let Some(v) = values.get(t as usize) {
+                masked[t as usize] = *v;
+            }
+        }
+
+        Ok(self.logits.sample(&Tensor::new(masked, logits.device())?)?)
+    }
+
+    // advances the grammar past `token`, returns `true` if nothing more can follow
+    fn accept(&mut self, token: u32) -> bool {
+        let Some((matcher, trie)) = self.grammar.as_mut() else {
+            return false;
+        };
+        if let Some(t) = trie.text(token) {
+            matcher.accept_bytes(t);
+        }
+
+        matcher.is_done()
+    }
+
+    // generation stopped before the grammar was complete
+    fn is_incomplete(&self) -> bool {
+        self.grammar
+            .as_ref()
+            .is_some_and(|(m, _)| !m.is_accepting())
+    }
+}
+
+// removes the turn at `idx`, the `user` message along with the `assistant` reply to it
+// the latest message is never removed
+fn drop_turn(messages: &mut Vec<Message>, mut idx: usize) {
+    if messages[idx].role == "assistant" && idx > 0 && messages[idx - 1].role == "user" {
+        idx -= 1;
+    }
+
+    messages.remove(idx);
+    if idx + 1 < messages.len() && messages[idx].role == "assistant" {
+        messages.remove(idx);
+    }
+}
+
+// adds the summary of the earlier turns to the `system` prompt
+fn add_summary(messages: &mut Vec<Message>, summary: &str) {
+    let summary = format!("Summary of the conversation so far:\n{}", summary.trim());
+
+    match messages.iter_mut().find(|m| m.role == "system") {
+        Some(m) => m.content = format!("{}\n\n{summary}", m.content.trim()),
+        None => messages.insert(
+            0,
+            Message {
+                role: "system".to_string(),
+                content: summary,
+            },
+        ),
+    }
+}
+
+/// A helper to convert a stream of tokens to a stream of text
+/// Tokens don't map to whole characters, so we hold back text till it's a valid utf-8 sequence
+/// Only the tokens since the last emitted text are decoded, along with the ones before them for context
+#[derive(Default)]
+struct TokenStream {
+    tokens: Vec<u32>,
+    // the tokens from `prev` are decoded, the text of the ones till `current` was emitted already
+    prev: usize,
+    current: usize,
+}
+
+impl TokenStream {
+    fn next(&mut self, tokenizer: &Tokenizer, token: u32) -> Option<String> {
+        let emitted = tokenizer
+            .decode(&self.tokens[self.prev..self.current], false)
+            .ok()?;
+        self.tokens.push(token);
+
+        let text = tokenizer.decode(&self.tokens[self.prev..], false).ok()?;
+        if text.len() <= emitted.len() || text.ends_with('\u{FFFD}') {
+            return None;
+        }
+
+        // the new token may have changed how the ones before it decode, we carry on from the next character
+        let mut start = emitted.len();
+        while !text.is_char_boundary(start) {
+            start += 1;
+        }
+        let next = text[start..].to_string();
+        self.prev = self.current;
+        self.current = self.tokens.len();
+
+        Some(next)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{cell::Cell, path::Path};
+
+    use anyhow::Result;
+    use tokenizers::{decoders::byte_level::ByteLevel, models::bpe::BPE, Tokenizer};
+
+    use super::{
+        add_summary, drop_turn, fit, ChatOptions, LlamaWrap, PrefixCache, Shorten, TokenStream, CUT,
+    };
+    use crate::{
+        config::{ContextConfig, ContextStrategy, Profile},
+        scheduler::Cancel,
+        types::{Message, PrefixOutcome},
+    };
+
+    // a token per character, the prompt is every message as `role: content` on a line of its own
+    struct Chars {
+        window: usize,
+        summaries: Cell<usize>,
+    }
+
+    impl Chars {
+        fn new(window: usize) -> Self {
+            Self {
+                window,
+                summaries: Cell::new(0),
+            }
+        }
+    }
+
+    impl Shorten for Chars {
+        fn window(&self) -> usize {
+            self.window
+        }
+
+        fn prompt(&self, messages: &[Message]) -> Result<Vec<u32>> {
+            let text = messages
+                .iter()
+                .map(|m| format!("{}: {}\n", m.role, m.content))
+                .collect::<String>();
+            self.tokens(&text)
+        }
+
+        fn tokens(&self, text: &str) -> Result<Vec<u32>> {
+            Ok(text.chars().map(|c| c as u32).collect())
+        }
+
+        fn text(&self, tokens: &[u32]) -> Result<String> {
+            Ok(tokens.iter().filter_map(|t| char::from_u32(*t)).collect())
+        }
+
+        fn summary(&self, _: &[Message], _: &ContextConfig, _: &Cancel) -> Result<String> {
+            self.summaries.set(self.summaries.get() + 1);
+            Ok("they met".to_string())
+        }
+    }
+
+    fn msg(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn context(strategy: ContextStrategy) -> ContextConfig {
+        ContextConfig {
+            strategy,
+            min_new_tokens: 10,
+        }
+    }
 
-    use super::LlamaWrap;
+    // a system prompt and three turns of 20 tokens each, the last one unanswered
+    fn conversation() -> Vec<Message> {
+        vec![
+            msg("system", "be brief"),
+            msg("user", "first question"),
+            msg("assistant", "first answer"),
+            msg("user", "second question"),
+            msg("assistant", "second answer"),
+            msg("user", "third question"),
+        ]
+    }
+
+    #[test]
+    fn budget() -> Result<()> {
+        let model = Chars::new(100);
+        let messages = conversation()[..2].to_vec();
+        let prompt = model.prompt(&messages[..])?.len();
+        assert!(prompt > 4);
+
+        // the budget is of generated tokens, however long the prompt
+        let (fitted, tokens, budget) = fit(
+            &model,
+            messages.clone(),
+            ContextStrategy::TruncateOldest,
+            &context(ContextStrategy::TruncateOldest),
+            4,
+            &Cancel::default(),
+        )?;
+        assert_eq!(
+            (fitted, tokens.len(), budget),
+            (messages.clone(), prompt, 4)
+        );
+
+        // and it's cut down to what's left of the window
+        let (_, _, budget) = fit(
+            &model,
+            messages,
+            ContextStrategy::TruncateOldest,
+            &context(ContextStrategy::TruncateOldest),
+            1000,
+            &Cancel::default(),
+        )?;
+        assert_eq!(budget, 100 - prompt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_oldest() -> Result<()> {
+        let model = Chars::new(100);
+        let (messages, tokens, budget) = fit(
+            &model,
+            conversation(),
+            ContextStrategy::TruncateOldest,
+            &context(ContextStrategy::TruncateOldest),
+            50,
+            &Cancel::default(),
+        )?;
+
+        // the first turn goes along with its answer
+        assert_eq!(
+            messages,
+            [&conversation()[..1], &conversation()[3..]].concat()
+        );
+        assert!(tokens.len() <= 90);
+        assert_eq!(budget, 100 - tokens.len());
+
+        Ok(())
+    }
 
     #[test]
+    fn middle_out() -> Result<()> {
+        // the middle turn goes first
+        let model = Chars::new(100);
+        let (messages, ..) = fit(
+            &model,
+            conversation(),
+            ContextStrategy::MiddleOut,
+            &context(ContextStrategy::MiddleOut),
+            50,
+            &Cancel::default(),
+        )?;
+        assert_eq!(
+            messages,
+            [&conversation()[..3], &conversation()[5..]].concat()
+        );
+
+        // a single long message loses its middle, once
+        let content = format!("{}{}{}", "a".repeat(50), "b".repeat(100), "c".repeat(50));
+        let (messages, tokens, _) = fit(
+            &model,
+            vec![msg("user", &content)],
+            ContextStrategy::MiddleOut,
+            &context(ContextStrategy::MiddleOut),
+            50,
+            &Cancel::default(),
+        )?;
+        assert_eq!(tokens.len(), 90);
+        let content = &messages[0].content;
+        assert!(content.starts_with("aaa") && content.ends_with("ccc"));
+        assert_eq!(content.matches(CUT).count(), 1);
+
+        // or fails when there's no room at all
+        assert!(fit(
+            &Chars::new(10),
+            vec![msg("user", &"a".repeat(200))],
+            ContextStrategy::MiddleOut,
+            &context(ContextStrategy::MiddleOut),
+            50,
+            &Cancel::default(),
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn summarize() -> Result<()> {
+        let model = Chars::new(100);
+        let (messages, ..) = fit(
+            &model,
+            conversation(),
+            ContextStrategy::Summarize,
+            &context(ContextStrategy::Summarize),
+            50,
+            &Cancel::default(),
+        )?;
+
+        assert_eq!(model.summaries.get(), 1);
+        assert_eq!(
+            messages,
+            [
+                msg(
+                    "system",
+                    "be brief\n\nSummary of the conversation so far:\nthey met"
+                ),
+                msg("user", "third question"),
+            ]
+        );
+
+        // the latest message is cut after the summary, which isn't asked for again
+        let model = Chars::new(120);
+        let mut long = conversation();
+        long.push(msg("user", &"a".repeat(100)));
+        let (messages, tokens, _) = fit(
+            &model,
+            long,
+            ContextStrategy::Summarize,
+            &context(ContextStrategy::Summarize),
+            50,
+            &Cancel::default(),
+        )?;
+        assert_eq!(model.summaries.get(), 1);
+        assert_eq!(messages.len(), 2);
+        assert!(tokens.len() <= 110);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = Cancel::default();
+        cancel.cancel();
+
+        assert!(fit(
+            &Chars::new(100),
+            conversation(),
+            ContextStrategy::TruncateOldest,
+            &context(ContextStrategy::TruncateOldest),
+            50,
+            &cancel,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn turns() {
+        // a question is dropped with its answer, whichever of the two is asked for
+        let mut messages = conversation();
+        drop_turn(&mut messages, 2);
+        assert_eq!(
+            messages,
+            [&conversation()[..1], &conversation()[3..]].concat()
+        );
+
+        let mut messages = conversation();
+        drop_turn(&mut messages, 3);
+        assert_eq!(
+            messages,
+            [&conversation()[..3], &conversation()[5..]].concat()
+        );
+
+        // a question left unanswered goes alone
+        let mut messages = vec![msg("user", "a"), msg("user", "b"), msg("assistant", "c")];
+        drop_turn(&mut messages, 0);
+        assert_eq!(messages, [msg("user", "b"), msg("assistant", "c")]);
+
+        // the summary goes to the system prompt, which is added if missing
+        let mut messages = vec![msg("user", "a")];
+        add_summary(&mut messages, " they met\n");
+        assert_eq!(
+            messages[0],
+            msg("system", "Summary of the conversation so far:\nthey met")
+        );
+    }
+
+    #[test]
+    fn prefix_cache_outcomes() {
+        let mut cache = PrefixCache::default();
+        cache.count(PrefixOutcome::Miss, 0);
+        cache.count(PrefixOutcome::Hit, 12);
+        // a long suffix isn't a miss, the cache wasn't looked at
+        let stats = cache.count(PrefixOutcome::Skipped, 0);
+
+        assert_eq!(stats.outcome, PrefixOutcome::Skipped);
+        assert_eq!((stats.hits, stats.misses, stats.skipped), (1, 1, 1));
+    }
+
+    #[test]
+    fn token_stream() -> Result<()> {
+        // byte level tokens, `é` is `Ã©` and `Ġ` a space
+        let vocab = [("a", 0), ("Ã", 1), ("©", 2), ("Ġ", 3)]
+            .into_iter()
+            .map(|(t, id)| (t.to_string(), id))
+            .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, vec![])
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer.with_decoder(Some(ByteLevel::default()));
+
+        // half a character is held back
+        let mut stream = TokenStream::default();
+        let texts = [0, 1, 2, 3, 0, 3, 1, 2]
+            .into_iter()
+            .map(|t| stream.next(&tokenizer, t))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [
+                Some("a"),
+                None,
+                Some("é"),
+                Some(" "),
+                Some("a"),
+                Some(" "),
+                None,
+                Some("é")
+            ]
+            .map(|t| t.map(str::to_string))
+        );
+        // and only the latest tokens are decoded
+        assert_eq!((stream.prev, stream.current), (6, 8));
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs the model downloaded to `AUDIO_INSTRUCT_LLM_DIR`"]
     fn llama_infer() -> anyhow::Result<()> {
         pretty_env_logger::init();
 
-        let dir = Path::new("/Users/anubhab/Library/Application Support/audio-instruct.llm");
-        let llama = LlamaWrap::new(dir)?;
+        let dir = std::env::var("AUDIO_INSTRUCT_LLM_DIR")?;
+        let llama = LlamaWrap::new(Path::new(&dir), &Default::default(), &Default::default())?;
 
-        let inf = llama.infer("Who is Steve Wozniak?")?;
+        let inf = llama.infer(
+            "Who is Steve Wozniak?",
+            &Profile::default(),
+            &Default::default(),
+            &Cancel::default(),
+        )?;
 
         info!("{inf:?}");
+
+        // the budget is of generated tokens, however long the prompt
+        let messages = [Message {
+            role: "user".to_string(),
+            content:
+                "Who is Steve Wozniak? Tell me about his early life and the founding of Apple."
+                    .to_string(),
+        }];
+        let (_, n_tokens, ..) = llama.chat(
+            &messages[..],
+            &Profile::default(),
+            &Default::default(),
+            ChatOptions {
+                max_new_tokens: Some(4),
+                ..Default::default()
+            },
+            &Cancel::default(),
+            |_| {},
+        )?;
+        assert_eq!(n_tokens, 4);
+
         Ok(())
     }
 }
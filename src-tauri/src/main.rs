@@ -1,17 +1,16 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use instruct::Instruct;
-use tauri::Manager;
+use std::sync::{Arc, Mutex};
+
+use audio_instruct::{instruct::Instruct, server::Server};
+use commands::ServerState;
+use tauri::{Emitter, Manager};
 
 #[macro_use]
 extern crate log;
 
 mod commands;
-mod instruct;
-mod llama;
-mod utils;
-mod whisper;
 
 fn main() {
     pretty_env_logger::init();
@@ -23,21 +22,75 @@ fn main() {
             let datadir = tauri_app.path().app_data_dir()?;
             // Initialize the instruct app
             let instruct = Instruct::new(datadir.clone()).expect("initialization failed");
+
+            // forward our events to the frontend
+            let handle = tauri_app.handle().clone();
+            instruct.subscribe(move |e| {
+                if let Err(err) = handle.emit(e.name(), e) {
+                    error!("error emitting event {}: {err:?}", e.name());
+                }
+            });
+
+            // The OpenAI compatible HTTP server is opt-in
+            let config = instruct.config();
+            let server = if config.server.enabled {
+                Some(Server::start(Arc::clone(&instruct), &config.server)?)
+            } else {
+                None
+            };
+
             tauri_app.manage(instruct);
+            tauri_app.manage(ServerState(Mutex::new(server)));
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             crate::commands::ask,
-            crate::commands::audio_chunk
+            crate::commands::audio_chunk,
+            crate::commands::cancel,
+            crate::commands::diarize,
+            crate::commands::profiles,
+            crate::commands::save_profile,
+            crate::commands::select_profile,
+            crate::commands::switch_llm,
+            crate::commands::switch_asr,
+            crate::commands::system_info,
+            crate::commands::share_file,
+            crate::commands::tts_stop,
+            crate::commands::tts_voices,
+            crate::commands::tts_select,
+            crate::commands::wake_enroll,
+            crate::commands::wake_clear,
+            crate::commands::documents_ingest,
+            crate::commands::documents_list,
+            crate::commands::documents_remove,
+            crate::commands::history_list,
+            crate::commands::history_conversation,
+            crate::commands::history_search,
+            crate::commands::history_delete,
+            crate::commands::history_export,
+            crate::commands::config,
+            crate::commands::set_config
         ])
         .build(tauri::generate_context!())
         .expect("Failed to build app!");
 
     // finally, lets run our app
-    app.run(|_app_handle, event| {
-        if let tauri::RunEvent::ExitRequested { api, .. } = event {
-            warn!("exit requested {api:?}");
+    app.run(|app_handle, event| {
+        if let tauri::RunEvent::ExitRequested { .. } = event {
+            info!("exit requested, shutting down");
+
+            if let Some(server) = app_handle
+                .state::<ServerState>()
+                .0
+                .lock()
+                .ok()
+                .and_then(|mut s| s.take())
+            {
+                server.stop();
+            }
+
+            app_handle.state::<Arc<Instruct>>().shutdown();
         }
     });
 }
@@ -0,0 +1,263 @@
+//! Log-mel features computed frame by frame as audio streams in
+//! A frame is the same as `whisper::audio::pcm_to_mel` computes, before it normalizes the whole input to its loudest frame
+
+use std::f32::consts::PI;
+
+use anyhow::Result;
+use candle_transformers::models::whisper::{CHUNK_LENGTH, HOP_LENGTH, N_FFT};
+
+/// The mel filter bank for `n_mel` bins, as `whisper` expects it
+pub fn filters(n_mel: usize) -> Result<Vec<f32>> {
+    let mel = match n_mel {
+        80 => include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/melfilters.bytes")).as_slice(),
+        128 => {
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/melfilters128.bytes")).as_slice()
+        }
+        nmel => anyhow::bail!("unexpected num_mel_bins {nmel}"),
+    };
+    let mut filters = vec![0f32; mel.len() / 4];
+    <byteorder::LittleEndian as byteorder::ByteOrder>::read_f32_into(mel, &mut filters);
+
+    Ok(filters)
+}
+
+/// Turns a stream of 16 kHz PCM into log-mel frames, one every `HOP_LENGTH` samples
+pub struct LogMel {
+    n_mel: usize,
+    filters: Vec<f32>,
+    hann: Vec<f32>,
+    /// samples not consumed by a frame yet
+    pending: Vec<f32>,
+}
+
+impl LogMel {
+    pub fn new(n_mel: usize) -> Result<Self> {
+        let hann = (0..N_FFT)
+            .map(|i| 0.5 * (1. - ((2. * PI * i as f32) / N_FFT as f32).cos()))
+            .collect();
+
+        Ok(Self {
+            n_mel,
+            filters: filters(n_mel)?,
+            hann,
+            pending: vec![],
+        })
+    }
+
+    pub fn n_mel(&self) -> usize {
+        self.n_mel
+    }
+
+    /// Adds `pcm`, returns the frames completed by it, each of `n_mel` `log10` mel powers
+    pub fn push(&mut self, pcm: &[f32]) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(pcm);
+
+        let mut frames = vec![];
+        let mut start = 0;
+        while start + N_FFT <= self.pending.len() {
+            frames.push(self.frame(&self.pending[start..start + N_FFT]));
+            start += HOP_LENGTH;
+        }
+        self.pending.drain(..start);
+
+        frames
+    }
+
+    /// Drops the samples waiting for a frame, e.g. between two unrelated streams
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// The normalized spectrogram of `pcm`, laid out and padded as `pcm_to_mel` returns it
+    /// `frames` are the ones `push()` returned for `pcm` so far, the rest are computed here
+    // returns the (spectrogram, loudest frame value it was normalized to)
+    pub fn spectrogram(&self, pcm: &[f32], mut frames: Vec<Vec<f32>>) -> (Vec<f32>, f32) {
+        // at least 30 s of silence is appended, rounding up to 30 s
+        let pad = 100 * CHUNK_LENGTH / 2;
+        let n_len = (pcm.len() / HOP_LENGTH).div_ceil(pad) * pad + pad;
+
+        // the frames running past the end of `pcm` are zero padded, the ones past it are silent
+        frames.truncate(n_len);
+        while frames.len() < n_len {
+            let start = frames.len() * HOP_LENGTH;
+            frames.push(if start < pcm.len() {
+                self.frame(&pcm[start..])
+            } else {
+                vec![-10.; self.n_mel]
+            });
+        }
+        let max = loudest(&frames[..]);
+        normalize(&mut frames[..], max);
+
+        (transpose(&frames[..]), max)
+    }
+
+    /// The frame starting at `samples`, zero padded to `N_FFT`
+    pub fn frame(&self, samples: &[f32]) -> Vec<f32> {
+        let mut input = vec![0f32; N_FFT];
+        input
+            .iter_mut()
+            .zip(samples.iter().zip(self.hann.iter()))
+            .for_each(|(i, (s, h))| *i = h * s);
+
+        // the power spectrum, folding the mirrored half onto the first
+        let out = fft(&input);
+        let mut power = (0..N_FFT)
+            .map(|j| out[2 * j] * out[2 * j] + out[2 * j + 1] * out[2 * j + 1])
+            .collect::<Vec<_>>();
+        for j in 1..N_FFT / 2 {
+            power[j] += power[N_FFT - j];
+        }
+
+        let n_fft = 1 + N_FFT / 2;
+        self.filters
+            .chunks_exact(n_fft)
+            .take(self.n_mel)
+            .map(|f| {
+                let sum = f.iter().zip(power.iter()).map(|(f, p)| f * p).sum::<f32>();
+                sum.max(1e-10).log10()
+            })
+            .collect()
+    }
+}
+
+/// The loudest value of `frames`, `pcm_to_mel` normalizes the whole input to it
+pub fn loudest(frames: &[Vec<f32>]) -> f32 {
+    frames
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max)
+}
+
+/// Scales `frames` the way `pcm_to_mel` does, relative to the `max` of the whole input
+pub fn normalize(frames: &mut [Vec<f32>], max: f32) {
+    frames
+        .iter_mut()
+        .flatten()
+        .for_each(|m| *m = m.max(max - 8.) / 4. + 1.);
+}
+
+/// Lays `frames` out bin by bin, the `(n_mel, frames)` shape `whisper` takes
+pub fn transpose(frames: &[Vec<f32>]) -> Vec<f32> {
+    let n_mel = frames.first().map_or(0, |f| f.len());
+    let mut mel = vec![0f32; frames.len() * n_mel];
+    for (i, frame) in frames.iter().enumerate() {
+        for (j, m) in frame.iter().enumerate() {
+            mel[j * frames.len() + i] = *m;
+        }
+    }
+
+    mel
+}
+
+/// The recursive radix 2 FFT used by `whisper.cpp`, falling back to a DFT for odd lengths
+/// Returns interleaved real and imaginary parts
+pub fn fft(input: &[f32]) -> Vec<f32> {
+    let n = input.len();
+    if n == 1 {
+        return vec![input[0], 0.];
+    }
+    if n % 2 == 1 {
+        return dft(input);
+    }
+
+    let even = fft(&input.iter().step_by(2).copied().collect::<Vec<_>>());
+    let odd = fft(&input.iter().skip(1).step_by(2).copied().collect::<Vec<_>>());
+
+    let mut out = vec![0f32; n * 2];
+    for k in 0..n / 2 {
+        let theta = 2. * PI * k as f32 / n as f32;
+        let (re, im) = (theta.cos(), -theta.sin());
+        let (re_odd, im_odd) = (odd[2 * k], odd[2 * k + 1]);
+
+        out[2 * k] = even[2 * k] + re * re_odd - im * im_odd;
+        out[2 * k + 1] = even[2 * k + 1] + re * im_odd + im * re_odd;
+        out[2 * (k + n / 2)] = even[2 * k] - re * re_odd + im * im_odd;
+        out[2 * (k + n / 2) + 1] = even[2 * k + 1] - re * im_odd - im * re_odd;
+    }
+
+    out
+}
+
+fn dft(input: &[f32]) -> Vec<f32> {
+    let n = input.len();
+
+    (0..n)
+        .flat_map(|k| {
+            let (re, im) = input
+                .iter()
+                .enumerate()
+                .fold((0f32, 0f32), |(re, im), (j, x)| {
+                    let angle = 2. * PI * k as f32 * j as f32 / n as f32;
+                    (re + x * angle.cos(), im - x * angle.sin())
+                });
+            [re, im]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_transformers::models::whisper::{audio::log_mel_spectrogram_, HOP_LENGTH, N_FFT};
+
+    fn pcm(n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| (i as f32 * 0.07).sin() * 0.3 + (i as f32 * 0.011).cos() * 0.1)
+            .collect()
+    }
+
+    use super::{loudest, normalize, LogMel};
+
+    #[test]
+    fn matches_pcm_to_mel() -> anyhow::Result<()> {
+        let pcm = pcm(16_000);
+
+        let mut mel = LogMel::new(80)?;
+        // in uneven chunks, as it'd stream in
+        let mut frames = pcm
+            .chunks(1234)
+            .flat_map(|c| mel.push(c))
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), (pcm.len() - N_FFT) / HOP_LENGTH + 1);
+        let max = loudest(&frames[..]);
+        normalize(&mut frames[..], max);
+
+        let expected = log_mel_spectrogram_(&pcm, &mel.filters, N_FFT, HOP_LENGTH, 80, false);
+        let n_len = expected.len() / 80;
+        for (i, frame) in frames.iter().enumerate() {
+            for (j, m) in frame.iter().enumerate() {
+                assert!(
+                    (m - expected[j * n_len + i]).abs() < 1e-4,
+                    "frame {i} bin {j}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn spectrogram() -> anyhow::Result<()> {
+        // frame boundaries falling on, just before and just after the chunk edges and the end
+        for (len, chunk) in [
+            (16_000, 160),
+            (16_123, 400),
+            (48_000, 1_599),
+            (240_000, 1_024),
+        ] {
+            let pcm = pcm(len);
+            let mut mel = LogMel::new(80)?;
+            let frames = pcm.chunks(chunk).flat_map(|c| mel.push(c)).collect();
+            let (streamed, _) = mel.spectrogram(&pcm, frames);
+
+            let expected = log_mel_spectrogram_(&pcm, &mel.filters, N_FFT, HOP_LENGTH, 80, false);
+            assert_eq!(streamed.len(), expected.len(), "{len} samples");
+            for (i, (m, e)) in streamed.iter().zip(expected.iter()).enumerate() {
+                assert!((m - e).abs() < 1e-4, "{len} samples, value {i}");
+            }
+        }
+
+        Ok(())
+    }
+}
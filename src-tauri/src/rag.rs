@@ -0,0 +1,513 @@
+//! Question answering over the user's own documents
+//! Text, Markdown and PDF files are split into overlapping chunks, embedded with `all-MiniLM-L6-v2` and kept in a local index
+//! The chunks closest to a question go in the prompt, numbered, so that the answer can cite the files they came from
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use serde::{Deserialize, Serialize};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use crate::{
+    events::{EventBus, ModelKind},
+    scheduler::Cancel,
+    slot::Slot,
+    utils::{device, hf_download},
+};
+
+const MODEL_REPO: &str = "sentence-transformers/all-MiniLM-L6-v2";
+const LOCAL_MODEL_TOK: &str = "minilm-tokenizer.json";
+const LOCAL_MODEL_CFG: &str = "minilm-config.json";
+const LOCAL_MODEL_MODEL: &str = "minilm.safetensors";
+
+const INDEX_FILE: &str = "documents.json";
+
+const DOCUMENTS_PROMPT: &str = "Below are numbered excerpts from the user's documents. Use the ones relevant to the instruction, and cite them by their number e.g. [1]. If they don't help, answer without them.";
+
+/// The files we know how to read
+const EXTENSIONS: &[&str] = &["txt", "md", "markdown", "pdf"];
+
+/// Chunk length and the overlap between consecutive chunks, in words
+const CHUNK_WORDS: usize = 160;
+const CHUNK_OVERLAP: usize = 32;
+/// MiniLM was trained on sequences of up to 256 tokens, longer chunks are truncated
+const MAX_TOKENS: usize = 256;
+/// Number of chunks embedded in a single forward pass
+const BATCH_SIZE: usize = 16;
+
+/// A sentence embedding model, `all-MiniLM-L6-v2` mean pooled and normalized
+pub struct Embedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl Embedder {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let device = device()?;
+        Self::model_path(dir)?;
+
+        info!("Loading embedding model");
+        let mut tokenizer = match Tokenizer::from_file(dir.join(LOCAL_MODEL_TOK)) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Error loading embedding tokenizer: {e:?}");
+                anyhow::bail!("error loading embedding tokenizer");
+            }
+        };
+        // chunks are embedded in batches, padded to the longest one
+        tokenizer
+            .with_padding(Some(PaddingParams::default()))
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_TOKENS,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+
+        let config: Config =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(LOCAL_MODEL_CFG))?)?;
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[dir.join(LOCAL_MODEL_MODEL)], DTYPE, &device)?
+        };
+        let model = BertModel::load(vb, &config)?;
+
+        info!("Embedding model ready!");
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+        })
+    }
+
+    /// Downloads the model to `dir` if it isn't already present
+    pub fn download(dir: &Path) -> Result<()> {
+        Self::model_path(dir)
+    }
+
+    fn model_path(dir: &Path) -> Result<()> {
+        for (file, local) in [
+            ("tokenizer.json", LOCAL_MODEL_TOK),
+            ("config.json", LOCAL_MODEL_CFG),
+            ("model.safetensors", LOCAL_MODEL_MODEL),
+        ] {
+            if !dir.join(local).is_file() {
+                hf_download(dir, MODEL_REPO, file, Some(local))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The embeddings of `texts`, unit length so that a dot product is the cosine similarity
+    pub fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let enc = match self.tokenizer.encode_batch(texts.to_vec(), true) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("embed: error tokenizing: {e:?}");
+                anyhow::bail!("error tokenizing text to embed");
+            }
+        };
+
+        let ids = enc
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let mask = enc
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let ids = Tensor::stack(&ids[..], 0)?;
+        let mask = Tensor::stack(&mask[..], 0)?;
+
+        let hidden = self.model.forward(&ids, &ids.zeros_like()?, Some(&mask))?;
+
+        // the mean of the token embeddings, leaving out the padding
+        let mask = mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let sum = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let mean = sum.broadcast_div(&mask.sum(1)?)?;
+        let norm = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+
+        Ok(mean.broadcast_div(&norm)?.to_vec2::<f32>()?)
+    }
+}
+
+/// A piece of a document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub source: PathBuf,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A chunk cited in a response, `id` is the number it was given in the prompt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub id: usize,
+    pub file: PathBuf,
+    /// cosine similarity to the question
+    pub score: f32,
+}
+
+/// The chunks of every document, persisted as a `json` file in the app data directory
+/// It's a flat list searched exhaustively, plenty fast for a personal collection of documents
+pub struct Index {
+    path: PathBuf,
+    chunks: RwLock<Vec<Chunk>>,
+}
+
+impl Index {
+    /// Loads the index from `dir`, starts empty if there is none or it can't be read
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(INDEX_FILE);
+        let chunks = if path.is_file() {
+            std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|s| Ok(serde_json::from_str(&s)?))
+                .unwrap_or_else(|e| {
+                    error!("error reading document index: {e:?}");
+                    vec![]
+                })
+        } else {
+            vec![]
+        };
+
+        Self {
+            path,
+            chunks: RwLock::new(chunks),
+        }
+    }
+
+    fn save(&self, chunks: &[Chunk]) -> Result<()> {
+        let file = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(file, chunks)?;
+
+        Ok(())
+    }
+
+    /// Replaces the chunks of the `source` document
+    pub fn add(&self, source: &Path, new: Vec<Chunk>) -> Result<()> {
+        let mut chunks = match self.chunks.write() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("index: error acquiring lock: {e:?}");
+                anyhow::bail!("error updating document index");
+            }
+        };
+        chunks.retain(|c| c.source != source);
+        chunks.extend(new);
+
+        self.save(&chunks[..])
+    }
+
+    /// Drops the `source` document, returns `false` if it wasn't indexed
+    pub fn remove(&self, source: &Path) -> Result<bool> {
+        let mut chunks = match self.chunks.write() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("index: error acquiring lock: {e:?}");
+                anyhow::bail!("error updating document index");
+            }
+        };
+        let n = chunks.len();
+        chunks.retain(|c| c.source != source);
+        if chunks.len() == n {
+            return Ok(false);
+        }
+
+        self.save(&chunks[..])?;
+        Ok(true)
+    }
+
+    /// The indexed documents along with their number of chunks
+    pub fn documents(&self) -> Vec<(PathBuf, usize)> {
+        let mut docs: Vec<(PathBuf, usize)> = vec![];
+        if let Ok(chunks) = self.chunks.read() {
+            for c in chunks.iter() {
+                match docs.iter_mut().find(|(d, _)| *d == c.source) {
+                    Some((_, n)) => *n += 1,
+                    None => docs.push((c.source.clone(), 1)),
+                }
+            }
+        }
+
+        docs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.read().map_or(true, |c| c.is_empty())
+    }
+
+    /// The `k` chunks most similar to `query`, best first, leaving out the ones scoring below `min_score`
+    pub fn search(&self, query: &[f32], k: usize, min_score: f32) -> Vec<(Chunk, f32)> {
+        let Ok(chunks) = self.chunks.read() else {
+            error!("index: error acquiring lock");
+            return vec![];
+        };
+
+        let mut hits = chunks
+            .iter()
+            .map(|c| {
+                let score = c
+                    .embedding
+                    .iter()
+                    .zip(query)
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>();
+                (c, score)
+            })
+            .filter(|(_, s)| *s >= min_score)
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        hits.into_iter()
+            .take(k)
+            .map(|(c, s)| (c.clone(), s))
+            .collect()
+    }
+}
+
+/// The user's documents: the index, and the embedding model loaded on first use
+pub struct Documents {
+    index: Index,
+    embedder: Slot<Embedder>,
+}
+
+impl Documents {
+    pub fn new(dir: &Path, events: Arc<EventBus>) -> Self {
+        let embedder = {
+            let dir = dir.to_path_buf();
+            Slot::new(
+                ModelKind::Embedding,
+                MODEL_REPO.to_string(),
+                move || Embedder::new(&dir),
+                events,
+            )
+        };
+
+        Self {
+            index: Index::load(dir),
+            embedder,
+        }
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Frees the embedding model if it wasn't used in the last `idle`, a zero `idle` keeps it loaded
+    pub fn unload_idle(&self, idle: Duration) {
+        self.embedder.unload_idle(idle, |_| false);
+    }
+
+    /// Indexes the documents in `paths`, folders are walked recursively
+    /// A document indexed before is replaced, returns the documents indexed
+    pub fn ingest(&self, paths: &[PathBuf], cancel: &Cancel) -> Result<Vec<PathBuf>> {
+        let embedder = self.embedder.get()?;
+
+        let mut done = vec![];
+        for file in files(paths) {
+            let text = match read_text(&file) {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!("skipping {file:?}: {e:?}");
+                    continue;
+                }
+            };
+
+            let pieces = chunk(&text);
+            let mut chunks = Vec::with_capacity(pieces.len());
+            for batch in pieces.chunks(BATCH_SIZE) {
+                if cancel.is_cancelled() {
+                    anyhow::bail!("ingestion cancelled");
+                }
+
+                let texts = batch.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+                let embeddings = embedder.embed(&texts[..])?;
+                chunks.extend(batch.iter().zip(embeddings).map(|(t, e)| Chunk {
+                    source: file.clone(),
+                    text: t.clone(),
+                    embedding: e,
+                }));
+            }
+
+            info!("indexed {file:?} in {} chunks", chunks.len());
+            self.index.add(&file, chunks)?;
+            done.push(file);
+        }
+
+        Ok(done)
+    }
+
+    /// The `k` chunks most relevant to `question`
+    pub fn retrieve(&self, question: &str, k: usize, min_score: f32) -> Result<Vec<(Chunk, f32)>> {
+        // nothing to search, no need to load the model
+        if k == 0 || self.index.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let query = self.embedder.get()?.embed(&[question])?;
+        Ok(self.index.search(&query[0], k, min_score))
+    }
+}
+
+/// The `instruct` with the `hits` in front of it, along with the sources to cite
+pub fn augment(instruct: &str, hits: Vec<(Chunk, f32)>) -> (String, Vec<Source>) {
+    let excerpts = hits
+        .iter()
+        .enumerate()
+        .map(|(i, (c, _))| {
+            let name = c.source.file_name().unwrap_or(c.source.as_os_str());
+            format!("[{}] {}\n{}", i + 1, name.to_string_lossy(), c.text)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    let sources = hits
+        .into_iter()
+        .enumerate()
+        .map(|(i, (c, score))| Source {
+            id: i + 1,
+            file: c.source,
+            score,
+        })
+        .collect();
+
+    (
+        format!("{DOCUMENTS_PROMPT}\n\n{excerpts}\n\nInstruction: {instruct}"),
+        sources,
+    )
+}
+
+// the readable files in `paths`, walking folders recursively
+fn files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut pending = paths.to_vec();
+
+    while let Some(p) = pending.pop() {
+        if p.is_dir() {
+            match std::fs::read_dir(&p) {
+                Ok(entries) => pending.extend(entries.filter_map(|e| Some(e.ok()?.path()))),
+                Err(e) => warn!("skipping {p:?}: {e:?}"),
+            }
+        } else if p
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        {
+            files.push(p);
+        }
+    }
+    files.sort();
+
+    files
+}
+
+// the text of a document, PDFs only if they have a text layer
+fn read_text(path: &Path) -> Result<String> {
+    let is_pdf = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("pdf"));
+
+    if is_pdf {
+        Ok(pdf_extract::extract_text(path)?)
+    } else {
+        Ok(std::fs::read_to_string(path)?)
+    }
+}
+
+/// Splits `text` into chunks of `CHUNK_WORDS` words, consecutive chunks share `CHUNK_OVERLAP` words
+/// so that a passage cut at a chunk boundary is still whole in one of them
+pub fn chunk(text: &str) -> Vec<String> {
+    let words = text.split_whitespace().collect::<Vec<_>>();
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < words.len() {
+        let end = (start + CHUNK_WORDS).min(words.len());
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+
+        start = end - CHUNK_OVERLAP;
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{chunk, Chunk, Index, CHUNK_OVERLAP, CHUNK_WORDS};
+
+    #[test]
+    fn chunks_overlap() {
+        assert!(chunk(" \n ").is_empty());
+        assert_eq!(chunk("a  short\n\ntext"), vec!["a short text"]);
+
+        let text = (0..400)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let chunks = chunk(&text);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("0 1 2"));
+        // the second chunk picks up `CHUNK_OVERLAP` words before the end of the first
+        let second = CHUNK_WORDS - CHUNK_OVERLAP;
+        assert!(chunks[1].starts_with(&format!("{second} {}", second + 1)));
+        assert!(chunks[2].ends_with("398 399"));
+    }
+
+    #[test]
+    fn index_search() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("audio-instruct-{:08x}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+
+        let c = |source: &str, embedding: [f32; 2]| Chunk {
+            source: Path::new(source).to_path_buf(),
+            text: source.to_string(),
+            embedding: embedding.to_vec(),
+        };
+
+        let index = Index::load(&dir);
+        index.add(
+            Path::new("a.md"),
+            vec![c("a.md", [1., 0.]), c("a.md", [0.6, 0.8])],
+        )?;
+        index.add(Path::new("b.txt"), vec![c("b.txt", [0., 1.])])?;
+
+        let hits = index.search(&[0., 1.], 2, 0.5);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].0.source, Path::new("b.txt"));
+        assert_eq!(hits[1].1, 0.8);
+
+        // re-indexing a document replaces its chunks, and the index survives a reload
+        index.add(Path::new("a.md"), vec![c("a.md", [1., 0.])])?;
+        let index = Index::load(&dir);
+        assert_eq!(
+            index.documents(),
+            vec![
+                (Path::new("b.txt").to_path_buf(), 1),
+                (Path::new("a.md").to_path_buf(), 1)
+            ]
+        );
+
+        assert!(index.remove(Path::new("b.txt"))?);
+        assert!(!index.remove(Path::new("b.txt"))?);
+        assert!(index.search(&[0., 1.], 2, 0.5).is_empty());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,781 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::events::{Event, EventBus, ModelKind, QueueEvent};
+
+/// The priority of a request, `Interactive` requests always go ahead of `Background` ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Background,
+    #[default]
+    Interactive,
+}
+
+/// The lifecycle of a request in the scheduler, reported through `Event::Queue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    /// the request returned an error, or panicked
+    Failed,
+    Cancelled,
+}
+
+/// A flag shared with a running job, long running inference loops are expected to check it and bail out
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a request could not be scheduled
+#[derive(Debug)]
+pub enum ScheduleError {
+    /// the queue already holds the max number of pending requests
+    QueueFull(usize),
+    /// a request with the same `id` is already pending
+    Duplicate(String),
+    /// the app is exiting, no new requests are accepted
+    ShuttingDown,
+    /// the queue couldn't be accessed
+    Unavailable,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueueFull(n) => write!(
+                f,
+                "inference queue is full ({n} pending requests), try again later"
+            ),
+            Self::Duplicate(id) => write!(f, "a request with id `{id}` is already pending"),
+            Self::ShuttingDown => write!(f, "shutting down, not accepting new requests"),
+            Self::Unavailable => write!(f, "the inference queue is unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+// The result of a job, shared between the worker and the `Ticket`
+struct Slot<T> {
+    value: Option<Result<T>>,
+    // set once the job is done, or dropped without running
+    done: bool,
+    waker: Option<Waker>,
+}
+
+struct Oneshot<T> {
+    slot: Mutex<Slot<T>>,
+    cond: Condvar,
+}
+
+impl<T> Oneshot<T> {
+    fn complete(&self, value: Option<Result<T>>) {
+        match self.slot.lock() {
+            Ok(mut slot) => {
+                slot.value = value;
+                slot.done = true;
+
+                if let Some(w) = slot.waker.take() {
+                    w.wake();
+                }
+            }
+            // the waiter fails on the same lock
+            Err(e) => error!("complete: error acquiring lock: {e:?}"),
+        }
+        self.cond.notify_all();
+    }
+}
+
+// The sending half, held by the job. Dropping it without a value resolves the `Ticket` as cancelled
+struct Completer<T>(Arc<Oneshot<T>>);
+
+impl<T> Completer<T> {
+    fn send(self, value: Result<T>) {
+        self.0.complete(Some(value));
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if !self.0.slot.lock().map_or(true, |s| s.done) {
+            self.0.complete(None);
+        }
+    }
+}
+
+/// A handle to a scheduled request
+/// Either block on it with `wait()` or `.await` it, the request runs on the scheduler's workers either way
+pub struct Ticket<T> {
+    id: String,
+    result: Arc<Oneshot<T>>,
+}
+
+impl<T> Ticket<T> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Blocks till the request is processed
+    pub fn wait(self) -> Result<T> {
+        let mut slot = match self.result.slot.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("wait: error acquiring lock: {e:?}");
+                anyhow::bail!("error waiting for request `{}`", self.id);
+            }
+        };
+        while !slot.done {
+            slot = match self.result.cond.wait(slot) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("wait: error acquiring lock: {e:?}");
+                    anyhow::bail!("error waiting for request `{}`", self.id);
+                }
+            };
+        }
+
+        match slot.value.take() {
+            Some(r) => r,
+            // the job was dropped without running
+            None => anyhow::bail!("request `{}` was cancelled", self.id),
+        }
+    }
+}
+
+impl<T> Future for Ticket<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = match self.result.slot.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("poll: error acquiring lock: {e:?}");
+                return Poll::Ready(Err(anyhow::anyhow!(
+                    "error waiting for request `{}`",
+                    self.id
+                )));
+            }
+        };
+        if !slot.done {
+            slot.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        match slot.value.take() {
+            Some(r) => Poll::Ready(r),
+            None => Poll::Ready(Err(anyhow::anyhow!("request `{}` was cancelled", self.id))),
+        }
+    }
+}
+
+type JobFn = Box<dyn FnOnce(&Cancel, &dyn Fn()) -> bool + Send>;
+
+struct Job {
+    id: String,
+    priority: Priority,
+    /// the models the job runs on
+    models: &'static [ModelKind],
+    cancel: Cancel,
+    /// runs the job and calls `release` once it's over, before its result is sent
+    /// returns `false` if the job failed
+    run: JobFn,
+}
+
+/// A job being processed by a worker
+struct Running {
+    cancel: Cancel,
+    models: &'static [ModelKind],
+}
+
+#[derive(Default)]
+struct State {
+    // ordered by priority, FIFO within the same priority
+    queue: Vec<Job>,
+    running: HashMap<String, Running>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+impl State {
+    // the index of the first job in `queue` that can start, a job waiting for a busy model keeps the jobs queued
+    // behind it off that model, so that the model goes to the waiting jobs in priority order
+    fn next(&self) -> Option<usize> {
+        let mut busy = self
+            .running
+            .values()
+            .flat_map(|r| r.models.iter().copied())
+            .collect::<Vec<_>>();
+
+        for (i, job) in self.queue.iter().enumerate() {
+            if job.models.iter().all(|m| !busy.contains(m)) {
+                return Some(i);
+            }
+            busy.extend_from_slice(job.models);
+        }
+
+        None
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    events: Arc<EventBus>,
+}
+
+/// A bounded priority queue in front of our models
+/// Instead of every caller blocking on the model mutexes in arbitrary order, requests are queued and processed in priority order
+/// by a pool of dedicated worker threads. A request only starts once the models it runs on are free, so that two workers never
+/// queue up on the same model mutex
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    capacity: usize,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    pub fn new(capacity: usize, workers: usize, events: Arc<EventBus>) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            events,
+        });
+
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let s = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("inference-{i}"))
+                    .spawn(move || Self::work(s))
+                    .expect("failed to spawn inference worker")
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            shared,
+            capacity,
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Queues `f`, running on `models`, for execution, `id` is generated if not provided
+    /// Fails right away if the queue is full, callers are expected to retry later
+    pub fn submit<T, F>(
+        &self,
+        id: Option<String>,
+        priority: Priority,
+        models: &'static [ModelKind],
+        f: F,
+    ) -> Result<Ticket<T>, ScheduleError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Cancel) -> Result<T> + Send + 'static,
+    {
+        let result = Arc::new(Oneshot {
+            slot: Mutex::new(Slot {
+                value: None,
+                done: false,
+                waker: None,
+            }),
+            cond: Condvar::new(),
+        });
+        let send = Completer(Arc::clone(&result));
+
+        let mut state = match self.shared.state.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("submit: error acquiring lock: {e:?}");
+                return Err(ScheduleError::Unavailable);
+            }
+        };
+        if state.shutdown {
+            return Err(ScheduleError::ShuttingDown);
+        }
+        if state.queue.len() >= self.capacity {
+            return Err(ScheduleError::QueueFull(state.queue.len()));
+        }
+
+        state.next_id += 1;
+        let id = id.unwrap_or_else(|| format!("job-{}", state.next_id));
+        if state.running.contains_key(&id) || state.queue.iter().any(|j| j.id == id) {
+            return Err(ScheduleError::Duplicate(id));
+        }
+
+        let job = Job {
+            id: id.clone(),
+            priority,
+            models,
+            cancel: Cancel::default(),
+            run: Box::new({
+                let id = id.clone();
+                move |cancel, release| {
+                    // a panicking job must neither take the worker down nor keep its id and models
+                    let res = match panic::catch_unwind(AssertUnwindSafe(|| f(cancel))) {
+                        Ok(r) => r,
+                        Err(_) => {
+                            error!("worker: request `{id}` panicked");
+                            Err(anyhow::anyhow!("request `{id}` failed unexpectedly"))
+                        }
+                    };
+                    // a retry with the same id is accepted as soon as the result is out
+                    release();
+
+                    let ok = res.is_ok();
+                    send.send(res);
+
+                    ok
+                }
+            }),
+        };
+
+        let at = state
+            .queue
+            .iter()
+            .position(|j| j.priority < priority)
+            .unwrap_or(state.queue.len());
+        state.queue.insert(at, job);
+
+        let queued = Self::positions(&state.queue, at);
+        drop(state);
+
+        Self::emit(&self.shared.events, queued);
+        self.shared.cond.notify_all();
+
+        Ok(Ticket { id, result })
+    }
+
+    /// Cancels a request, a queued request is dropped and a running one is signalled to stop
+    /// Returns `false` if no such request is pending
+    pub fn cancel(&self, id: &str) -> bool {
+        let mut state = match self.shared.state.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("cancel: error acquiring lock: {e:?}");
+                return false;
+            }
+        };
+
+        if let Some(r) = state.running.get(id) {
+            r.cancel.cancel();
+            return true;
+        }
+
+        let Some(at) = state.queue.iter().position(|j| j.id == id) else {
+            return false;
+        };
+
+        let job = state.queue.remove(at);
+        let mut events = vec![QueueEvent {
+            id: job.id.clone(),
+            state: JobState::Cancelled,
+            position: 0,
+        }];
+        events.extend(Self::positions(&state.queue, at));
+        drop(state);
+
+        // dropping the job drops the sender, the `Ticket` resolves with an error
+        drop(job);
+        Self::emit(&self.shared.events, events);
+        // the jobs waiting behind it for a model may start now
+        self.shared.cond.notify_all();
+
+        true
+    }
+
+    /// Stops accepting requests, drops everything still queued and signals the running requests to stop
+    /// Blocks till the workers exit, safe to call more than once
+    pub fn shutdown(&self) {
+        let dropped = match self.shared.state.lock() {
+            Ok(mut state) => {
+                state.shutdown = true;
+                state.running.values().for_each(|r| r.cancel.cancel());
+
+                state.queue.drain(..).collect::<Vec<_>>()
+            }
+            Err(e) => {
+                // the workers exit on the same error
+                error!("shutdown: error acquiring lock: {e:?}");
+                vec![]
+            }
+        };
+
+        // dropping the jobs resolves their tickets as cancelled
+        dropped.into_iter().for_each(|j| {
+            self.shared.events.emit(Event::Queue(QueueEvent {
+                id: j.id,
+                state: JobState::Cancelled,
+                position: 0,
+            }))
+        });
+        self.shared.cond.notify_all();
+
+        let workers = match self.workers.lock() {
+            Ok(mut w) => w.drain(..).collect::<Vec<_>>(),
+            Err(e) => {
+                error!("shutdown: error acquiring workers lock: {e:?}");
+                return;
+            }
+        };
+
+        workers.into_iter().for_each(|w| {
+            if let Err(e) = w.join() {
+                error!("shutdown: error joining worker: {e:?}");
+            }
+        });
+        info!("scheduler: shutdown complete");
+    }
+
+    /// Number of requests waiting to be processed
+    pub fn pending(&self) -> usize {
+        self.shared.state.lock().map_or(0, |s| s.queue.len())
+    }
+
+    // the positions of the jobs in `queue` starting at `from` changed
+    // the events are emitted by the caller once the state lock is released
+    fn positions(queue: &[Job], from: usize) -> Vec<QueueEvent> {
+        queue
+            .iter()
+            .enumerate()
+            .skip(from)
+            .map(|(i, j)| QueueEvent {
+                id: j.id.clone(),
+                state: JobState::Queued,
+                position: i + 1,
+            })
+            .collect()
+    }
+
+    fn emit(bus: &EventBus, events: Vec<QueueEvent>) {
+        events.into_iter().for_each(|e| bus.emit(Event::Queue(e)));
+    }
+
+    fn work(shared: Arc<Shared>) {
+        loop {
+            let (job, queued) = {
+                let mut state = match shared.state.lock() {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("worker: error acquiring lock: {e:?}");
+                        return;
+                    }
+                };
+                let at = loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some(at) = state.next() {
+                        break at;
+                    }
+                    state = match shared.cond.wait(state) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("worker: error acquiring lock: {e:?}");
+                            return;
+                        }
+                    };
+                };
+
+                let job = state.queue.remove(at);
+                state.running.insert(
+                    job.id.clone(),
+                    Running {
+                        cancel: job.cancel.clone(),
+                        models: job.models,
+                    },
+                );
+
+                (job, Self::positions(&state.queue, at))
+            };
+
+            let Job {
+                id, cancel, run, ..
+            } = job;
+            let mut events = queued;
+            events.push(QueueEvent {
+                id: id.clone(),
+                state: JobState::Running,
+                position: 0,
+            });
+            Self::emit(&shared.events, events);
+
+            let release = || {
+                match shared.state.lock() {
+                    Ok(mut state) => {
+                        state.running.remove(&id);
+                    }
+                    Err(e) => error!("worker: error acquiring lock: {e:?}"),
+                }
+                // its models are free for the jobs waiting on them
+                shared.cond.notify_all();
+            };
+            let ok = run(&cancel, &release);
+
+            let state = if cancel.is_cancelled() {
+                JobState::Cancelled
+            } else if ok {
+                JobState::Done
+            } else {
+                JobState::Failed
+            };
+            Self::emit(
+                &shared.events,
+                vec![QueueEvent {
+                    id,
+                    state,
+                    position: 0,
+                }],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::channel, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::{JobState, Priority, ScheduleError, Scheduler};
+    use crate::events::{Event, EventBus, ModelKind};
+
+    const LLM: &[ModelKind] = &[ModelKind::Llm];
+
+    // occupies a worker and the language model till the returned sender is dropped
+    fn block(s: &Scheduler) -> std::sync::mpsc::Sender<()> {
+        let (send, recv) = channel::<()>();
+        let (started, wait_started) = channel();
+        s.submit(
+            Some("blocker".to_string()),
+            Priority::Interactive,
+            LLM,
+            move |_| {
+                started.send(()).unwrap();
+                let _ = recv.recv();
+                Ok(())
+            },
+        )
+        .unwrap();
+        wait_started.recv().unwrap();
+
+        send
+    }
+
+    #[test]
+    fn interactive_goes_first() -> anyhow::Result<()> {
+        let s = Scheduler::new(8, 1, Arc::new(EventBus::default()));
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let blocker = block(&s);
+        let tickets = [
+            ("bg-1", Priority::Background),
+            ("bg-2", Priority::Background),
+            ("ui-1", Priority::Interactive),
+        ]
+        .map(|(id, p)| {
+            let order = Arc::clone(&order);
+            s.submit(Some(id.to_string()), p, LLM, move |_| {
+                order.lock().unwrap().push(id);
+                Ok(())
+            })
+            .unwrap()
+        });
+        drop(blocker);
+
+        for t in tickets {
+            t.wait()?;
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec!["ui-1", "bg-1", "bg-2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn backpressure_and_cancel() {
+        let s = Scheduler::new(1, 1, Arc::new(EventBus::default()));
+
+        let blocker = block(&s);
+        let queued = s
+            .submit(
+                Some("queued".to_string()),
+                Priority::Background,
+                LLM,
+                |_| Ok(()),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            s.submit(None, Priority::Interactive, LLM, |_| Ok(())),
+            Err(ScheduleError::QueueFull(1))
+        ));
+
+        assert!(s.cancel("queued"));
+        assert!(queued.wait().is_err());
+        assert_eq!(s.pending(), 0);
+
+        drop(blocker);
+    }
+
+    #[test]
+    fn await_and_shutdown() -> anyhow::Result<()> {
+        let s = Scheduler::new(4, 2, Arc::new(EventBus::default()));
+
+        let t = s.submit(None, Priority::Interactive, LLM, |_| Ok(42))?;
+        assert_eq!(block_on(t)?, 42);
+
+        let blocker = block(&s);
+        // the second worker picks this up, it runs on no model, and spins till cancelled
+        let running = s.submit(
+            None,
+            Priority::Interactive,
+            &[],
+            |c| -> anyhow::Result<()> {
+                while !c.is_cancelled() {
+                    std::thread::yield_now();
+                }
+                anyhow::bail!("cancelled")
+            },
+        )?;
+
+        drop(blocker);
+        s.shutdown();
+
+        assert!(running.wait().is_err());
+        assert!(matches!(
+            s.submit(None, Priority::Interactive, LLM, |_| Ok(())),
+            Err(ScheduleError::ShuttingDown)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn model_goes_to_interactive_first() -> anyhow::Result<()> {
+        let s = Scheduler::new(8, 2, Arc::new(EventBus::default()));
+        // stands in for the model mutex
+        let model = Arc::new(Mutex::new(vec![]));
+        let run = |id: &'static str, p| {
+            let model = Arc::clone(&model);
+            s.submit(Some(id.to_string()), p, LLM, move |_| {
+                model.lock().unwrap().push(id);
+                Ok(())
+            })
+        };
+
+        let blocker = block(&s);
+        let bg = run("bg", Priority::Background)?;
+        // the second worker is idle, but mustn't take the background request while the model is busy
+        thread::sleep(Duration::from_millis(100));
+        assert!(model.lock().unwrap().is_empty());
+
+        // a request on another model isn't held up
+        s.submit(None, Priority::Background, &[ModelKind::Asr], |_| Ok(()))?
+            .wait()?;
+
+        let ui = run("ui", Priority::Interactive)?;
+        drop(blocker);
+        ui.wait()?;
+        bg.wait()?;
+
+        assert_eq!(*model.lock().unwrap(), vec!["ui", "bg"]);
+        Ok(())
+    }
+
+    #[test]
+    fn survives_panics() -> anyhow::Result<()> {
+        let events = Arc::new(EventBus::default());
+        let states = Arc::new(Mutex::new(vec![]));
+        {
+            let states = Arc::clone(&states);
+            events.subscribe(move |e| {
+                if let Event::Queue(q) = e {
+                    states.lock().unwrap().push((q.id.clone(), q.state));
+                }
+            });
+        }
+        let s = Scheduler::new(8, 1, events);
+
+        let t = s.submit(
+            Some("boom".to_string()),
+            Priority::Interactive,
+            LLM,
+            |_| -> anyhow::Result<()> { panic!("boom") },
+        )?;
+        assert!(t.wait().is_err());
+
+        // the single worker is still around, and the id and the model are free again
+        let t = s.submit(Some("boom".to_string()), Priority::Interactive, LLM, |_| {
+            Ok(1)
+        })?;
+        assert_eq!(t.wait()?, 1);
+
+        // the terminal event follows the result
+        let terminal = || {
+            states
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, st)| matches!(st, JobState::Done | JobState::Failed))
+                .map(|(_, st)| *st)
+                .collect::<Vec<_>>()
+        };
+        for _ in 0..100 {
+            if terminal().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(terminal(), vec![JobState::Failed, JobState::Done]);
+
+        Ok(())
+    }
+
+    // a minimal executor, just enough to drive a `Ticket` to completion
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = std::pin::pin!(f);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(r) => return r,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! An embedded, OpenAI compatible HTTP API so that other local tools can reuse the models loaded by the app
+//! Supported endpoints:
+//!   GET  /v1/models
+//!   POST /v1/chat/completions      - `stream: true` responds with server sent events
+//!   POST /v1/audio/transcriptions  - `multipart/form-data` with a `.wav` in the `file` field
+//! Requests are queued with `Priority::Background`, so the app's own requests go ahead of them
+
+use std::{
+    fmt,
+    io::{Cursor, Read, Write},
+    sync::{mpsc::channel, Arc},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, StatusCode};
+
+use crate::{
+    config::ServerConfig,
+    events::ModelKind,
+    instruct::Instruct,
+    scheduler::{Priority, ScheduleError},
+    types::{Message, ResponseFormat},
+    utils::read_wav,
+};
+
+/// Request bodies are read up to 64 MB, a 30 minute `.wav` at 16 kHz is about 58 MB
+const MAX_BODY: usize = 64 << 20;
+
+/// A request body over `MAX_BODY`
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request body over {} MB", MAX_BODY >> 20)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// A handle to the running server
+pub struct Server {
+    server: Arc<tiny_http::Server>,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
+    max_tokens: Option<usize>,
+    response_format: Option<ResponseFormat>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatChoice {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<serde_json::Value>,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatResponse {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<ChatChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<serde_json::Value>,
+}
+
+impl ChatResponse {
+    fn new(id: &str, object: &'static str, model: &str, choice: ChatChoice) -> Self {
+        Self {
+            id: id.to_string(),
+            object,
+            created: now(),
+            model: model.to_string(),
+            choices: vec![choice],
+            usage: None,
+        }
+    }
+}
+
+impl Server {
+    /// Starts the server on `127.0.0.1:<port>`, requests are served by the given `Instruct` instance
+    pub fn start(app: Arc<Instruct>, config: &ServerConfig) -> Result<Self> {
+        let server = match tiny_http::Server::http(("127.0.0.1", config.port)) {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                error!("server: error binding to port {}: {e:?}", config.port);
+                anyhow::bail!("error starting server");
+            }
+        };
+
+        let srv = Arc::clone(&server);
+        let handle = thread::spawn(move || {
+            for req in srv.incoming_requests() {
+                let app = Arc::clone(&app);
+                // each request on its own thread, inference is long running and we don't want to block `accept`
+                thread::spawn(move || Self::handle(app, req));
+            }
+        });
+
+        info!("server: listening @ 127.0.0.1:{}", config.port);
+        Ok(Self {
+            server,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stops accepting new connections and waits for the accept loop to exit
+    pub fn stop(mut self) {
+        self.server.unblock();
+        if let Some(h) = self.handle.take() {
+            if let Err(e) = h.join() {
+                error!("server: error joining accept loop: {e:?}");
+            }
+        }
+
+        info!("server: stopped");
+    }
+
+    fn handle(app: Arc<Instruct>, mut req: Request) {
+        let path = req.url().split('?').next().unwrap_or_default().to_string();
+
+        let res = match (req.method(), path.as_str()) {
+            (Method::Get, "/v1/models") => {
+                let (llm, asr) = app.models();
+                Ok(json!({
+                    "object": "list",
+                    "data": [
+                        { "id": llm, "object": "model", "owned_by": "local" },
+                        { "id": asr, "object": "model", "owned_by": "local" }
+                    ]
+                }))
+            }
+            (Method::Post, "/v1/chat/completions") => {
+                let body = match read_body(&mut req) {
+                    Ok(b) => b,
+                    Err(e) => return Self::respond_error(req, 413, &e.to_string()),
+                };
+                let chat = match serde_json::from_slice::<ChatRequest>(&body[..]) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        return Self::respond_error(req, 400, &format!("invalid request: {e}"))
+                    }
+                };
+
+                if chat.stream {
+                    return Self::stream_chat(app, req, chat);
+                }
+
+                Self::chat(&app, chat)
+            }
+            (Method::Post, "/v1/audio/transcriptions") => Self::transcribe(&app, &mut req),
+            _ => return Self::respond_error(req, 404, "not found"),
+        };
+
+        match res {
+            Ok(v) => Self::respond_json(req, 200, &v),
+            // backpressure, the client should retry later
+            Err(e) if e.downcast_ref::<ScheduleError>().is_some() => {
+                warn!("server: rejected {path}: {e}");
+                Self::respond_error(req, 503, &e.to_string())
+            }
+            Err(e) if e.downcast_ref::<TooLarge>().is_some() => {
+                warn!("server: rejected {path}: {e}");
+                Self::respond_error(req, 413, &e.to_string())
+            }
+            Err(e) => {
+                error!("server: error serving {path}: {e:?}");
+                Self::respond_error(req, 500, &e.to_string())
+            }
+        }
+    }
+
+    fn chat(app: &Arc<Instruct>, chat: ChatRequest) -> Result<serde_json::Value> {
+        let ChatRequest {
+            messages,
+            max_tokens,
+            response_format,
+            ..
+        } = chat;
+        let res = app
+            .schedule(
+                None,
+                Priority::Background,
+                &[ModelKind::Llm],
+                move |app, cancel| {
+                    app.chat(
+                        &messages[..],
+                        max_tokens,
+                        response_format.as_ref(),
+                        cancel,
+                        |_| {},
+                    )
+                },
+            )?
+            .wait()?;
+
+        let mut out = ChatResponse::new(
+            &completion_id(),
+            "chat.completion",
+            &app.models().0,
+            ChatChoice {
+                index: 0,
+                message: Some(Message {
+                    role: "assistant".to_string(),
+                    content: res.text().to_string(),
+                }),
+                delta: None,
+                finish_reason: Some("stop"),
+            },
+        );
+        out.usage = Some(json!({
+            "completion_tokens": res.meta().n_tokens()
+        }));
+
+        Ok(serde_json::to_value(out)?)
+    }
+
+    // streams the generated text as server sent events
+    // `tiny_http` buffers chunked responses, so we take over the raw stream and write the events ourselves
+    fn stream_chat(app: Arc<Instruct>, req: Request, chat: ChatRequest) {
+        let ChatRequest {
+            messages,
+            max_tokens,
+            response_format,
+            ..
+        } = chat;
+
+        // the generation runs on the scheduler, the text is relayed back to us over a channel
+        let (send_txt, recv_txt) = channel::<String>();
+        let ticket = match app.schedule(
+            None,
+            Priority::Background,
+            &[ModelKind::Llm],
+            move |app, cancel| {
+                app.chat(
+                    &messages[..],
+                    max_tokens,
+                    response_format.as_ref(),
+                    cancel,
+                    |t| {
+                        let _ = send_txt.send(t.to_string());
+                    },
+                )
+            },
+        ) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("server: rejected streaming chat: {e}");
+                return Self::respond_error(req, 503, &e.to_string());
+            }
+        };
+
+        let id = completion_id();
+        let model = app.models().0;
+        let mut writer = req.into_writer();
+
+        let mut res = writer
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+            .and_then(|_| writer.flush());
+        if let Err(e) = &res {
+            error!("server: error writing stream headers: {e:?}");
+            app.cancel(ticket.id());
+        }
+
+        let mut send = |choice: ChatChoice| -> std::io::Result<()> {
+            let chunk = ChatResponse::new(&id, "chat.completion.chunk", &model, choice);
+            let data = serde_json::to_string(&chunk).unwrap_or_default();
+            writer.write_all(format!("data: {data}\n\n").as_bytes())?;
+            writer.flush()
+        };
+
+        // `recv` fails once the job is done and the sender is dropped
+        while let Ok(t) = recv_txt.recv() {
+            if res.is_err() {
+                continue;
+            }
+
+            res = send(ChatChoice {
+                index: 0,
+                message: None,
+                delta: Some(json!({ "content": t })),
+                finish_reason: None,
+            });
+
+            // the client went away, no point in generating any further
+            if res.is_err() {
+                app.cancel(ticket.id());
+            }
+        }
+
+        let finish = match ticket.wait() {
+            Ok(_) => "stop",
+            Err(e) => {
+                error!("server: error during streaming inference: {e:?}");
+                "error"
+            }
+        };
+
+        let _ = send(ChatChoice {
+            index: 0,
+            message: None,
+            delta: Some(json!({})),
+            finish_reason: Some(finish),
+        });
+
+        let _ = writer.write_all(b"data: [DONE]\n\n");
+        let _ = writer.flush();
+    }
+
+    fn transcribe(app: &Arc<Instruct>, req: &mut Request) -> Result<serde_json::Value> {
+        let Some(boundary) = req
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Content-Type"))
+            .and_then(|h| boundary(h.value.as_str()))
+        else {
+            anyhow::bail!("expected a multipart/form-data request");
+        };
+
+        let body = read_body(req)?;
+
+        let Some(file) = multipart_field(&body[..], &boundary, "file") else {
+            anyhow::bail!("missing `file` field");
+        };
+
+        let pcm = read_wav(Cursor::new(file))?;
+        let res = app
+            .schedule(
+                None,
+                Priority::Background,
+                &[ModelKind::Asr],
+                move |app, cancel| app.transcribe(&pcm[..], cancel),
+            )?
+            .wait()?;
+
+        Ok(json!({ "text": res.text().trim() }))
+    }
+
+    fn respond_json(req: Request, status: u16, body: &serde_json::Value) {
+        let res = tiny_http::Response::from_string(body.to_string())
+            .with_status_code(StatusCode(status))
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                    .expect("valid header"),
+            );
+
+        if let Err(e) = req.respond(res) {
+            error!("server: error responding: {e:?}");
+        }
+    }
+
+    fn respond_error(req: Request, status: u16, msg: &str) {
+        Self::respond_json(req, status, &json!({ "error": { "message": msg } }))
+    }
+}
+
+// reads the body of `req`, up to `MAX_BODY`
+fn read_body(req: &mut Request) -> Result<Vec<u8>> {
+    if req.body_length().is_some_and(|l| l > MAX_BODY) {
+        return Err(TooLarge.into());
+    }
+
+    // the length isn't known up front for a chunked body
+    read_capped(req.as_reader(), MAX_BODY)
+}
+
+fn read_capped(reader: impl Read, max: usize) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    reader.take(max as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > max {
+        return Err(TooLarge.into());
+    }
+
+    Ok(body)
+}
+
+// the boundary of a `multipart/form-data` content type
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+
+    params
+        .find_map(|p| p.trim().strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"').to_string())
+        .filter(|b| !b.is_empty())
+}
+
+// extracts the raw bytes of the field `name` from a `multipart/form-data` body
+fn multipart_field<'a>(body: &'a [u8], boundary: &str, name: &str) -> Option<&'a [u8]> {
+    // a delimiter is on a line of its own, the data may contain the boundary elsewhere
+    let delim = format!("\r\n--{boundary}");
+    let delim = delim.as_bytes();
+
+    // the first one may open the body, without a line break before it
+    let mut rest = match body.strip_prefix(&delim[2..]) {
+        Some(r) => r,
+        None => &body[find(body, delim)? + delim.len()..],
+    };
+    // the last one is followed by `--`, a part that isn't closed by a delimiter is incomplete
+    while !rest.starts_with(b"--") {
+        let end = find(rest, delim)?;
+        if let Some(data) = part_data(&rest[..end], name) {
+            return Some(data);
+        }
+        rest = &rest[end + delim.len()..];
+    }
+
+    None
+}
+
+// the data of a body `part` following its delimiter, if it's the field `name`
+fn part_data<'a>(part: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    // the rest of the delimiter line is blank, unless it's a longer boundary starting with ours
+    // the headers follow, up to an empty line
+    let start = find(part, b"\r\n")?;
+    if !part[..start].iter().all(|b| b" \t".contains(b)) {
+        return None;
+    }
+    let part = &part[start..];
+    let hdr_end = find(part, b"\r\n\r\n")?;
+    let headers = std::str::from_utf8(&part[..hdr_end]).ok()?;
+
+    // `name` exactly, `filename` would match a looser search
+    let named = headers
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .filter(|(h, _)| h.trim().eq_ignore_ascii_case("Content-Disposition"))
+        .any(|(_, v)| {
+            v.split(';')
+                .filter_map(|p| p.trim().strip_prefix("name="))
+                .any(|n| n.trim_matches('"') == name)
+        });
+
+    named.then(|| &part[hdr_end + 4..])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn completion_id() -> String {
+    format!("chatcmpl-{}", rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{boundary, multipart_field, read_capped};
+
+    fn body(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = b"preamble\r\n".to_vec();
+        for (headers, data) in parts {
+            body.extend_from_slice(b"--XyZ\r\n");
+            body.extend_from_slice(headers.as_bytes());
+            body.extend_from_slice(b"\r\n\r\n");
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--XyZ--\r\n");
+
+        body
+    }
+
+    #[test]
+    fn multipart_fields() {
+        // binary data, ending in a line break and holding the boundary mid line
+        let wav = b"RIFF\x00\xff--XyZ\r\n-\r\n";
+        let body = body(&[
+            (
+                "Content-Disposition: form-data; name=\"model\"; filename=\"file\"",
+                b"whisper",
+            ),
+            (
+                "content-disposition: form-data; name=file; filename=\"a.wav\"\r\nContent-Type: audio/wav",
+                wav,
+            ),
+            ("Content-Disposition: form-data; name=\"empty\"", b""),
+        ]);
+
+        assert_eq!(multipart_field(&body, "XyZ", "file"), Some(&wav[..]));
+        assert_eq!(
+            multipart_field(&body, "XyZ", "model"),
+            Some(&b"whisper"[..])
+        );
+        assert_eq!(multipart_field(&body, "XyZ", "empty"), Some(&b""[..]));
+        assert_eq!(multipart_field(&body, "XyZ", "language"), None);
+        assert_eq!(multipart_field(&body, "Xy", "file"), None);
+
+        // the body may open with the delimiter
+        let body = body[b"preamble\r\n".len()..].to_vec();
+        assert_eq!(multipart_field(&body, "XyZ", "file"), Some(&wav[..]));
+
+        // a part cut short isn't returned
+        let cut = &body[..body.len() - b"\r\n--XyZ--\r\n".len() - 2];
+        assert_eq!(multipart_field(cut, "XyZ", "empty"), None);
+        assert_eq!(multipart_field(cut, "XyZ", "file"), Some(&wav[..]));
+        assert_eq!(multipart_field(b"", "XyZ", "file"), None);
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=XyZ").as_deref(),
+            Some("XyZ")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data;boundary=\"a b\"; charset=utf-8").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("application/json; boundary=XyZ"), None);
+    }
+
+    #[test]
+    fn capped_body() -> anyhow::Result<()> {
+        assert_eq!(read_capped(&b"12345"[..], 5)?, b"12345");
+        assert!(read_capped(&b"123456"[..], 5).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! A model shared by the requests that is loaded on first use and can be replaced or freed while the app runs
+//! Every request holds on to the model it started with, a replacement is loaded in the background and swapped in once
+//! it's ready, the previous model is freed when the last request using it is done
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::events::{Event, EventBus, ModelEvent, ModelKind, ModelState};
+
+/// How often a replaced model is checked for requests still using it
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Loads the model of a slot, again after it's been unloaded
+type Loader<T> = Arc<dyn Fn() -> Result<T> + Send + Sync>;
+
+/// The model a slot is set to, `model` is `None` until it's needed
+struct Current<T> {
+    name: String,
+    model: Option<Arc<T>>,
+    load: Loader<T>,
+}
+
+pub struct Slot<T> {
+    kind: ModelKind,
+    current: RwLock<Current<T>>,
+    /// held while a model is loaded, so that it's loaded once
+    loading: Mutex<()>,
+    /// the last time the model was asked for
+    used: Mutex<Instant>,
+    /// a replacement is being loaded
+    switching: AtomicBool,
+    events: Arc<EventBus>,
+}
+
+impl<T: Send + Sync + 'static> Slot<T> {
+    /// A slot for the model `name`, loaded with `load` the first time it's needed
+    pub fn new<L>(kind: ModelKind, name: String, load: L, events: Arc<EventBus>) -> Self
+    where
+        L: Fn() -> Result<T> + Send + Sync + 'static,
+    {
+        Self {
+            kind,
+            current: RwLock::new(Current {
+                name,
+                model: None,
+                load: Arc::new(load),
+            }),
+            loading: Mutex::new(()),
+            used: Mutex::new(Instant::now()),
+            switching: AtomicBool::new(false),
+            events,
+        }
+    }
+
+    /// The current model, loaded if it isn't yet. Hold on to it for the whole of a request
+    pub fn get(&self) -> Result<Arc<T>> {
+        self.touch();
+        if let Some(m) = self.loaded() {
+            return Ok(m);
+        }
+
+        let _loading = match self.loading.lock() {
+            Ok(l) => l,
+            Err(e) => {
+                error!("slot: error acquiring loading lock: {e:?}");
+                anyhow::bail!("error loading the {:?} model", self.kind);
+            }
+        };
+        // loaded by someone else while we waited
+        let (name, load) = match self.current.read() {
+            Ok(c) => match &c.model {
+                Some(m) => return Ok(Arc::clone(m)),
+                None => (c.name.clone(), Arc::clone(&c.load)),
+            },
+            Err(e) => {
+                error!("slot: error acquiring lock: {e:?}");
+                anyhow::bail!("error acquiring the {:?} model", self.kind);
+            }
+        };
+
+        self.emit(&name, ModelState::Loading, None);
+        let model = match load() {
+            Ok(m) => Arc::new(m),
+            Err(e) => {
+                error!("slot: error loading {name}: {e:?}");
+                self.emit(&name, ModelState::Failed, Some(e.to_string()));
+                return Err(e);
+            }
+        };
+        match self.current.write() {
+            Ok(mut c) => c.model = Some(Arc::clone(&model)),
+            Err(e) => {
+                error!("slot: error acquiring lock: {e:?}");
+                anyhow::bail!("error acquiring the {:?} model", self.kind);
+            }
+        }
+        self.emit(&name, ModelState::Ready, None);
+
+        Ok(model)
+    }
+
+    /// The current model if it's loaded, without loading it
+    pub fn loaded(&self) -> Option<Arc<T>> {
+        match self.current.read() {
+            Ok(c) => c.model.clone(),
+            Err(e) => {
+                error!("slot: error acquiring lock: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// The name of the current model
+    pub fn name(&self) -> String {
+        self.current
+            .read()
+            .map(|c| c.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Frees the model if it wasn't asked for in the last `idle`, it's loaded again on the next `get()`
+    /// A zero `idle` keeps the model loaded, as does `busy` for a model holding state that would be lost, e.g. a recording
+    pub fn unload_idle<B: Fn(&T) -> bool>(&self, idle: Duration, busy: B) {
+        if idle.is_zero() {
+            return;
+        }
+        // a model being loaded isn't idle
+        let Ok(_loading) = self.loading.try_lock() else {
+            return;
+        };
+        let Ok(mut used) = self.used.lock() else {
+            return;
+        };
+        let mut current = match self.current.write() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("slot: error acquiring lock: {e:?}");
+                return;
+            }
+        };
+
+        match &current.model {
+            None => return,
+            // a request is still running on it, or it will be needed for what it holds
+            Some(m) if Arc::strong_count(m) > 1 || busy(m) => {
+                *used = Instant::now();
+                return;
+            }
+            Some(_) if used.elapsed() < idle => return,
+            Some(_) => {}
+        }
+
+        let model = current.model.take();
+        let name = current.name.clone();
+        drop(current);
+        drop(model);
+        self.emit(&name, ModelState::Unloaded, None);
+    }
+
+    /// Loads `name` with `load` on a background thread and swaps it in once it's ready
+    /// `ready` runs with the current model, if it's loaded, and the new model right before the swap, to carry state over
+    /// Returns once the load has started, the progress is reported with `Event::Model`
+    pub fn switch<L, R>(self: &Arc<Self>, name: String, load: L, ready: R) -> Result<()>
+    where
+        L: Fn() -> Result<T> + Send + Sync + 'static,
+        R: FnOnce(Option<&T>, &T) + Send + 'static,
+    {
+        if self.switching.swap(true, Ordering::SeqCst) {
+            anyhow::bail!("a {:?} model is already being loaded", self.kind);
+        }
+
+        let slot = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name(format!("switch-{:?}", self.kind).to_lowercase())
+            .spawn(move || {
+                slot.run_switch(name, Arc::new(load), ready);
+                slot.switching.store(false, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            self.switching.store(false, Ordering::SeqCst);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    fn run_switch<R>(&self, name: String, load: Loader<T>, ready: R)
+    where
+        R: FnOnce(Option<&T>, &T),
+    {
+        // requests waiting for a model get the new one
+        let loading = match self.loading.lock() {
+            Ok(l) => l,
+            Err(e) => {
+                error!("switch: error acquiring loading lock: {e:?}");
+                self.emit(
+                    &name,
+                    ModelState::Failed,
+                    Some("error loading model".to_string()),
+                );
+                return;
+            }
+        };
+
+        self.emit(&name, ModelState::Loading, None);
+        let model = match load() {
+            Ok(m) => Arc::new(m),
+            Err(e) => {
+                error!("switch: error loading {name}: {e:?}");
+                self.emit(&name, ModelState::Failed, Some(e.to_string()));
+                return;
+            }
+        };
+
+        let (old_name, old) = match self.current.write() {
+            Ok(mut c) => {
+                ready(c.model.as_deref(), &model);
+                c.load = load;
+                (
+                    std::mem::replace(&mut c.name, name.clone()),
+                    c.model.replace(model),
+                )
+            }
+            Err(e) => {
+                error!("switch: error acquiring lock: {e:?}");
+                self.emit(
+                    &name,
+                    ModelState::Failed,
+                    Some("error swapping models".to_string()),
+                );
+                return;
+            }
+        };
+        drop(loading);
+        self.touch();
+        self.emit(&name, ModelState::Ready, None);
+
+        let Some(old) = old else {
+            return;
+        };
+        // the requests that started on the previous model finish on it
+        if Arc::strong_count(&old) > 1 {
+            self.emit(&old_name, ModelState::Draining, None);
+            while Arc::strong_count(&old) > 1 {
+                thread::sleep(DRAIN_POLL);
+            }
+        }
+        drop(old);
+        self.emit(&old_name, ModelState::Unloaded, None);
+    }
+
+    // marks the model as just used
+    fn touch(&self) {
+        match self.used.lock() {
+            Ok(mut u) => *u = Instant::now(),
+            Err(e) => error!("slot: error acquiring lock: {e:?}"),
+        }
+    }
+
+    fn emit(&self, name: &str, state: ModelState, error: Option<String>) {
+        info!("{:?} model {name}: {state:?}", self.kind);
+        self.events.emit(Event::Model(ModelEvent {
+            kind: self.kind,
+            name: name.to_string(),
+            state,
+            error,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::Slot;
+    use crate::events::{Event, EventBus, ModelKind, ModelState};
+
+    /// The (name, state) of the model events so far
+    type States = Arc<Mutex<Vec<(String, ModelState)>>>;
+
+    // an event bus recording the model events
+    fn recorder() -> (Arc<EventBus>, States) {
+        let events = Arc::new(EventBus::default());
+        let states = Arc::new(Mutex::new(vec![]));
+        {
+            let states = Arc::clone(&states);
+            events.subscribe(move |e| {
+                if let Event::Model(m) = e {
+                    states.lock().unwrap().push((m.name.clone(), m.state));
+                }
+            });
+        }
+
+        (events, states)
+    }
+
+    #[test]
+    fn switches_and_drains() -> anyhow::Result<()> {
+        let (events, states) = recorder();
+        let slot = Arc::new(Slot::new(ModelKind::Llm, "a".to_string(), || Ok(1), events));
+        // a request in flight on the first model
+        let in_flight = slot.get()?;
+
+        slot.switch(
+            "b".to_string(),
+            || {
+                thread::sleep(Duration::from_millis(50));
+                Ok(2)
+            },
+            |old, new| assert_eq!((old.copied(), *new), (Some(1), 2)),
+        )?;
+        assert!(slot.switch("c".to_string(), || Ok(3), |_, _| {}).is_err());
+
+        let start = Instant::now();
+        while *slot.get()? != 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(slot.name(), "b");
+        assert_eq!(*in_flight, 1);
+
+        drop(in_flight);
+        while states.lock().unwrap().len() < 6 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                ("a".to_string(), ModelState::Loading),
+                ("a".to_string(), ModelState::Ready),
+                ("b".to_string(), ModelState::Loading),
+                ("b".to_string(), ModelState::Ready),
+                ("a".to_string(), ModelState::Draining),
+                ("a".to_string(), ModelState::Unloaded),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_lazily_and_unloads_idle() -> anyhow::Result<()> {
+        let (events, states) = recorder();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let slot = {
+            let loads = Arc::clone(&loads);
+            Slot::new(
+                ModelKind::Asr,
+                "a".to_string(),
+                move || Ok(loads.fetch_add(1, Ordering::SeqCst)),
+                events,
+            )
+        };
+        assert!(slot.loaded().is_none());
+        assert_eq!(loads.load(Ordering::SeqCst), 0);
+
+        // loaded once, on first use
+        assert_eq!(*slot.get()?, 0);
+        assert_eq!(*slot.get()?, 0);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let idle = Duration::from_millis(20);
+        // not idle yet, and never unloaded while in use
+        slot.unload_idle(idle, |_| false);
+        let in_flight = slot.get()?;
+        thread::sleep(idle * 2);
+        slot.unload_idle(idle, |_| false);
+        assert!(slot.loaded().is_some());
+        drop(in_flight);
+
+        // a zero timeout keeps the model, as does one still holding something
+        thread::sleep(idle * 2);
+        slot.unload_idle(Duration::ZERO, |_| false);
+        assert!(slot.loaded().is_some());
+        slot.unload_idle(idle, |m| *m == 0);
+        assert!(slot.loaded().is_some());
+
+        // and being busy counts as a use
+        slot.unload_idle(idle, |_| false);
+        assert!(slot.loaded().is_some());
+        thread::sleep(idle * 2);
+        slot.unload_idle(idle, |_| false);
+        assert!(slot.loaded().is_none());
+        assert_eq!(*slot.get()?, 1);
+
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                ("a".to_string(), ModelState::Loading),
+                ("a".to_string(), ModelState::Ready),
+                ("a".to_string(), ModelState::Unloaded),
+                ("a".to_string(), ModelState::Loading),
+                ("a".to_string(), ModelState::Ready),
+            ]
+        );
+
+        Ok(())
+    }
+}
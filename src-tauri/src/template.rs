@@ -0,0 +1,339 @@
+//! Renders a conversation into a prompt with the model's chat template
+//! The template is read from the `tokenizer.chat_template` metadata of the `gguf` file, if the model doesn't ship one
+//! we fall back to a built-in template for the model family
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file;
+use minijinja::{context, Environment, Error, ErrorKind};
+use tokenizers::Tokenizer;
+
+use crate::types::Message;
+
+const TEMPLATE_NAME: &str = "chat";
+
+/// Markers used by the known templates to end a turn, the ones present in a template are treated as stop tokens
+const END_OF_TURN: [&str; 7] = [
+    "<|eot_id|>",
+    "<|end_of_text|>",
+    "<|im_end|>",
+    "<end_of_turn>",
+    "<|end|>",
+    "</s>",
+    "<eos>",
+];
+
+// https://huggingface.co/meta-llama/Meta-Llama-3-8B-Instruct/blob/main/tokenizer_config.json
+const LLAMA3: &str = "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}";
+
+// https://github.com/openai/openai-python/blob/release-v0.28.0/chatml.md
+const CHATML: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+// https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.1/blob/main/tokenizer_config.json
+const MISTRAL: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token }}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
+
+// https://huggingface.co/google/gemma-7b-it/blob/main/tokenizer_config.json
+const GEMMA: &str = "{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}";
+
+/// The templates we ship for models that don't bring their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Llama3,
+    ChatML,
+    Mistral,
+    Gemma,
+}
+
+impl Builtin {
+    fn source(&self) -> &'static str {
+        match self {
+            Self::Llama3 => LLAMA3,
+            Self::ChatML => CHATML,
+            Self::Mistral => MISTRAL,
+            Self::Gemma => GEMMA,
+        }
+    }
+
+    /// Guesses the model family from the special tokens in the vocabulary
+    pub fn detect(tokenizer: &Tokenizer) -> Self {
+        let has = |t: &str| tokenizer.token_to_id(t).is_some();
+
+        if has("<|eot_id|>") {
+            Self::Llama3
+        } else if has("<|im_end|>") {
+            Self::ChatML
+        } else if has("<end_of_turn>") {
+            Self::Gemma
+        } else if has("[INST]") || has("</s>") {
+            Self::Mistral
+        } else {
+            Self::ChatML
+        }
+    }
+}
+
+/// A compiled chat template and the special tokens it needs
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+    stop_tokens: Vec<u32>,
+}
+
+impl ChatTemplate {
+    /// Compiles the template `source`, `tokenizer` is used to resolve the stop tokens
+    pub fn new(
+        source: &str,
+        bos_token: &str,
+        eos_token: &str,
+        tokenizer: &Tokenizer,
+    ) -> Result<Self> {
+        let mut env = Environment::new();
+        // the `jinja2` environment `transformers` renders chat templates with
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |msg: String| -> Result<String, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, msg))
+        });
+        env.add_template_owned(TEMPLATE_NAME, source.to_string())?;
+
+        let mut stop_tokens = vec![];
+        END_OF_TURN
+            .iter()
+            .filter(|t| source.contains(**t))
+            .chain([&eos_token])
+            .filter_map(|t| tokenizer.token_to_id(t))
+            .for_each(|t| {
+                if !stop_tokens.contains(&t) {
+                    stop_tokens.push(t);
+                }
+            });
+
+        Ok(Self {
+            env,
+            bos_token: bos_token.to_string(),
+            eos_token: eos_token.to_string(),
+            stop_tokens,
+        })
+    }
+
+    /// One of the templates we ship
+    pub fn builtin(template: Builtin, tokenizer: &Tokenizer) -> Result<Self> {
+        let (bos, eos) = match template {
+            Builtin::Llama3 => ("<|begin_of_text|>", "<|end_of_text|>"),
+            Builtin::ChatML => ("", "<|im_end|>"),
+            Builtin::Mistral => ("<s>", "</s>"),
+            Builtin::Gemma => ("<bos>", "<eos>"),
+        };
+
+        Self::new(template.source(), bos, eos, tokenizer)
+    }
+
+    /// The template from the `gguf` metadata, or the built-in template for the model family if it has none
+    pub fn from_gguf(content: &gguf_file::Content, tokenizer: &Tokenizer) -> Result<Self> {
+        let token = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_u32().ok())
+                .and_then(|id| tokenizer.id_to_token(id))
+        };
+
+        if let Some(source) = content
+            .metadata
+            .get("tokenizer.chat_template")
+            .and_then(|v| v.to_string().ok())
+        {
+            let bos = token("tokenizer.ggml.bos_token_id").unwrap_or_default();
+            let eos = token("tokenizer.ggml.eos_token_id").unwrap_or_default();
+
+            match Self::new(source, &bos, &eos, tokenizer) {
+                Ok(t) => return Ok(t),
+                Err(e) => error!("error compiling chat template from gguf, using built-in: {e:?}"),
+            }
+        }
+
+        let builtin = Builtin::detect(tokenizer);
+        info!("using built-in chat template: {builtin:?}");
+
+        Self::builtin(builtin, tokenizer)
+    }
+
+    /// Renders the conversation to a prompt, ready for the `assistant` to respond
+    /// Templates that reject the `system` role get the system prompt folded into the first `user` message
+    pub fn render(&self, messages: &[Message]) -> Result<String> {
+        match self.render_raw(messages) {
+            Ok(p) => Ok(p),
+            Err(e) if messages.first().is_some_and(|m| m.role == "system") => {
+                warn!("chat template rejected the system prompt, folding it into the first message: {e}");
+                self.render_raw(&fold_system(messages)).map_err(Into::into)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn render_raw(&self, messages: &[Message]) -> Result<String, Error> {
+        self.env.get_template(TEMPLATE_NAME)?.render(context! {
+            messages => messages,
+            add_generation_prompt => true,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })
+    }
+
+    /// The tokens that end the `assistant` turn
+    pub fn stop_tokens(&self) -> &[u32] {
+        &self.stop_tokens[..]
+    }
+}
+
+// merges a leading `system` message into the `user` message that follows it
+fn fold_system(messages: &[Message]) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    let system = messages.remove(0);
+
+    match messages.first_mut() {
+        Some(m) if m.role == "user" => {
+            m.content = format!("{}\n\n{}", system.content.trim(), m.content);
+        }
+        _ => messages.insert(
+            0,
+            Message {
+                role: "user".to_string(),
+                content: system.content,
+            },
+        ),
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use super::{Builtin, ChatTemplate};
+    use crate::types::Message;
+
+    // a tokenizer that knows just the special tokens of the built-in templates
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            "<unk>",
+            "<|begin_of_text|>",
+            "<|end_of_text|>",
+            "<|eot_id|>",
+            "<|im_end|>",
+            "<s>",
+            "</s>",
+            "<bos>",
+            "<eos>",
+            "<end_of_turn>",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.to_string(), i as u32))
+        .collect::<HashMap<_, _>>();
+
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+
+        Tokenizer::new(model)
+    }
+
+    fn conversation() -> Vec<Message> {
+        [
+            ("system", "You are a pirate."),
+            ("user", "Hello!"),
+            ("assistant", "Ahoy!"),
+            ("user", "Where's the treasure?"),
+        ]
+        .iter()
+        .map(|(role, content)| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn llama3() -> anyhow::Result<()> {
+        let tokenizer = tokenizer();
+        let tmpl = ChatTemplate::builtin(Builtin::Llama3, &tokenizer)?;
+
+        assert_eq!(
+            tmpl.render(&conversation())?,
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nYou are a pirate.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nHello!<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\nAhoy!<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nWhere's the treasure?<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(tmpl.stop_tokens(), &[3, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chatml() -> anyhow::Result<()> {
+        let tokenizer = tokenizer();
+        let tmpl = ChatTemplate::builtin(Builtin::ChatML, &tokenizer)?;
+
+        assert_eq!(
+            tmpl.render(&conversation())?,
+            "<|im_start|>system\nYou are a pirate.<|im_end|>\n<|im_start|>user\nHello!<|im_end|>\n<|im_start|>assistant\nAhoy!<|im_end|>\n<|im_start|>user\nWhere's the treasure?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(tmpl.stop_tokens(), &[4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mistral() -> anyhow::Result<()> {
+        let tokenizer = tokenizer();
+        let tmpl = ChatTemplate::builtin(Builtin::Mistral, &tokenizer)?;
+
+        // the template rejects the `system` role, so it's folded into the first message
+        assert_eq!(
+            tmpl.render(&conversation())?,
+            "<s>[INST] You are a pirate.\n\nHello! [/INST]Ahoy!</s>[INST] Where's the treasure? [/INST]"
+        );
+        assert_eq!(tmpl.stop_tokens(), &[6]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gemma() -> anyhow::Result<()> {
+        let tokenizer = tokenizer();
+        let tmpl = ChatTemplate::builtin(Builtin::Gemma, &tokenizer)?;
+
+        assert_eq!(
+            tmpl.render(&conversation())?,
+            "<bos><start_of_turn>user\nYou are a pirate.\n\nHello!<end_of_turn>\n<start_of_turn>model\nAhoy!<end_of_turn>\n<start_of_turn>user\nWhere's the treasure?<end_of_turn>\n<start_of_turn>model\n"
+        );
+        assert_eq!(tmpl.stop_tokens(), &[9, 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_template() -> anyhow::Result<()> {
+        let tokenizer = tokenizer();
+        // python style string methods are common in the templates shipped with models
+        let tmpl = ChatTemplate::new(
+            "{% for m in messages %}{{ m.role.upper() }}: {{ m.content.strip() }}{{ eos_token }}\n{% endfor %}ASSISTANT:",
+            "",
+            "</s>",
+            &tokenizer,
+        )?;
+
+        assert_eq!(
+            tmpl.render(&conversation()[1..2])?,
+            "USER: Hello!</s>\nASSISTANT:"
+        );
+        assert_eq!(tmpl.stop_tokens(), &[6]);
+
+        Ok(())
+    }
+}
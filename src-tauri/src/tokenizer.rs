@@ -0,0 +1,327 @@
+//! Rebuilds the tokenizer of a model from the `tokenizer.ggml.*` metadata of its `gguf` file
+//! This keeps the tokenizer in lock step with the weights, no separate `tokenizer.json` required
+//! Supports byte level BPE (`gpt2`, e.g. Llama 3) and sentencepiece BPE (`llama`, e.g. Llama 2, Mistral, Gemma) vocabularies
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use candle_core::quantized::gguf_file::Value;
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, byte_level::ByteLevel, fuse::Fuse, sequence::Sequence,
+        strip::Strip,
+    },
+    models::bpe::BPE,
+    normalizers::{Prepend, Replace, Sequence as NormSequence},
+    pre_tokenizers::{
+        sequence::Sequence as PreSequence,
+        split::{Split, SplitPattern},
+        PreTokenizerWrapper,
+    },
+    processors::template::TemplateProcessing,
+    AddedToken, DecoderWrapper, SplitDelimiterBehavior, Tokenizer,
+};
+
+// https://huggingface.co/meta-llama/Meta-Llama-3-8B/blob/main/tokenizer.json
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// The `tokenizer.ggml.token_type` of a token, we only care about the ones that are never split
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// Builds the tokenizer from the `gguf` metadata
+pub fn from_gguf(metadata: &HashMap<String, Value>) -> Result<Tokenizer> {
+    let get = |key: &str| match metadata.get(key) {
+        Some(v) => Ok(v),
+        None => anyhow::bail!("gguf has no `{key}`"),
+    };
+
+    let model = get("tokenizer.ggml.model")?.to_string()?;
+    let tokens = get("tokenizer.ggml.tokens")?
+        .to_vec()?
+        .iter()
+        .map(|t| t.to_string().cloned())
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    let types = match metadata.get("tokenizer.ggml.token_type") {
+        Some(t) => t
+            .to_vec()?
+            .iter()
+            .map(|t| t.to_i32())
+            .collect::<candle_core::Result<Vec<_>>>()?,
+        None => vec![],
+    };
+    let token = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(|v| v.to_u32().ok())
+            .and_then(|id| tokens.get(id as usize).map(|t| (t.as_str(), id)))
+    };
+
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.clone(), i as u32))
+        .collect::<HashMap<_, _>>();
+
+    let mut tokenizer = match model.as_str() {
+        "gpt2" => {
+            let merges = get("tokenizer.ggml.merges")?
+                .to_vec()?
+                .iter()
+                .filter_map(|m| {
+                    let (a, b) = m.to_string().ok()?.split_once(' ')?;
+                    Some((a.to_string(), b.to_string()))
+                })
+                .collect::<Vec<_>>();
+
+            byte_level(vocab, merges, metadata.get("tokenizer.ggml.pre"))?
+        }
+        "llama" => {
+            let scores = match metadata.get("tokenizer.ggml.scores") {
+                Some(s) => s
+                    .to_vec()?
+                    .iter()
+                    .map(|s| s.to_f32())
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+                None => vec![0.; tokens.len()],
+            };
+            let unk = token("tokenizer.ggml.unknown_token_id").map(|(t, _)| t.to_string());
+
+            sentencepiece(&tokens[..], &scores[..], vocab, unk)?
+        }
+        m => anyhow::bail!("unsupported tokenizer model `{m}`"),
+    };
+
+    // special tokens are matched as a whole, before the text is split
+    let special = tokens
+        .iter()
+        .zip(types.iter())
+        .filter(|(_, t)| **t == TOKEN_TYPE_CONTROL || **t == TOKEN_TYPE_USER_DEFINED)
+        .map(|(tok, _)| AddedToken::from(tok.as_str(), true))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&special[..]);
+
+    let add_bos = metadata
+        .get("tokenizer.ggml.add_bos_token")
+        .and_then(|v| v.to_bool().ok())
+        .unwrap_or(true);
+    if let Some((bos, id)) = token("tokenizer.ggml.bos_token_id").filter(|_| add_bos) {
+        let processor = TemplateProcessing::builder()
+            .try_single(format!("{bos} $A"))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos, id)])
+            .build()?;
+        tokenizer.with_post_processor(Some(processor));
+    }
+
+    Ok(tokenizer)
+}
+
+/// The bytes behind a token that isn't valid utf-8 on its own, a piece of a multi byte character
+/// Either a sentencepiece byte fallback token e.g. `<0xE2>`, or a byte level BPE token spelled with the GPT-2 byte to unicode mapping
+pub fn token_bytes(token: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>')) {
+        return Some(vec![u8::from_str_radix(hex, 16).ok()?]);
+    }
+
+    token
+        .chars()
+        .map(|c| match c as u32 {
+            // printable bytes stand for themselves
+            n @ (0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF) => Some(n as u8),
+            // the rest are shifted past 255, in order
+            n @ 0x100..=0x143 => (0u8..=0xFF)
+                .filter(|b| matches!(b, 0..=0x20 | 0x7F..=0xA0 | 0xAD))
+                .nth((n - 0x100) as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+// byte level BPE, the `tokenizer.ggml.pre` decides how the text is split into words before the merges are applied
+fn byte_level(
+    vocab: HashMap<String, u32>,
+    merges: Vec<(String, String)>,
+    pre: Option<&Value>,
+) -> Result<Tokenizer> {
+    let pre = pre.and_then(|p| p.to_string().ok()).map(|p| p.as_str());
+
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        // the Llama 3 vocab has whole words that aren't reachable through merges
+        .ignore_merges(matches!(pre, Some("llama-bpe" | "llama3")))
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    match pre {
+        Some("llama-bpe" | "llama3") => {
+            let split = Split::new(
+                SplitPattern::Regex(LLAMA3_PATTERN.to_string()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map_err(anyhow::Error::msg)?;
+            tokenizer.with_pre_tokenizer(Some(PreSequence::new(vec![
+                PreTokenizerWrapper::Split(split),
+                PreTokenizerWrapper::ByteLevel(ByteLevel::new(false, true, false)),
+            ])));
+        }
+        // the GPT-2 split
+        _ => {
+            tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, true, true)));
+        }
+    }
+    tokenizer.with_decoder(Some(ByteLevel::default()));
+
+    Ok(tokenizer)
+}
+
+// sentencepiece BPE, the `gguf` has no merges so we derive them from the scores of the merged tokens
+fn sentencepiece(
+    tokens: &[String],
+    scores: &[f32],
+    vocab: HashMap<String, u32>,
+    unk: Option<String>,
+) -> Result<Tokenizer> {
+    let mut ranked = tokens.iter().zip(scores.iter()).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.1.total_cmp(a.1));
+
+    let merges = ranked
+        .into_iter()
+        .flat_map(|(t, _)| {
+            let vocab = &vocab;
+            t.char_indices().skip(1).filter_map(move |(i, _)| {
+                let (a, b) = t.split_at(i);
+                (vocab.contains_key(a) && vocab.contains_key(b))
+                    .then(|| (a.to_string(), b.to_string()))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .byte_fallback(true)
+        .fuse_unk(true);
+    if let Some(unk) = unk {
+        bpe = bpe.unk_token(unk);
+    }
+
+    let mut tokenizer = Tokenizer::new(bpe.build().map_err(anyhow::Error::msg)?);
+    tokenizer.with_normalizer(Some(NormSequence::new(vec![
+        Prepend::new("▁".to_string()).into(),
+        Replace::new(" ", "▁").map_err(anyhow::Error::msg)?.into(),
+    ])));
+    tokenizer.with_decoder(Some(Sequence::new(vec![
+        DecoderWrapper::Replace(Replace::new("▁", " ").map_err(anyhow::Error::msg)?),
+        DecoderWrapper::ByteFallback(ByteFallback::new()),
+        DecoderWrapper::Fuse(Fuse::new()),
+        DecoderWrapper::Strip(Strip::new(' ', 1, 0)),
+    ])));
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::quantized::gguf_file::Value;
+
+    fn metadata(model: &str, tokens: &[&str], merges: &[&str]) -> HashMap<String, Value> {
+        let strings =
+            |s: &[&str]| Value::Array(s.iter().map(|s| Value::String(s.to_string())).collect());
+        let types = tokens
+            .iter()
+            .map(|t| Value::I32(if t.starts_with("<|") { 3 } else { 1 }))
+            .collect();
+
+        HashMap::from([
+            (
+                "tokenizer.ggml.model".to_string(),
+                Value::String(model.to_string()),
+            ),
+            (
+                "tokenizer.ggml.pre".to_string(),
+                Value::String("llama-bpe".to_string()),
+            ),
+            ("tokenizer.ggml.tokens".to_string(), strings(tokens)),
+            ("tokenizer.ggml.merges".to_string(), strings(merges)),
+            ("tokenizer.ggml.token_type".to_string(), Value::Array(types)),
+            ("tokenizer.ggml.bos_token_id".to_string(), Value::U32(0)),
+        ])
+    }
+
+    #[test]
+    fn byte_level() -> anyhow::Result<()> {
+        let tokens = [
+            "<|begin_of_text|>",
+            "<|eot_id|>",
+            "h",
+            "e",
+            "l",
+            "o",
+            "Ġ",
+            "he",
+            "ll",
+            "hell",
+            "hello",
+            "Ġhello",
+        ];
+        let merges = ["h e", "l l", "he ll", "hell o", "Ġ hello"];
+        let tokenizer = super::from_gguf(&metadata("gpt2", &tokens, &merges))?;
+
+        let enc = tokenizer
+            .encode("hello hello<|eot_id|>", true)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(enc.get_ids(), &[0, 10, 11, 1]);
+
+        let dec = tokenizer
+            .decode(enc.get_ids(), false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(dec, "<|begin_of_text|>hello hello<|eot_id|>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn sentencepiece() -> anyhow::Result<()> {
+        let tokens = ["<unk>", "<s>", "</s>", "▁", "h", "i", "▁h", "▁hi"];
+        let mut meta = metadata("llama", &tokens, &[]);
+        meta.insert("tokenizer.ggml.bos_token_id".to_string(), Value::U32(1));
+        // the merges are derived from the scores, `▁hi` can only be reached through `▁h`
+        meta.insert(
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array(
+                [0., 0., 0., -1., -2., -3., -4., -5.]
+                    .into_iter()
+                    .map(Value::F32)
+                    .collect(),
+            ),
+        );
+        let tokenizer = super::from_gguf(&meta)?;
+
+        let enc = tokenizer
+            .encode("hi hi", true)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(enc.get_ids(), &[1, 7, 7]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn token_bytes() {
+        assert_eq!(super::token_bytes("<0xE2>"), Some(vec![0xE2]));
+        // `é` is `0xC3 0xA9`, split in two byte level tokens
+        assert_eq!(super::token_bytes("Ã"), Some(vec![0xC3]));
+        assert_eq!(super::token_bytes("©"), Some(vec![0xA9]));
+        assert_eq!(super::token_bytes("Ġa"), Some(vec![b' ', b'a']));
+        assert_eq!(super::token_bytes("ĊĠ"), Some(vec![b'\n', b' ']));
+        assert_eq!(super::token_bytes("日"), None);
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(super::from_gguf(&metadata("bert", &["a"], &[])).is_err());
+    }
+}
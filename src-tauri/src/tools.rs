@@ -0,0 +1,468 @@
+//! Tools the model can call to act on an instruction, e.g. "compute 17% of 2340"
+//! Tools are described to the model in the Llama 3 JSON tool calling format, a call is a JSON object
+//! `{"name": "...", "parameters": {...}}` as the whole of the answer, the result goes back as an `ipython` message
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Max number of bytes of a shared file given to the model
+const MAX_FILE_BYTES: usize = 16 * 1024;
+
+const TOOLS_PROMPT: &str = "You have access to the following functions. To call a function, respond only with a JSON object in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}. Do not use variables. The result of the call will be given to you to answer with. If none of the functions are needed, answer the instruction directly.";
+
+type Handler = Box<dyn Fn(&Value) -> Result<Value> + Send + Sync>;
+
+/// A tool the model can call
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// a JSON Schema of the `parameters` object
+    pub parameters: Value,
+    handler: Handler,
+}
+
+/// A call made by the model, and what came of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub parameters: Value,
+    /// the result of the call, or `{"error": "..."}`
+    pub result: Value,
+}
+
+/// The tools available to the model
+pub struct Tools {
+    tools: RwLock<Vec<Arc<Tool>>>,
+    /// the files the user picked to share with the model, `read_file` can't read anything else
+    files: Arc<RwLock<Vec<PathBuf>>>,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        let tools = Self {
+            tools: RwLock::new(vec![]),
+            files: Arc::new(RwLock::new(vec![])),
+        };
+        tools.builtin();
+
+        tools
+    }
+}
+
+impl Tools {
+    /// Registers a tool, replacing the tool of the same `name` if any
+    pub fn register<F>(&self, name: &str, description: &str, parameters: Value, handler: F)
+    where
+        F: Fn(&Value) -> Result<Value> + Send + Sync + 'static,
+    {
+        let tool = Arc::new(Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            handler: Box::new(handler),
+        });
+
+        match self.tools.write() {
+            Ok(mut t) => match t.iter_mut().find(|t| t.name == name) {
+                Some(t) => *t = tool,
+                None => t.push(tool),
+            },
+            Err(e) => error!("register: error acquiring lock: {e:?}"),
+        }
+    }
+
+    /// Shares a file the user picked with the `read_file` tool
+    pub fn share_file(&self, path: &Path) -> Result<()> {
+        if !path.is_file() {
+            anyhow::bail!("`{}` is not a file", path.display());
+        }
+
+        let mut files = match self.files.write() {
+            Ok(f) => f,
+            Err(e) => {
+                error!("share_file: error acquiring lock: {e:?}");
+                anyhow::bail!("error sharing file");
+            }
+        };
+        if !files.iter().any(|f| f == path) {
+            files.push(path.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    /// The files shared with the `read_file` tool
+    pub fn shared_files(&self) -> Vec<PathBuf> {
+        match self.files.read() {
+            Ok(f) => f.clone(),
+            Err(e) => {
+                error!("shared_files: error acquiring lock: {e:?}");
+                vec![]
+            }
+        }
+    }
+
+    fn list(&self) -> Vec<Arc<Tool>> {
+        match self.tools.read() {
+            Ok(t) => t.clone(),
+            Err(e) => {
+                error!("tools: error acquiring lock: {e:?}");
+                vec![]
+            }
+        }
+    }
+
+    /// The instructions and the tool definitions, to go in the system prompt
+    pub fn prompt(&self) -> String {
+        let defs = self
+            .list()
+            .iter()
+            .map(|t| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": t.name,
+                        "description": t.description,
+                        "parameters": t.parameters
+                    }
+                })
+                .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        format!("{TOOLS_PROMPT}\n\n{defs}")
+    }
+
+    /// The call in `text`, if the model's answer is a call to one of our tools
+    pub fn parse_call(&self, text: &str) -> Option<(String, Value)> {
+        // Llama 3.1 may start a call with `<|python_tag|>`
+        let text = text.trim().trim_start_matches("<|python_tag|>").trim();
+        if !text.starts_with('{') {
+            return None;
+        }
+
+        let call = serde_json::from_str::<Value>(text).ok()?;
+        let name = call.get("name")?.as_str()?.to_string();
+        if !self.list().iter().any(|t| t.name == name) {
+            return None;
+        }
+        let parameters = call
+            .get("parameters")
+            .or_else(|| call.get("arguments"))
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+
+        Some((name, parameters))
+    }
+
+    /// Calls the tool `name`, a failure is returned as the result so that the model can tell the user about it
+    pub fn call(&self, name: &str, parameters: &Value) -> ToolCall {
+        let tool = self.list().into_iter().find(|t| t.name == name);
+        let result = match tool.map(|t| (t.handler)(parameters)) {
+            Some(Ok(r)) => r,
+            Some(Err(e)) => {
+                warn!("tool `{name}` failed: {e:?}");
+                json!({ "error": e.to_string() })
+            }
+            None => json!({ "error": format!("no tool named `{name}`") }),
+        };
+
+        ToolCall {
+            name: name.to_string(),
+            parameters: parameters.clone(),
+            result,
+        }
+    }
+
+    fn builtin(&self) {
+        self.register(
+            "calculator",
+            "Evaluates an arithmetic expression with + - * / ^, parentheses, sqrt(x), abs(x) and x% for percentages e.g. `17% * 2340`",
+            json!({
+                "type": "object",
+                "properties": {
+                    "expression": { "type": "string", "description": "the expression to evaluate" }
+                },
+                "required": ["expression"]
+            }),
+            |p| {
+                let Some(expr) = p.get("expression").and_then(|e| e.as_str()) else {
+                    anyhow::bail!("missing `expression`");
+                };
+
+                Ok(json!({ "result": calculate(expr)? }))
+            },
+        );
+
+        self.register(
+            "current_datetime",
+            "The current local date, time, weekday and timezone",
+            json!({ "type": "object", "properties": {} }),
+            |_| {
+                let now = chrono::Local::now();
+                Ok(json!({
+                    "date": now.format("%Y-%m-%d").to_string(),
+                    "time": now.format("%H:%M:%S").to_string(),
+                    "weekday": now.format("%A").to_string(),
+                    "timezone": now.format("%:z").to_string()
+                }))
+            },
+        );
+
+        let files = Arc::clone(&self.files);
+        self.register(
+            "read_file",
+            "Reads a text file the user shared, by its file name",
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "description": "the name of the file" }
+                },
+                "required": ["name"]
+            }),
+            move |p| {
+                let Some(name) = p.get("name").and_then(|n| n.as_str()) else {
+                    anyhow::bail!("missing `name`");
+                };
+                let files = match files.read() {
+                    Ok(f) => f.clone(),
+                    Err(e) => {
+                        error!("read_file: error acquiring lock: {e:?}");
+                        anyhow::bail!("error reading shared files");
+                    }
+                };
+
+                read_shared(&files[..], name)
+            },
+        );
+    }
+}
+
+// the content of the shared file `name`, matched by its file name or full path
+fn read_shared(files: &[PathBuf], name: &str) -> Result<Value> {
+    let Some(path) = files
+        .iter()
+        .find(|f| f.as_os_str() == name || f.file_name().is_some_and(|n| n == name))
+    else {
+        let shared = files
+            .iter()
+            .filter_map(|f| f.file_name()?.to_str())
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "`{name}` is not a shared file, the shared files are: {}",
+            shared.join(", ")
+        );
+    };
+
+    let bytes = std::fs::read(path)?;
+    let truncated = bytes.len() > MAX_FILE_BYTES;
+    let content = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_FILE_BYTES)]);
+
+    Ok(json!({ "content": content, "truncated": truncated }))
+}
+
+/// Evaluates an arithmetic expression
+pub fn calculate(expr: &str) -> Result<f64> {
+    let mut calc = Calc {
+        src: expr.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+
+    let v = calc.expr()?;
+    if calc.pos < calc.src.len() {
+        anyhow::bail!("unexpected `{}` in `{expr}`", calc.src[calc.pos]);
+    }
+    if !v.is_finite() {
+        anyhow::bail!("`{expr}` is not a finite number");
+    }
+
+    Ok(v)
+}
+
+// a recursive descent evaluator, `^` binds tightest and is right associative
+struct Calc {
+    src: Vec<char>,
+    pos: usize,
+}
+
+impl Calc {
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    // term (("+" | "-") term)*
+    fn expr(&mut self) -> Result<f64> {
+        let mut v = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            v = if op == '+' { v + rhs } else { v - rhs };
+        }
+
+        Ok(v)
+    }
+
+    // unary (("*" | "/") unary)*
+    fn term(&mut self) -> Result<f64> {
+        let mut v = self.unary()?;
+        while let Some(op @ ('*' | '/' | 'x' | '×' | '÷')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            v = if matches!(op, '/' | '÷') {
+                v / rhs
+            } else {
+                v * rhs
+            };
+        }
+
+        Ok(v)
+    }
+
+    // "-" unary | power
+    fn unary(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    // postfix ("^" unary)?
+    fn power(&mut self) -> Result<f64> {
+        let base = self.postfix()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(base.powf(self.unary()?));
+        }
+
+        Ok(base)
+    }
+
+    // primary "%"?
+    fn postfix(&mut self) -> Result<f64> {
+        let v = self.primary()?;
+        if self.peek() == Some('%') {
+            self.pos += 1;
+            return Ok(v / 100.);
+        }
+
+        Ok(v)
+    }
+
+    // number | "(" expr ")" | function "(" expr ")"
+    fn primary(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let v = self.expr()?;
+                if self.peek() != Some(')') {
+                    anyhow::bail!("expected `)`");
+                }
+                self.pos += 1;
+                Ok(v)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || c == '.' || c == ',')
+                {
+                    self.pos += 1;
+                }
+
+                // thousands separators are dropped
+                let num = self.src[start..self.pos]
+                    .iter()
+                    .filter(|c| **c != ',')
+                    .collect::<String>();
+                Ok(num.parse::<f64>()?)
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.pos += 1;
+                }
+                let name = self.src[start..self.pos].iter().collect::<String>();
+                let f = match name.as_str() {
+                    "sqrt" => f64::sqrt,
+                    "abs" => f64::abs,
+                    "pi" => return Ok(std::f64::consts::PI),
+                    n => anyhow::bail!("unknown function `{n}`"),
+                };
+
+                Ok(f(self.primary()?))
+            }
+            Some(c) => anyhow::bail!("unexpected `{c}`"),
+            None => anyhow::bail!("unexpected end of expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{calculate, Tools};
+
+    #[test]
+    fn calculator() -> anyhow::Result<()> {
+        assert_eq!(calculate("17% * 2340")?, 397.8);
+        assert_eq!(calculate("2 + 3 * (4 - 1)")?, 11.);
+        assert_eq!(calculate("-2^2")?, -4.);
+        assert_eq!(calculate("2^3^2")?, 512.);
+        assert_eq!(calculate("sqrt(16) + abs(-1,000)")?, 1004.);
+        assert!(calculate("1 / 0").is_err());
+        assert!(calculate("2 +").is_err());
+        assert!(calculate("rm -rf").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn calls() {
+        let tools = Tools::default();
+        tools.register(
+            "echo",
+            "Echoes the input",
+            json!({ "type": "object" }),
+            |p| Ok(p.clone()),
+        );
+
+        let (name, params) = tools
+            .parse_call(
+                r#"<|python_tag|>{"name": "calculator", "parameters": {"expression": "1 + 1"}}"#,
+            )
+            .expect("a call");
+        assert_eq!(name, "calculator");
+        assert_eq!(tools.call(&name, &params).result, json!({ "result": 2.0 }));
+
+        let (name, params) = tools
+            .parse_call(r#"{"name": "echo", "arguments": {"a": 1}}"#)
+            .expect("a call");
+        assert_eq!(tools.call(&name, &params).result, json!({ "a": 1 }));
+
+        // prose, or a call to a tool we don't have, is the answer itself
+        assert!(tools.parse_call("The answer is 2").is_none());
+        assert!(tools
+            .parse_call(r#"{"name": "weather", "parameters": {}}"#)
+            .is_none());
+
+        // only the files the user shared can be read
+        let res = tools.call("read_file", &json!({ "name": "Cargo.toml" }));
+        assert!(res.result.get("error").is_some());
+        assert!(tools.prompt().contains(r#""name":"read_file""#));
+    }
+}
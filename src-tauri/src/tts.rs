@@ -0,0 +1,545 @@
+//! Speaks the answers with Parler-TTS mini
+//! The answer is split into sentences as it's generated, each sentence is synthesized on the `speaker` thread while
+//! the next one is being generated and emitted as a `speech` event with its PCM audio
+//! Parler-TTS is steered by a description of the voice, we describe one of its named speakers at the chosen pace
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::{
+    generation::LogitsProcessor,
+    models::parler_tts::{Config, Model},
+};
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::{Speed, TtsConfig},
+    events::{Event, EventBus, ModelEvent, ModelKind, ModelState, SpeechEvent},
+    utils::{device, hf_download},
+};
+
+const MODEL_REPO: &str = "parler-tts/parler-tts-mini-v1";
+const LOCAL_MODEL_TOK: &str = "parler-tokenizer.json";
+const LOCAL_MODEL_CFG: &str = "parler-config.json";
+const LOCAL_MODEL_MODEL: &str = "parler.safetensors";
+
+/// The speakers Parler-TTS mini was trained on, naming one in the description keeps the voice consistent across sentences
+pub const VOICES: &[&str] = &[
+    "Laura", "Gary", "Jon", "Lea", "Karen", "Rick", "Brenda", "David", "Eileen", "Jordan", "Mike",
+    "Yann", "Joy", "James", "Eric", "Lauren", "Rose", "Will", "Jason", "Aaron", "Naomie", "Alisa",
+    "Patrick", "Jerry", "Tina", "Jenna", "Bill", "Tom", "Carol", "Barbara", "Rebecca", "Anna",
+    "Bruce", "Emily",
+];
+
+/// Sentences shorter than this are joined with the next one, fragments sound choppy on their own
+const MIN_SENTENCE_CHARS: usize = 24;
+/// Longer sentences are broken at a comma or a space
+const MAX_SENTENCE_CHARS: usize = 280;
+/// A generous speaking rate, in characters per second, to bound the number of audio frames generated
+const MIN_CHARS_PER_SEC: usize = 8;
+/// A fixed seed, so that the same voice is sampled for every sentence
+const SEED: u64 = 299_792_458;
+
+/// Parler-TTS mini: text and a description of the voice in, mono PCM out
+pub struct Tts {
+    model: Model,
+    tokenizer: Tokenizer,
+    device: Device,
+    /// DAC frames per second of audio
+    frame_rate: usize,
+    sample_rate: u32,
+}
+
+impl Tts {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let device = device()?;
+        Self::model_path(dir)?;
+
+        info!("Loading speech model");
+        let tokenizer = match Tokenizer::from_file(dir.join(LOCAL_MODEL_TOK)) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Error loading speech tokenizer: {e:?}");
+                anyhow::bail!("error loading speech tokenizer");
+            }
+        };
+
+        let config: Config =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(LOCAL_MODEL_CFG))?)?;
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(
+                &[dir.join(LOCAL_MODEL_MODEL)],
+                DType::F32,
+                &device,
+            )?
+        };
+        let model = Model::new(&config, vb)?;
+
+        info!("Speech model ready!");
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+            frame_rate: config.audio_encoder.frame_rate as usize,
+            sample_rate: config.audio_encoder.sampling_rate,
+        })
+    }
+
+    /// Downloads the model to `dir` if it isn't already present
+    pub fn download(dir: &Path) -> Result<()> {
+        Self::model_path(dir)
+    }
+
+    fn model_path(dir: &Path) -> Result<()> {
+        for (file, local) in [
+            ("tokenizer.json", LOCAL_MODEL_TOK),
+            ("config.json", LOCAL_MODEL_CFG),
+            ("model.safetensors", LOCAL_MODEL_MODEL),
+        ] {
+            if !dir.join(local).is_file() {
+                hf_download(dir, MODEL_REPO, file, Some(local))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn tokens(&self, text: &str) -> Result<Tensor> {
+        let ids = match self.tokenizer.encode(text, true) {
+            Ok(e) => e.get_ids().to_vec(),
+            Err(e) => {
+                error!("tts: error tokenizing: {e:?}");
+                anyhow::bail!("error tokenizing text to speak");
+            }
+        };
+
+        Ok(Tensor::new(ids, &self.device)?.unsqueeze(0)?)
+    }
+
+    /// Speaks `text` in the voice matching `description`, returns the PCM @ `sample_rate()`
+    pub fn synthesize(&mut self, text: &str, description: &str) -> Result<Vec<f32>> {
+        let prompt = self.tokens(text)?;
+        let description = self.tokens(description)?;
+        let max_steps = (text.chars().count() / MIN_CHARS_PER_SEC + 2) * self.frame_rate;
+
+        let codes = self.model.generate(
+            &prompt,
+            &description,
+            LogitsProcessor::new(SEED, Some(1.), None),
+            max_steps,
+        )?;
+        let codes = codes.to_dtype(DType::I64)?.unsqueeze(0)?;
+        let pcm = self
+            .model
+            .audio_encoder
+            .decode_codes(&codes.to_device(&self.device)?)?
+            .i((0, 0))?
+            .to_vec1::<f32>()?;
+
+        Ok(normalize(pcm))
+    }
+}
+
+// scales the peak to just below full scale, the loudness varies from one sentence to the next otherwise
+fn normalize(mut pcm: Vec<f32>) -> Vec<f32> {
+    let peak = pcm.iter().fold(0f32, |m, s| m.max(s.abs()));
+    if peak > f32::EPSILON {
+        let gain = 0.9 / peak;
+        pcm.iter_mut().for_each(|s| *s *= gain);
+    }
+
+    pcm
+}
+
+/// The description Parler-TTS is prompted with for `voice` at `speed`
+pub fn description(voice: &str, speed: Speed) -> String {
+    let pace = match speed {
+        Speed::Slow => "speaks slowly",
+        Speed::Normal => "speaks at a moderate speed",
+        Speed::Fast => "speaks quickly",
+    };
+
+    format!("{voice} {pace} with a moderate pitch, in a very clear and close recording with no background noise.")
+}
+
+// markdown doesn't read out well
+fn speakable(text: &str) -> String {
+    text.chars()
+        .filter(|c| !matches!(c, '*' | '#' | '`' | '|'))
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+// the period after `text` is likely part of an abbreviation like `e.g.` or `Dr.` rather than the end of a sentence
+fn abbreviation(text: &str) -> bool {
+    let word = text.rsplit(char::is_whitespace).next().unwrap_or_default();
+
+    word.chars().count() == 1
+        || word.contains('.')
+        || ["Mr", "Mrs", "Ms", "Dr", "St", "Jr", "Sr", "vs"].contains(&word)
+}
+
+/// Splits text into sentences as it streams in
+#[derive(Debug, Default)]
+pub struct Sentences {
+    buf: String,
+}
+
+impl Sentences {
+    /// Adds the next piece of text, returns the sentences it completes
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.buf.push_str(text);
+
+        let mut sentences = vec![];
+        while let Some(end) = self.boundary() {
+            let rest = self.buf.split_off(end);
+            let sentence = std::mem::replace(&mut self.buf, rest);
+            let sentence = sentence.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+        }
+
+        sentences
+    }
+
+    /// The text left over once the stream is done
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.buf).trim().to_string()
+    }
+
+    // the end of the first complete sentence in the buffer, if any
+    fn boundary(&self) -> Option<usize> {
+        let mut chars = self.buf.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let end = i + c.len_utf8();
+            let long_enough = self.buf[..end].trim().chars().count() >= MIN_SENTENCE_CHARS;
+            match (c, chars.peek()) {
+                // a line break ends list items and headings, however short
+                ('\n', _) => return Some(end),
+                // the terminator has to be followed by a space, `3.14` or `...` aren't done yet
+                ('.' | '!' | '?', Some((_, n)))
+                    if n.is_whitespace() && long_enough && !abbreviation(&self.buf[..i]) =>
+                {
+                    return Some(end)
+                }
+                _ => {}
+            }
+        }
+
+        // no sentence in sight, break a long one at the last comma, or space
+        if self.buf.chars().count() <= MAX_SENTENCE_CHARS {
+            return None;
+        }
+        let head = match self.buf.char_indices().nth(MAX_SENTENCE_CHARS) {
+            Some((i, _)) => &self.buf[..i],
+            None => &self.buf[..],
+        };
+        head.rfind(", ")
+            .map(|i| i + 1)
+            .or_else(|| head.rfind(' '))
+            .filter(|i| *i > 0)
+            .or(Some(head.len()))
+    }
+}
+
+/// Messages for the speaker thread
+enum Job {
+    Say {
+        utterance: u64,
+        seq: usize,
+        text: String,
+        description: String,
+        last: bool,
+    },
+    /// frees the model if it didn't speak for this long
+    UnloadIdle(Duration),
+    Exit,
+}
+
+/// Synthesizes sentences on a dedicated thread, so that speech overlaps with the generation of the answer
+/// The model is loaded on the first sentence, the app doesn't pay for it unless speech is enabled
+pub struct Speaker {
+    send: Sender<Job>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// the id of the last utterance started
+    latest: AtomicU64,
+    /// utterances up to this id were stopped, their pending sentences are dropped
+    stopped: Arc<AtomicU64>,
+}
+
+impl Speaker {
+    pub fn new(dir: PathBuf, events: Arc<EventBus>) -> Result<Self> {
+        let (send, recv) = channel();
+        let stopped = Arc::new(AtomicU64::new(0));
+
+        let s = Arc::clone(&stopped);
+        let thread = thread::Builder::new()
+            .name("speaker".to_string())
+            .spawn(move || Self::run(dir, recv, events, s))?;
+
+        Ok(Self {
+            send,
+            thread: Mutex::new(Some(thread)),
+            latest: AtomicU64::new(0),
+            stopped,
+        })
+    }
+
+    /// Starts speaking a new answer with the voice and speed in `config`
+    pub fn utterance(&self, config: &TtsConfig) -> Utterance<'_> {
+        Utterance {
+            speaker: self,
+            id: self.latest.fetch_add(1, Ordering::SeqCst) + 1,
+            seq: 0,
+            sentences: Sentences::default(),
+            description: description(&config.voice, config.speed),
+            done: false,
+        }
+    }
+
+    /// Stops every utterance started so far, the sentences not yet emitted are dropped
+    pub fn stop(&self) {
+        self.stopped
+            .store(self.latest.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    /// Frees the model once the sentences queued so far are spoken, if it didn't speak in the last `idle`
+    /// It's loaded again for the next sentence, a zero `idle` keeps it loaded
+    pub fn unload_idle(&self, idle: Duration) {
+        if idle.is_zero() {
+            return;
+        }
+        if let Err(e) = self.send.send(Job::UnloadIdle(idle)) {
+            error!("speaker: error signalling thread: {e:?}");
+        }
+    }
+
+    /// Drops whatever is pending and waits for the speaker thread to exit
+    pub fn shutdown(&self) {
+        let thread = match self.thread.lock() {
+            Ok(mut t) => t.take(),
+            Err(e) => {
+                error!("speaker: error acquiring thread lock: {e:?}");
+                None
+            }
+        };
+
+        if let Some(t) = thread {
+            self.stop();
+            if let Err(e) = self.send.send(Job::Exit) {
+                error!("speaker: error signalling thread: {e:?}");
+            }
+            if let Err(e) = t.join() {
+                error!("speaker: error joining thread: {e:?}");
+            }
+        }
+    }
+
+    fn run(dir: PathBuf, recv: Receiver<Job>, events: Arc<EventBus>, stopped: Arc<AtomicU64>) {
+        let mut tts: Option<Tts> = None;
+        // the utterance the model last failed to load for, the rest of it is left silent rather than retried sentence by sentence
+        let mut failed: Option<u64> = None;
+        let mut used = Instant::now();
+        let emit = |state: ModelState, error: Option<String>| {
+            events.emit(Event::Model(ModelEvent {
+                kind: ModelKind::Tts,
+                name: MODEL_REPO.to_string(),
+                state,
+                error,
+            }))
+        };
+
+        loop {
+            let (utterance, seq, text, description, last) = match recv.recv() {
+                Ok(Job::Say {
+                    utterance,
+                    seq,
+                    text,
+                    description,
+                    last,
+                }) => (utterance, seq, text, description, last),
+                Ok(Job::UnloadIdle(idle)) => {
+                    if tts.is_some() && used.elapsed() >= idle {
+                        tts = None;
+                        emit(ModelState::Unloaded, None);
+                    }
+                    continue;
+                }
+                Ok(Job::Exit) | Err(_) => break,
+            };
+
+            if utterance <= stopped.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            let pcm = if text.is_empty() {
+                vec![]
+            } else {
+                if tts.is_none() && failed != Some(utterance) {
+                    emit(ModelState::Loading, None);
+                    match Tts::new(dir.as_path()) {
+                        Ok(t) => {
+                            tts = Some(t);
+                            emit(ModelState::Ready, None);
+                        }
+                        Err(e) => {
+                            error!("speaker: error loading model: {e:?}");
+                            emit(ModelState::Failed, Some(e.to_string()));
+                            failed = Some(utterance);
+                        }
+                    }
+                }
+
+                let pcm = match tts.as_mut().map(|t| t.synthesize(&text, &description)) {
+                    Some(Ok(pcm)) => pcm,
+                    Some(Err(e)) => {
+                        error!("speaker: error synthesizing `{text}`: {e:?}");
+                        vec![]
+                    }
+                    None => vec![],
+                };
+                used = Instant::now();
+
+                pcm
+            };
+
+            // the utterance may have been stopped while we were at it
+            if utterance <= stopped.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            events.emit(Event::Speech(SpeechEvent {
+                utterance,
+                seq,
+                text,
+                sample_rate: tts.as_ref().map_or(0, |t| t.sample_rate()),
+                pcm,
+                last,
+            }));
+        }
+
+        info!("speaker: stopped");
+    }
+}
+
+/// An answer being spoken, sentence by sentence as it's generated
+/// Dropping it before `finish()`, e.g. on an error, ends the utterance without speaking the rest
+pub struct Utterance<'a> {
+    speaker: &'a Speaker,
+    id: u64,
+    seq: usize,
+    sentences: Sentences,
+    description: String,
+    done: bool,
+}
+
+impl Utterance<'_> {
+    /// The id the `speech` events of this utterance carry
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Adds the next piece of the answer, the sentences it completes are queued for synthesis
+    pub fn push(&mut self, text: &str) {
+        for s in self.sentences.push(text) {
+            self.say(&s, false);
+        }
+    }
+
+    /// Speaks whatever is left of the answer
+    pub fn finish(mut self) {
+        let rest = self.sentences.finish();
+        self.say(&rest, true);
+        self.done = true;
+    }
+
+    fn say(&mut self, text: &str, last: bool) {
+        let text = speakable(text);
+        // the last one is sent regardless, to tell the frontend the utterance is complete
+        if text.is_empty() && !last {
+            return;
+        }
+
+        let job = Job::Say {
+            utterance: self.id,
+            seq: self.seq,
+            text,
+            description: self.description.clone(),
+            last,
+        };
+        if let Err(e) = self.speaker.send.send(job) {
+            error!("utterance: error queueing sentence: {e:?}");
+        }
+        self.seq += 1;
+    }
+}
+
+impl Drop for Utterance<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.say("", true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{speakable, Sentences, MAX_SENTENCE_CHARS};
+
+    #[test]
+    fn sentences() {
+        let mut s = Sentences::default();
+        let mut out = vec![];
+        for piece in [
+            "Steve Wozniak co-founded Apple",
+            " in 1976, e.g. with Jobs. He designed",
+            " the Apple I and II! Pi is 3.14 roughly",
+            ".\n- a list\n",
+            "- of items",
+        ] {
+            out.extend(s.push(piece));
+        }
+        out.push(s.finish());
+
+        assert_eq!(
+            out,
+            [
+                "Steve Wozniak co-founded Apple in 1976, e.g. with Jobs.",
+                "He designed the Apple I and II!",
+                "Pi is 3.14 roughly.",
+                "- a list",
+                "- of items",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_sentences() {
+        let mut s = Sentences::default();
+        let text = "word, ".repeat(100);
+        let out = s.push(&text);
+
+        assert!(!out.is_empty());
+        assert!(out
+            .iter()
+            .all(|o| o.chars().count() <= MAX_SENTENCE_CHARS && o.ends_with(',')));
+        assert_eq!(speakable("**Bold** `code`"), "Bold code");
+    }
+}
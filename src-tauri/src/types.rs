@@ -0,0 +1,297 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    grammar::{self, Grammar},
+    rag::Source,
+    scheduler::Priority,
+    tools::ToolCall,
+};
+
+/// A struct to represent the incoming request
+/// For `text` inference it would contain the instruction itself,
+/// But for `audio` instructions we'll just need to indicate that we are looking for the audio recorded to generate inference since audio data is sent in chunks and
+/// already maintained in the app state
+#[derive(Debug, Deserialize)]
+pub struct Command {
+    text: Option<String>,
+    audio: Option<bool>,
+    /// an optional client generated id, to track the request through `queue` events and to `cancel` it
+    id: Option<String>,
+    /// defaults to `interactive`
+    priority: Option<Priority>,
+    /// the conversation this instruction continues, a new conversation is started if not provided
+    conversation: Option<String>,
+    /// constrains the answer to a format, plain text if not provided
+    response_format: Option<ResponseFormat>,
+}
+
+/// Enum to maintain what kind of instruction this is
+pub enum Mode {
+    Text(String),
+    Audio,
+}
+
+impl Command {
+    pub fn mode(&self) -> Result<Mode> {
+        if let Some(t) = self.text.as_ref() {
+            Ok(Mode::Text(t.to_owned()))
+        } else if self.audio.is_some_and(|d| d) {
+            Ok(Mode::Audio)
+        } else {
+            anyhow::bail!("not a valid command")
+        }
+    }
+
+    pub fn id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or_default()
+    }
+
+    pub fn conversation(&self) -> Option<String> {
+        self.conversation.clone()
+    }
+
+    pub fn response_format(&self) -> Option<ResponseFormat> {
+        self.response_format.clone()
+    }
+}
+
+/// The format of the answer, the same shape as OpenAI's `response_format`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// any JSON object
+    JsonObject,
+    /// JSON valid under the given schema
+    JsonSchema {
+        json_schema: JsonSchema,
+    },
+    /// text matching a llama.cpp style GBNF grammar
+    Grammar {
+        grammar: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchema {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub schema: serde_json::Value,
+}
+
+impl ResponseFormat {
+    /// The grammar the generated text is constrained to, `None` for free text
+    pub fn grammar(&self) -> Result<Option<Grammar>> {
+        match self {
+            Self::Text => Ok(None),
+            Self::JsonObject => Ok(Some(grammar::json::any_object()?)),
+            Self::JsonSchema { json_schema } => {
+                Ok(Some(grammar::json::from_schema(&json_schema.schema)?))
+            }
+            Self::Grammar { grammar } => Ok(Some(grammar::gbnf::parse(grammar)?)),
+        }
+    }
+
+    /// The answer is JSON, to be parsed into `Response::json`
+    pub fn is_json(&self) -> bool {
+        matches!(self, Self::JsonObject | Self::JsonSchema { .. })
+    }
+}
+
+/// A single message of a chat conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    /// one of `system`, `user` or `assistant`
+    pub role: String,
+    pub content: String,
+}
+
+/// A struct to hold the response data and some stats or metadata required to show the inference
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    text: String,
+    meta: Meta,
+    instruct: String,
+    /// the name of the persona profile used to generate this
+    profile: String,
+    /// the id of the history entry, if recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// the conversation this response belongs to, if recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation: Option<String>,
+    /// the parsed answer, for a JSON `response_format`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    json: Option<serde_json::Value>,
+    /// the tools called to answer, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolCall>,
+    /// the document excerpts the answer was based on, cited by their `id`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<Source>,
+}
+
+/// A struct to hold some metadata and additional information about the QA/ Response/ Instruction etc.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Meta {
+    // number of tokens generated
+    n_tokens: u32,
+    // number of seconds elapsed
+    n_secs: u64,
+    // how the system prompt prefix cache fared for this response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefix_cache: Option<PrefixCacheStats>,
+}
+
+/// How a request fared with the cache of the system prompt prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrefixOutcome {
+    /// the cached prefix was restored
+    Hit,
+    /// the prefix was run and cached, it differed from the cached one
+    #[default]
+    Miss,
+    /// the cache wasn't looked at, there's no system prompt or the rest of the prompt is long enough to run the whole of it at once
+    Skipped,
+}
+
+/// Stats of the cache of the system prompt prefix
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrefixCacheStats {
+    pub outcome: PrefixOutcome,
+    /// number of prompt tokens restored from the cache
+    pub tokens: usize,
+    /// totals since the model was loaded
+    pub hits: u64,
+    pub misses: u64,
+    pub skipped: u64,
+}
+
+impl Meta {
+    pub fn n_tokens(&self) -> u32 {
+        self.n_tokens
+    }
+}
+
+impl Response {
+    pub fn new(instruct: &str, txt: &str, profile: &str, n_tokens: u32, n_secs: u64) -> Self {
+        Self {
+            instruct: instruct.to_string(),
+            text: txt.to_string(),
+            profile: profile.to_string(),
+            meta: Meta {
+                n_secs,
+                n_tokens,
+                prefix_cache: None,
+            },
+            id: None,
+            conversation: None,
+            json: None,
+            tools: vec![],
+            sources: vec![],
+        }
+    }
+
+    pub fn set_prefix_cache(&mut self, stats: PrefixCacheStats) {
+        self.meta.prefix_cache = Some(stats);
+    }
+
+    /// Links the response to its history entry
+    pub fn set_history(&mut self, id: &str, conversation: &str) {
+        self.id = Some(id.to_string());
+        self.conversation = Some(conversation.to_string());
+    }
+
+    pub fn set_tools(&mut self, tools: Vec<ToolCall>) {
+        self.tools = tools;
+    }
+
+    pub fn set_sources(&mut self, sources: Vec<Source>) {
+        self.sources = sources;
+    }
+
+    /// Parses the answer as JSON, if the request asked for a JSON `format`
+    pub fn parse_json(&mut self, format: Option<&ResponseFormat>) -> Result<()> {
+        if format.is_some_and(|f| f.is_json()) {
+            self.json = Some(serde_json::from_str(self.text.trim())?);
+        }
+
+        Ok(())
+    }
+
+    /// The parsed answer, for a JSON `response_format`
+    pub fn json(&self) -> Option<&serde_json::Value> {
+        self.json.as_ref()
+    }
+
+    /// The generated answer
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The instruction this is a response to, for `audio` this is the transcript
+    pub fn instruct(&self) -> &str {
+        &self.instruct
+    }
+
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+}
+
+/// A struct to hold the output of a plain transcription - no text generation involved
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transcription {
+    text: String,
+    meta: Meta,
+}
+
+impl Transcription {
+    pub fn new(txt: &str, n_tokens: u32, n_secs: u64) -> Self {
+        Self {
+            text: txt.to_string(),
+            meta: Meta {
+                n_secs,
+                n_tokens,
+                prefix_cache: None,
+            },
+        }
+    }
+
+    /// The transcribed text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// How the models are set up to run, returned by the `system_info` command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemInfo {
+    /// the compute device, e.g. `Cpu` or `Metal { gpu_id: 0 }`
+    pub device: String,
+    /// the threads the CPU ops run on
+    pub threads: usize,
+    /// the cores available to the app
+    pub cores: usize,
+    /// the model files are mapped while loading, instead of read in memory
+    pub mmap: bool,
+    /// the precision `whisper` runs in, `f32`, `f16` or `bf16`
+    pub asr_dtype: String,
+    pub llm: ModelInfo,
+    pub asr: ModelInfo,
+    /// the CPU extensions and BLAS libraries `candle` was built with
+    pub cpu_features: Vec<String>,
+}
+
+/// A model and whether it's in memory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub loaded: bool,
+}
@@ -1,9 +1,15 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use candle_core::Device;
 use hf_hub::api::sync::ApiBuilder;
 
+/// The app identifier, this must match the `identifier` in `tauri.conf.json`
+pub const APP_IDENTIFIER: &str = "audio-instruct.llm";
+
+/// The sample rate our `whisper` pipeline expects
+pub const SAMPLE_RATE: u32 = 16000;
+
 /// a helper function to download a file and move to a specific path from `huggingface hub`
 pub fn hf_download(dir: &Path, repo: &str, file: &str, rename: Option<&str>) -> Result<()> {
     let path = ApiBuilder::new()
@@ -47,6 +53,52 @@ pub fn device() -> Result<Device> {
     Ok(dev)
 }
 
+/// Sizes the thread pool the CPU ops run on, 0 keeps a thread per core
+/// The pool can only be sized before it's first used, so this has to run before any model is loaded
+pub fn init_threads(threads: usize) {
+    if threads == 0 {
+        return;
+    }
+
+    match rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+    {
+        Ok(()) => info!("CPU threads: {threads}"),
+        Err(e) => warn!("error sizing the thread pool, it's already running: {e:?}"),
+    }
+}
+
+/// The contents of a model file, mapped or read in memory
+pub enum ModelBytes {
+    Mapped(memmap2::Mmap),
+    Read(Vec<u8>),
+}
+
+impl std::ops::Deref for ModelBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(m) => m,
+            Self::Read(v) => v,
+        }
+    }
+}
+
+/// Maps the model file at `path` if `mmap`, reads it in memory otherwise
+pub fn model_bytes(path: &Path, mmap: bool) -> Result<ModelBytes> {
+    if !mmap {
+        return Ok(ModelBytes::Read(std::fs::read(path)?));
+    }
+
+    let file = std::fs::File::open(path)?;
+    // the model files are only written while they are downloaded, before they are loaded
+    let mapped = unsafe { memmap2::Mmap::map(&file)? };
+
+    Ok(ModelBytes::Mapped(mapped))
+}
+
 /// A helper function to convert incomig &[u8] to Vec<f32>
 pub fn bytes_to_f32(bytes: &[u8]) -> Vec<f32> {
     bytes
@@ -54,3 +106,63 @@ pub fn bytes_to_f32(bytes: &[u8]) -> Vec<f32> {
         .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
         .collect()
 }
+
+/// The default data directory, this resolves to the same path as tauri's `app_data_dir()` so that the desktop app and the cli share the downloaded models
+pub fn default_data_dir() -> Result<PathBuf> {
+    let Some(dir) = dirs::data_dir() else {
+        anyhow::bail!("could not resolve the data directory");
+    };
+
+    Ok(dir.join(APP_IDENTIFIER))
+}
+
+/// A helper function to read a `.wav` file into mono `f32` samples @ `SAMPLE_RATE`
+/// Multi-channel audio is averaged down to a single channel and other sample rates are linearly resampled
+pub fn read_wav<R: std::io::Read>(reader: R) -> Result<Vec<f32>> {
+    let mut wav = hound::WavReader::new(reader)?;
+    let spec = wav.spec();
+
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => wav.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let max = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            wav.samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / max))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let mono = if channels > 1 {
+        samples
+            .chunks_exact(channels)
+            .map(|c| c.iter().sum::<f32>() / channels as f32)
+            .collect::<Vec<_>>()
+    } else {
+        samples
+    };
+
+    Ok(resample(&mono, spec.sample_rate, SAMPLE_RATE))
+}
+
+/// A naive linear interpolation resampler, good enough for speech
+pub fn resample(pcm: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || pcm.is_empty() {
+        return pcm.to_vec();
+    }
+
+    let ratio = from as f64 / to as f64;
+    let n = (pcm.len() as f64 / ratio).floor() as usize;
+
+    (0..n)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let idx = pos.floor() as usize;
+            let frac = (pos - idx as f64) as f32;
+            let a = pcm[idx];
+            let b = *pcm.get(idx + 1).unwrap_or(&a);
+
+            a + (b - a) * frac
+        })
+        .collect()
+}
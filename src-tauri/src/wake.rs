@@ -0,0 +1,398 @@
+//! Wake word spotting for the always-listening mode
+//! The user records the wake phrase a few times, each recording is kept as a template of its log-mel frames
+//! The incoming audio is compared to the templates with dynamic time warping (DTW) every `STEP`, once it matches
+//! the audio that follows is captured for `whisper` until the user stops speaking
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use candle_transformers::models::whisper::{HOP_LENGTH, SAMPLE_RATE};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::WakeConfig, mel::LogMel};
+
+const TEMPLATES_FILE: &str = "wake.json";
+
+/// The wake phrase doesn't need the detail `whisper` does
+const N_MEL: usize = 80;
+/// The spotter looks for the wake phrase, and the capture for silence, every 100 ms
+const STEP: usize = SAMPLE_RATE / 10;
+/// A recorded wake phrase has to be between 0.3 and 3 seconds long once the silence around it is trimmed
+const MIN_TEMPLATE_FRAMES: usize = 30;
+const MAX_TEMPLATE_FRAMES: usize = 300;
+/// Frames this far (in `log10` power) below the loudest one are silence around a recorded wake phrase
+const TRIM_LEVEL: f32 = 2.;
+/// Audio quieter than this (RMS) is silence
+const SILENCE_RMS: f32 = 0.01;
+/// The capture ends after this many silent steps following speech
+const END_SILENCE_STEPS: usize = 10;
+/// The capture is abandoned if nothing is said this many steps after the wake phrase
+const NO_SPEECH_STEPS: usize = 50;
+/// The window matched against a template is this much shorter or longer than it, to allow for the pace of speech
+const WINDOW_SCALES: &[f32] = &[0.8, 1., 1.25];
+
+/// What the always-listening mode makes of the incoming audio
+#[derive(Debug, PartialEq)]
+pub enum Heard {
+    /// the wake phrase was spotted, with the DTW distance of the best match
+    Wake(f32),
+    /// audio following the wake phrase, to be transcribed
+    Speech(Vec<f32>),
+    /// the speech following the wake phrase ended
+    End,
+}
+
+enum State {
+    Listening,
+    Capturing {
+        steps: usize,
+        silent: usize,
+        spoke: bool,
+    },
+}
+
+/// The recorded wake phrases, persisted as `wake.json` in the app data directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Templates {
+    /// frames of `N_MEL` features, mean normalized and unit length
+    templates: Vec<Vec<Vec<f32>>>,
+}
+
+/// Spots the wake phrase in the audio streaming in, and captures what follows it
+pub struct Spotter {
+    path: PathBuf,
+    templates: Templates,
+    mel: LogMel,
+    /// the latest frames, as many as the longest window
+    frames: VecDeque<Vec<f32>>,
+    /// the latest samples, as many as the longest window
+    recent: VecDeque<f32>,
+    /// samples not yet processed in a `STEP`
+    pending: Vec<f32>,
+    state: State,
+}
+
+impl Spotter {
+    /// Loads the wake phrases recorded earlier in `dir`, if any
+    pub fn new(dir: &Path) -> Result<Self> {
+        let path = dir.join(TEMPLATES_FILE);
+        let templates = if path.is_file() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            Templates::default()
+        };
+
+        Ok(Self {
+            path,
+            templates,
+            mel: LogMel::new(N_MEL)?,
+            frames: VecDeque::new(),
+            recent: VecDeque::new(),
+            pending: vec![],
+            state: State::Listening,
+        })
+    }
+
+    /// Number of recordings of the wake phrase
+    pub fn templates(&self) -> usize {
+        self.templates.templates.len()
+    }
+
+    /// Adds a recording of the wake phrase, mono @ 16 kHz
+    /// Returns the number of recordings so far
+    pub fn enroll(&mut self, pcm: &[f32]) -> Result<usize> {
+        if rms(pcm.iter()) < SILENCE_RMS {
+            anyhow::bail!("the wake phrase recording is too quiet");
+        }
+
+        let mut mel = LogMel::new(N_MEL)?;
+        let frames = trim(mel.push(pcm));
+        if frames.len() < MIN_TEMPLATE_FRAMES {
+            anyhow::bail!("the wake phrase recording is too short");
+        }
+        if frames.len() > MAX_TEMPLATE_FRAMES {
+            anyhow::bail!("the wake phrase recording is too long, keep it under 3 seconds");
+        }
+
+        self.templates.templates.push(features(frames));
+        self.save()?;
+        self.reset();
+
+        Ok(self.templates())
+    }
+
+    /// Forgets the recordings of the wake phrase
+    pub fn clear(&mut self) -> Result<()> {
+        self.templates.templates.clear();
+        self.reset();
+
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = File::create(&self.path)?;
+        serde_json::to_writer(BufWriter::new(file), &self.templates)?;
+
+        Ok(())
+    }
+
+    // starts listening afresh
+    fn reset(&mut self) {
+        self.mel.reset();
+        self.frames.clear();
+        self.recent.clear();
+        self.state = State::Listening;
+    }
+
+    /// Feeds the next chunk of audio, mono @ 16 kHz
+    pub fn feed(&mut self, chunk: &[f32], config: &WakeConfig) -> Vec<Heard> {
+        self.pending.extend_from_slice(chunk);
+
+        let mut heard = vec![];
+        let mut start = 0;
+        while start + STEP <= self.pending.len() {
+            let step = self.pending[start..start + STEP].to_vec();
+            self.step(step, config, &mut heard);
+            start += STEP;
+        }
+        self.pending.drain(..start);
+
+        heard
+    }
+
+    fn step(&mut self, pcm: Vec<f32>, config: &WakeConfig, heard: &mut Vec<Heard>) {
+        match &mut self.state {
+            State::Listening => {
+                if let Some(distance) = self.listen(&pcm, config.sensitivity) {
+                    heard.push(Heard::Wake(distance));
+                    self.state = State::Capturing {
+                        steps: 0,
+                        silent: 0,
+                        spoke: false,
+                    };
+                }
+            }
+            State::Capturing {
+                steps,
+                silent,
+                spoke,
+            } => {
+                *steps += 1;
+                if rms(pcm.iter()) < SILENCE_RMS {
+                    *silent += 1;
+                } else {
+                    *silent = 0;
+                    *spoke = true;
+                }
+
+                let done = (*spoke && *silent >= END_SILENCE_STEPS)
+                    || (!*spoke && *steps >= NO_SPEECH_STEPS)
+                    || *steps * STEP >= config.max_secs * SAMPLE_RATE;
+                heard.push(Heard::Speech(pcm));
+                if done {
+                    heard.push(Heard::End);
+                    self.reset();
+                }
+            }
+        }
+    }
+
+    // adds a step of audio, returns the distance to the closest template if it matches the latest audio
+    fn listen(&mut self, pcm: &[f32], sensitivity: f32) -> Option<f32> {
+        let longest = self
+            .templates
+            .templates
+            .iter()
+            .map(|t| window(t.len(), WINDOW_SCALES[WINDOW_SCALES.len() - 1]))
+            .max()?;
+
+        self.frames.extend(self.mel.push(pcm));
+        self.recent.extend(pcm.iter());
+        while self.frames.len() > longest {
+            self.frames.pop_front();
+        }
+        while self.recent.len() > longest * HOP_LENGTH {
+            self.recent.pop_front();
+        }
+
+        // nothing to match in silence
+        if rms(self.recent.iter()) < SILENCE_RMS {
+            return None;
+        }
+
+        let threshold = threshold(sensitivity);
+        let frames = &*self.frames.make_contiguous();
+        let distance = self
+            .templates
+            .templates
+            .iter()
+            .flat_map(|t| {
+                WINDOW_SCALES.iter().filter_map(move |&s| {
+                    let len = window(t.len(), s);
+                    (len <= frames.len()).then_some((t, len))
+                })
+            })
+            .map(|(t, len)| dtw(t, &features(frames[frames.len() - len..].to_vec())))
+            .fold(f32::INFINITY, f32::min);
+
+        (distance <= threshold).then_some(distance)
+    }
+}
+
+// the number of frames in a window `scale` times a template of `len` frames
+fn window(len: usize, scale: f32) -> usize {
+    (len as f32 * scale).round() as usize
+}
+
+// the max DTW distance of a match, `sensitivity` between `0` and `1` with higher being more lenient
+fn threshold(sensitivity: f32) -> f32 {
+    0.1 + 0.3 * sensitivity.clamp(0., 1.)
+}
+
+fn rms<'a, I: ExactSizeIterator<Item = &'a f32>>(samples: I) -> f32 {
+    let n = samples.len().max(1);
+    (samples.map(|s| s * s).sum::<f32>() / n as f32).sqrt()
+}
+
+// drops the silent frames around a recorded wake phrase
+fn trim(frames: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let level = |f: &Vec<f32>| f.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let loudest = frames.iter().map(level).fold(f32::NEG_INFINITY, f32::max);
+    let voiced = |f: &Vec<f32>| level(f) >= loudest - TRIM_LEVEL;
+
+    let Some(start) = frames.iter().position(voiced) else {
+        return vec![];
+    };
+    let end = frames.iter().rposition(voiced).unwrap_or(start);
+
+    frames[start..=end].to_vec()
+}
+
+// subtracts the mean of every mel bin over the frames, so that the loudness and the microphone matter less
+// then scales every frame to unit length, to compare them by their cosine
+fn features(mut frames: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let n = frames.len().max(1) as f32;
+    let mut mean = vec![0f32; N_MEL];
+    frames.iter().for_each(|f| {
+        mean.iter_mut().zip(f.iter()).for_each(|(m, v)| *m += v / n);
+    });
+
+    frames.iter_mut().for_each(|f| {
+        f.iter_mut().zip(mean.iter()).for_each(|(v, m)| *v -= m);
+        let norm = f.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > f32::EPSILON {
+            f.iter_mut().for_each(|v| *v /= norm);
+        }
+    });
+
+    frames
+}
+
+/// The dynamic time warping distance between two sequences of unit length frames
+/// The average cosine distance along the best alignment, `0` for identical sequences
+pub fn dtw(a: &[Vec<f32>], b: &[Vec<f32>]) -> f32 {
+    if a.is_empty() || b.is_empty() {
+        return f32::INFINITY;
+    }
+
+    let cost = |i: usize, j: usize| {
+        1. - a[i]
+            .iter()
+            .zip(b[j].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>()
+    };
+
+    // the cumulative cost and the length of the best path to each cell, a row at a time
+    let mut prev = vec![(f32::INFINITY, 0usize); b.len()];
+    let mut row = vec![(f32::INFINITY, 0usize); b.len()];
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            let best = match (i, j) {
+                (0, 0) => (0., 0),
+                (0, _) => row[j - 1],
+                (_, 0) => prev[j],
+                _ => [prev[j], row[j - 1], prev[j - 1]]
+                    .into_iter()
+                    .min_by(|x, y| x.0.total_cmp(&y.0))
+                    .unwrap_or(prev[j - 1]),
+            };
+            row[j] = (best.0 + cost(i, j), best.1 + 1);
+        }
+        std::mem::swap(&mut prev, &mut row);
+    }
+
+    let (total, len) = prev[b.len() - 1];
+    total / len as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{Heard, Spotter, SAMPLE_RATE};
+    use crate::config::WakeConfig;
+
+    // a tone sweeping from `from` to `to` Hz over `secs`
+    fn sweep(from: f32, to: f32, secs: f32) -> Vec<f32> {
+        let n = (secs * SAMPLE_RATE as f32) as usize;
+        let mut phase = 0f32;
+        (0..n)
+            .map(|i| {
+                let f = from + (to - from) * i as f32 / n as f32;
+                phase += 2. * PI * f / SAMPLE_RATE as f32;
+                phase.sin() * 0.3
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spots_and_captures() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("wake-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let mut spotter = Spotter::new(&dir)?;
+        let phrase = [sweep(300., 1500., 0.4), sweep(1500., 600., 0.4)].concat();
+        assert_eq!(spotter.enroll(&phrase)?, 1);
+        assert!(spotter.enroll(&vec![0.; 16_000]).is_err());
+
+        let config = WakeConfig::default();
+        let heard = |s: &mut Spotter, pcm: &[f32]| {
+            pcm.chunks(4096)
+                .flat_map(|c| s.feed(c, &config))
+                .collect::<Vec<_>>()
+        };
+
+        // something else, and silence, is ignored
+        let other = [vec![0.; 8_000], sweep(2000., 200., 0.8), vec![0.; 8_000]].concat();
+        assert!(heard(&mut spotter, &other).is_empty());
+
+        // the phrase is spotted, what follows it is captured until a second of silence
+        let command = [sweep(400., 500., 1.), vec![0.; 24_000]].concat();
+        let out = heard(&mut spotter, &[phrase.clone(), command].concat());
+        assert!(matches!(out[0], Heard::Wake(_)), "{:?}", out.first());
+        assert_eq!(out.last(), Some(&Heard::End));
+        let captured = out
+            .iter()
+            .filter_map(|h| match h {
+                Heard::Speech(s) => Some(s.len()),
+                _ => None,
+            })
+            .sum::<usize>();
+        assert!((16_000..40_000).contains(&captured), "{captured}");
+
+        // the templates persist
+        assert_eq!(Spotter::new(&dir)?.templates(), 1);
+        spotter.clear()?;
+        assert_eq!(Spotter::new(&dir)?.templates(), 0);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -1,30 +1,38 @@
 use core::f64;
 use std::{
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
-use candle_core::{Device, IndexOp, Tensor};
+use candle_core::{DType, Device, IndexOp, Tensor};
 use candle_nn::{ops::softmax, VarBuilder};
-use candle_transformers::models::whisper::{
-    audio::pcm_to_mel, model::Whisper, Config, DTYPE, EOT_TOKEN, LOGPROB_THRESHOLD,
-    NO_SPEECH_THRESHOLD, NO_TIMESTAMPS_TOKEN, N_FRAMES, SOT_TOKEN, TEMPERATURES, TRANSCRIBE_TOKEN,
+use candle_transformers::{
+    models::whisper::{
+        audio::pcm_to_mel, Config, EOT_TOKEN, LOGPROB_THRESHOLD, NO_SPEECH_THRESHOLD,
+        NO_SPEECH_TOKENS, NO_TIMESTAMPS_TOKEN, N_FRAMES, SOT_TOKEN, TEMPERATURES, TRANSCRIBE_TOKEN,
+    },
+    quantized_var_builder,
 };
 use rand::prelude::Distribution;
 use tokenizers::Tokenizer;
 
-use crate::utils::{device, hf_download};
-
-const MODEL_REPO: &str = "distil-whisper/distil-large-v3";
-
-const LOCAL_MODEL_CFG: &str = "whisper-config.json";
-const LOCAL_MODEL_TOK: &str = "whisper-tokenizer.json";
-const LOCAL_MODEL_MODEL: &str = "whisper-model.safetensors";
+use crate::{
+    asr::{Weights, Whisper},
+    config::{AsrConfig, CpuConfig, Dtype, WhisperModel},
+    mel::{self, LogMel},
+    scheduler::Cancel,
+    utils::{device, hf_download, model_bytes},
+};
 
-/// Enabling inference for English only
-const LANGUAGE: &str = "en";
+/// The quantized weights converted for `candle`
+const QUANTIZED_REPO: &str = "lmz/candle-whisper";
 
 #[derive(Debug)]
 struct DecodingResult {
@@ -34,10 +42,53 @@ struct DecodingResult {
     temperature: f64,
 }
 
-/// A struct to hold `distil-whisper` object and associated methods
+/// The audio recorded so far and its log-mel frames, computed as it streams in
+struct Recording {
+    pcm: Vec<f32>,
+    mel: LogMel,
+    frames: Vec<Vec<f32>>,
+    /// counts the recordings taken, to tell their windows apart
+    generation: u64,
+    /// the full `N_FRAMES` windows handed to the encoder so far
+    windows: usize,
+    /// whether the frames and windows are computed as the audio streams in, decided at the start of the recording
+    ahead: bool,
+}
+
+/// The frames of the last recording taken, waiting for it to be transcribed
+struct Features {
+    // of the recorded samples, a transformed recording needs its frames computed again
+    fingerprint: u64,
+    frames: Vec<Vec<f32>>,
+    generation: u64,
+}
+
+/// A full window of a recording, to be encoded ahead of the transcription
+struct Window {
+    generation: u64,
+    index: usize,
+    frames: Vec<Vec<f32>>,
+    /// the loudest frame value so far, the window is normalized to it
+    max: f32,
+}
+
+/// The encoder output for a `Window`
+/// It's only valid if the whole recording turns out to be normalized to the same `max`
+struct Encoded {
+    generation: u64,
+    index: usize,
+    max: f32,
+    features: Tensor,
+}
+
+/// A struct to hold the `whisper` model and associated methods
 pub struct WhisperWrap {
     device: Device,
-    data: Arc<Mutex<Vec<f32>>>,
+    data: Mutex<Recording>,
+    features: Mutex<Option<Features>>,
+    /// hands the full windows to the `encoder` thread
+    ahead: Sender<Window>,
+    encoded: Arc<Mutex<Vec<Encoded>>>,
     config: Config,
     mel_filters: Vec<f32>,
     model: Arc<Mutex<Whisper>>,
@@ -61,80 +112,188 @@ pub struct DefaultTokens {
 }
 
 impl DefaultTokens {
-    pub fn init(tk: &Tokenizer) -> Self {
-        Self {
-            sot: tk.token_to_id(SOT_TOKEN).unwrap(),
-            eot: tk.token_to_id(EOT_TOKEN).unwrap(),
-            lang: tk.token_to_id(format!("<|{LANGUAGE}|>").as_str()).unwrap(),
-            transcribe: tk.token_to_id(TRANSCRIBE_TOKEN).unwrap(),
-            no_ts: tk.token_to_id(NO_TIMESTAMPS_TOKEN).unwrap(),
-            no_speech: tk.token_to_id("<|nospeech|>").unwrap(),
+    /// The special tokens of the model's tokenizer, to transcribe `language`
+    pub fn init(tk: &Tokenizer, language: &str) -> Result<Self> {
+        let token = |t: &str| {
+            tk.token_to_id(t)
+                .ok_or_else(|| anyhow!("{t} isn't in the whisper tokenizer"))
+        };
+
+        Ok(Self {
+            sot: token(SOT_TOKEN)?,
+            eot: token(EOT_TOKEN)?,
+            lang: token(&format!("<|{language}|>"))
+                .map_err(|_| anyhow!("language `{language}` isn't supported by this model"))?,
+            transcribe: token(TRANSCRIBE_TOKEN)?,
+            no_ts: token(NO_TIMESTAMPS_TOKEN)?,
+            // `<|nocaptions|>` until `large-v3`, `<|nospeech|>` after
+            no_speech: NO_SPEECH_TOKENS
+                .iter()
+                .find_map(|t| tk.token_to_id(t))
+                .ok_or_else(|| anyhow!("no speech token isn't in the whisper tokenizer"))?,
+        })
+    }
+}
+
+/// Where a `whisper` variant is downloaded from, and its files in the app data directory
+struct ModelFiles {
+    repo: &'static str,
+    // the (remote, local) names of the files
+    config: (String, String),
+    tokenizer: (String, String),
+    weights: (String, String),
+}
+
+impl ModelFiles {
+    fn new(config: &AsrConfig) -> Result<Self> {
+        let (repo, name) = match config.model {
+            WhisperModel::Tiny => ("openai/whisper-tiny", "tiny"),
+            WhisperModel::Base => ("openai/whisper-base", "base"),
+            WhisperModel::Small => ("openai/whisper-small", "small"),
+            WhisperModel::Medium => ("openai/whisper-medium", "medium"),
+            WhisperModel::Large => ("openai/whisper-large-v3", "large"),
+            WhisperModel::DistilLarge => ("distil-whisper/distil-large-v3", "distil-large"),
+        };
+
+        if config.quantized {
+            if config.model != WhisperModel::Tiny {
+                anyhow::bail!("quantized weights are only available for the tiny model");
+            }
+            let remote = |f: &str| {
+                format!(
+                    "{f}-tiny{}",
+                    if f == "model" { "-q80.gguf" } else { ".json" }
+                )
+            };
+            return Ok(Self {
+                repo: QUANTIZED_REPO,
+                config: (remote("config"), "whisper-tiny-q80-config.json".to_string()),
+                tokenizer: (
+                    remote("tokenizer"),
+                    "whisper-tiny-q80-tokenizer.json".to_string(),
+                ),
+                weights: (remote("model"), "whisper-tiny-q80.gguf".to_string()),
+            });
         }
+
+        // the default model keeps the names it has always been stored under
+        let local = |f: &str| match config.model {
+            WhisperModel::DistilLarge => format!("whisper-{f}"),
+            _ => format!("whisper-{name}-{f}"),
+        };
+        Ok(Self {
+            repo,
+            config: ("config.json".to_string(), local("config.json")),
+            tokenizer: ("tokenizer.json".to_string(), local("tokenizer.json")),
+            weights: ("model.safetensors".to_string(), local("model.safetensors")),
+        })
+    }
+
+    fn is_quantized(&self) -> bool {
+        self.repo == QUANTIZED_REPO
     }
 }
 
 impl WhisperWrap {
-    pub fn new(dir: &Path) -> Result<Self> {
+    /// Loads the `whisper` variant picked in `asr`
+    /// The mel bins and the special tokens are taken from the variant's config and tokenizer
+    pub fn new(dir: &Path, asr: &AsrConfig, cpu: &CpuConfig) -> Result<Self> {
         let device = device()?;
 
-        let model_path = Self::model_path(dir)?;
-        let (model, mel_filters, tokenizer, config) =
-            Self::load_model(model_path.as_path(), &device)?;
+        let files = Self::model_path(dir, asr)?;
+        let (model, tokenizer, config) = Self::load_model(dir, &files, asr, cpu, &device)?;
+        let model = Arc::new(Mutex::new(model));
+        let mel_filters = mel::filters(config.num_mel_bins)?;
+
+        let data = Recording {
+            pcm: vec![],
+            mel: LogMel::new(config.num_mel_bins)?,
+            frames: vec![],
+            generation: 0,
+            windows: 0,
+            ahead: true,
+        };
+
+        // the encoder runs on the full windows while the recording goes on
+        let encoded = Arc::new(Mutex::new(vec![]));
+        let (ahead, recv) = mpsc::channel();
+        {
+            let model = Arc::clone(&model);
+            let encoded = Arc::clone(&encoded);
+            let device = device.clone();
+            thread::Builder::new()
+                .name("encoder".to_string())
+                .spawn(move || Self::encode_ahead(model, device, encoded, recv))?;
+        }
 
         info!("Whisper ready!");
         Ok(Self {
             device,
             config,
-            data: Arc::new(Mutex::new(Vec::new())),
-            default_tokens: DefaultTokens::init(&tokenizer),
+            data: Mutex::new(data),
+            features: Mutex::new(None),
+            ahead,
+            encoded,
+            default_tokens: DefaultTokens::init(&tokenizer, &asr.language)?,
             mel_filters,
             model,
             tokenizer,
         })
     }
 
+    /// Downloads the model, tokenizer and config files of the variant picked in `asr` to `dir`, if they are not already present
+    pub fn download(dir: &Path, asr: &AsrConfig) -> Result<()> {
+        Self::model_path(dir, asr)?;
+
+        Ok(())
+    }
+
     // checks if the model file(s) exists or not and downloads it
-    // Unlike a `gguf` model, we'll need 33 files for our model to work
-    // the model weights will be in `model.safetensors`
+    // the model weights will be in `model.safetensors`, or a `gguf` for the quantized model
     // the tokenizer.json file for the vocab
     // config.json for the model config
-    fn model_path(base_dir: &Path) -> Result<PathBuf> {
-        // Check if tokenizer.json exists, or create
-        if !base_dir.join(LOCAL_MODEL_TOK).is_file() {
-            hf_download(
-                base_dir,
-                MODEL_REPO,
-                "tokenizer.json",
-                Some(LOCAL_MODEL_TOK),
-            )?;
-        }
+    fn model_path(base_dir: &Path, asr: &AsrConfig) -> Result<ModelFiles> {
+        let files = ModelFiles::new(asr)?;
 
-        // check if config.json exists else download
-        if !base_dir.join(LOCAL_MODEL_CFG).is_file() {
-            hf_download(base_dir, MODEL_REPO, "config.json", Some(LOCAL_MODEL_CFG))?;
+        // the weights are the large file, and might take a while
+        for (remote, local) in [&files.tokenizer, &files.config, &files.weights] {
+            if !base_dir.join(local).is_file() {
+                hf_download(base_dir, files.repo, remote, Some(local))?;
+            }
         }
 
-        // finally, check if model.safetensors exist or download
-        // this is a large file, and might take a while
-        if !base_dir.join(LOCAL_MODEL_MODEL).is_file() {
-            hf_download(
-                base_dir,
-                MODEL_REPO,
-                "model.safetensors",
-                Some(LOCAL_MODEL_MODEL),
-            )?;
-        }
+        Ok(files)
+    }
 
-        Ok(base_dir.to_path_buf())
+    /// The precision the variant in `asr` runs in on `device`
+    /// The quantized weights are dequantized to `f32`, and `candle` has no `bf16` matmul on the CPU
+    pub fn dtype(asr: &AsrConfig, device: &Device) -> DType {
+        match asr.dtype {
+            _ if asr.quantized => DType::F32,
+            Dtype::F32 => DType::F32,
+            Dtype::F16 => DType::F16,
+            Dtype::Bf16 if device.is_cpu() => {
+                warn!("bf16 isn't supported on the CPU, whisper runs in f32");
+                DType::F32
+            }
+            Dtype::Bf16 => DType::BF16,
+        }
     }
 
     fn load_model(
         model_dir: &Path,
+        files: &ModelFiles,
+        asr: &AsrConfig,
+        cpu: &CpuConfig,
         device: &Device,
-    ) -> Result<(Arc<Mutex<Whisper>>, Vec<f32>, Tokenizer, Config)> {
-        info!("Loading whisper");
-
-        let tokenizer = match Tokenizer::from_file(model_dir.join(LOCAL_MODEL_TOK)) {
+    ) -> Result<(Whisper, Tokenizer, Config)> {
+        let dtype = Self::dtype(asr, device);
+        info!(
+            "Loading whisper from {}, {dtype:?}, mmap: {}",
+            files.weights.1, cpu.mmap
+        );
+
+        let tokenizer = match Tokenizer::from_file(model_dir.join(&files.tokenizer.1)) {
             Ok(t) => t,
             Err(e) => {
                 error!("Error loading tokenizer: {e:?}");
@@ -143,40 +302,43 @@ impl WhisperWrap {
         };
 
         let config: Config =
-            serde_json::from_str(&std::fs::read_to_string(model_dir.join(LOCAL_MODEL_CFG))?)?;
-
-        let mel = match &config.num_mel_bins {
-            80 => {
-                include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/melfilters.bytes")).as_slice()
-            }
-            128 => include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/melfilters128.bytes"))
-                .as_slice(),
-            nmel => anyhow::bail!("unexpected num_mel_bins {nmel}"),
-        };
-        let mut mel_filters = vec![0f32; mel.len() / 4];
-        <byteorder::LittleEndian as byteorder::ByteOrder>::read_f32_into(mel, &mut mel_filters);
+            serde_json::from_str(&std::fs::read_to_string(model_dir.join(&files.config.1))?)?;
 
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                &[model_dir.join(LOCAL_MODEL_MODEL)],
-                DTYPE,
+        let weights = model_dir.join(&files.weights.1);
+        let vb = if files.is_quantized() {
+            Weights::Quantized(quantized_var_builder::VarBuilder::from_gguf_buffer(
+                &model_bytes(&weights, cpu.mmap)?,
+                device,
+            )?)
+        } else if cpu.mmap {
+            Weights::Full(unsafe {
+                VarBuilder::from_mmaped_safetensors(&[weights], dtype, device)?
+            })
+        } else {
+            Weights::Full(VarBuilder::from_buffered_safetensors(
+                std::fs::read(weights)?,
+                dtype,
                 device,
-            )?
+            )?)
         };
-        let model = Arc::new(Mutex::new(Whisper::load(&vb, config.clone())?));
+        let model = Whisper::load(&vb, config.clone())?;
 
-        Ok((model, mel_filters, tokenizer, config))
+        Ok((model, tokenizer, config))
     }
 
-    fn preproc(&self) -> Result<Tensor> {
-        let data = match self.data.lock() {
+    /// Drains the audio data buffered so far
+    /// Its log-mel frames are kept for when it's transcribed
+    pub fn take_data(&self) -> Result<Vec<f32>> {
+        let (pcm, frames, generation) = match self.data.lock() {
             Ok(mut d) => {
-                if d.len() < 4096 * 4 {
-                    anyhow::bail!("Not enough audio data in buffer!");
-                }
-                let d = d.drain(..).collect::<Vec<_>>();
-
-                d
+                d.mel.reset();
+                d.windows = 0;
+                d.generation += 1;
+                (
+                    std::mem::take(&mut d.pcm),
+                    std::mem::take(&mut d.frames),
+                    d.generation - 1,
+                )
             }
             Err(e) => {
                 error!("error acquiring data lock: {e:?}");
@@ -184,7 +346,63 @@ impl WhisperWrap {
             }
         };
 
-        let mel = pcm_to_mel(&self.config, &data[..], &self.mel_filters[..]);
+        match self.features.lock() {
+            Ok(mut f) => {
+                *f = Some(Features {
+                    fingerprint: fingerprint(&pcm[..]),
+                    frames,
+                    generation,
+                })
+            }
+            Err(e) => {
+                error!("error acquiring features lock: {e:?}");
+                anyhow::bail!("Not enough audio data in buffer!");
+            }
+        }
+
+        Ok(pcm)
+    }
+
+    /// Whether audio was buffered since the last `take_data()`
+    pub fn has_data(&self) -> bool {
+        self.data.lock().is_ok_and(|d| !d.pcm.is_empty())
+    }
+
+    /// Whether `pcm` is the recording last returned by `take_data()`, as is
+    pub fn is_recorded(&self, pcm: &[f32]) -> bool {
+        self.features.lock().is_ok_and(|f| {
+            f.as_ref()
+                .is_some_and(|f| f.fingerprint == fingerprint(pcm))
+        })
+    }
+
+    // returns the (mel, generation and loudest frame value of the recording, if `data` is the recording)
+    fn preproc(&self, data: &[f32]) -> Result<(Tensor, Option<(u64, f32)>)> {
+        if data.len() < 4096 * 4 {
+            anyhow::bail!("Not enough audio data in buffer!");
+        }
+
+        // the frames computed while recording are used if this is the recording, the whole of it is computed otherwise
+        let features = match self.features.lock() {
+            Ok(mut f) => f.take_if(|f| f.fingerprint == fingerprint(data)),
+            Err(e) => {
+                error!("preproc: error acquiring features lock: {e:?}");
+                None
+            }
+        };
+        let (mel, recording) = match features {
+            Some(f) => match self.data.lock() {
+                Ok(d) => {
+                    let (mel, max) = d.mel.spectrogram(data, f.frames);
+                    (mel, Some((f.generation, max)))
+                }
+                Err(e) => {
+                    error!("preproc: error acquiring data lock: {e:?}");
+                    anyhow::bail!("error during inference");
+                }
+            },
+            None => (pcm_to_mel(&self.config, data, &self.mel_filters[..]), None),
+        };
         let mel_len = mel.len();
         let mel = Tensor::from_vec(
             mel,
@@ -196,7 +414,59 @@ impl WhisperWrap {
             &self.device,
         )?;
 
-        Ok(mel)
+        Ok((mel, recording))
+    }
+
+    // encodes the windows sent by `chunk()` until `WhisperWrap` is dropped
+    fn encode_ahead(
+        model: Arc<Mutex<Whisper>>,
+        device: Device,
+        encoded: Arc<Mutex<Vec<Encoded>>>,
+        recv: Receiver<Window>,
+    ) {
+        while let Ok(mut w) = recv.recv() {
+            let start = Instant::now();
+            mel::normalize(&mut w.frames[..], w.max);
+            let n_mel = w.frames.first().map_or(0, |f| f.len());
+            let features =
+                Tensor::from_vec(mel::transpose(&w.frames[..]), (1, n_mel, N_FRAMES), &device)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mel| match model.lock() {
+                        Ok(mut m) => Ok(m.encoder.forward(&mel)?),
+                        Err(e) => anyhow::bail!("error acquiring model lock: {e:?}"),
+                    });
+            let features = match features {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("encode_ahead: error encoding window {}: {e:?}", w.index);
+                    continue;
+                }
+            };
+            info!("window {} encoded ahead in {:?}", w.index, start.elapsed());
+
+            match encoded.lock() {
+                Ok(mut e) => {
+                    // the recording being transcribed, if any, and the one after it
+                    e.retain(|e| e.generation + 1 >= w.generation);
+                    e.push(Encoded {
+                        generation: w.generation,
+                        index: w.index,
+                        max: w.max,
+                        features,
+                    });
+                }
+                Err(e) => error!("encode_ahead: error acquiring lock: {e:?}"),
+            }
+        }
+    }
+
+    // the features of the `index`th window of a recording encoded ahead, if it was normalized to `max` as well
+    fn encoded(&self, generation: u64, index: usize, max: f32) -> Option<Tensor> {
+        let encoded = self.encoded.lock().ok()?;
+        encoded
+            .iter()
+            .find(|e| e.generation == generation && e.index == index && e.max == max)
+            .map(|e| e.features.clone())
     }
 
     fn preproc_decode(&self) -> Vec<u32> {
@@ -208,9 +478,10 @@ impl WhisperWrap {
         ]
     }
 
-    fn decode_segment(&self, model: &mut Whisper, segment: &Tensor) -> Result<DecodingResult> {
+    // decodes the encoder `features` of a segment, retrying at higher temperatures if it's unsure
+    fn decode_segment(&self, model: &mut Whisper, features: &Tensor) -> Result<DecodingResult> {
         for (i, &t) in TEMPERATURES.iter().enumerate() {
-            let decoded = self.decode(model, segment, t);
+            let decoded = self.decode(model, features, t);
             if i == TEMPERATURES.len() - 1 {
                 return decoded;
             }
@@ -237,20 +508,24 @@ impl WhisperWrap {
         unreachable!()
     }
 
-    fn decode(&self, model: &mut Whisper, segment: &Tensor, temp: f64) -> Result<DecodingResult> {
+    fn decode(&self, model: &mut Whisper, features: &Tensor, temp: f64) -> Result<DecodingResult> {
         let mut rng = rand::thread_rng();
 
-        let features = model.encoder.forward(segment, true)?;
         let mut tokens = self.preproc_decode();
 
         let mut no_speech_prob = f64::MAX;
         let mut sum_log_p = 0.;
 
+        model.decoder.reset();
         for i in 0..self.config.max_target_positions {
-            let tensor = Tensor::new(&tokens[..], &self.device)?;
-            let dec = model
-                .decoder
-                .forward(&tensor.unsqueeze(0)?, &features, i == 0)?;
+            // the prompt first, then only the newest token, the decoder keeps the ones before it
+            let new = if i == 0 {
+                &tokens[..]
+            } else {
+                &tokens[tokens.len() - 1..]
+            };
+            let tensor = Tensor::new(new, &self.device)?;
+            let dec = model.decoder.forward(&tensor.unsqueeze(0)?, features)?;
 
             // Extract the no speech probability on the first iteration by looking at the first
             // token logits and the probability for the according token.
@@ -305,16 +580,54 @@ impl WhisperWrap {
     }
 
     /// Accepts an incoming chunk of data and appends it to our `data` field of the struct
-    pub fn chunk(&self, chunk: Vec<f32>) {
+    /// The log-mel frames it completes are computed right away, so that they are ready for `infer()`
+    /// Pass `ahead: false` if the recording is changed before it's transcribed, the frames would be wasted
+    pub fn chunk(&self, chunk: Vec<f32>, ahead: bool) {
         let mut c = chunk;
-        let mut chunk = self.data.lock().unwrap();
+        let mut data = self.data.lock().unwrap();
+
+        // the frames have to cover the recording from its start, `infer()` computes whatever follows them
+        if data.pcm.is_empty() {
+            data.ahead = ahead;
+        }
+        if !data.ahead {
+            data.pcm.append(&mut c);
+            return;
+        }
 
-        chunk.append(&mut c);
+        let mut frames = data.mel.push(&c[..]);
+        data.frames.append(&mut frames);
+        data.pcm.append(&mut c);
+
+        // every full window is encoded right away, only the last one is left for `infer()`
+        while data.frames.len() >= (data.windows + 1) * N_FRAMES {
+            let start = data.windows * N_FRAMES;
+            let window = Window {
+                generation: data.generation,
+                index: data.windows,
+                frames: data.frames[start..start + N_FRAMES].to_vec(),
+                max: mel::loudest(&data.frames[..]),
+            };
+            if let Err(e) = self.ahead.send(window) {
+                error!("chunk: error sending window: {e:?}");
+            }
+            data.windows += 1;
+        }
+    }
+
+    /// Runs transcription on the audio data buffered so far
+    pub fn infer(&self, cancel: &Cancel) -> Result<(String, usize, std::time::Duration)> {
+        let data = self.take_data()?;
+        self.infer_pcm(&data[..], cancel)
     }
 
-    /// Runs transcription
-    pub fn infer(&self) -> Result<(String, usize, std::time::Duration)> {
-        let mels = self.preproc()?;
+    /// Runs transcription on the given `pcm` data, mono @ 16 kHz
+    pub fn infer_pcm(
+        &self,
+        pcm: &[f32],
+        cancel: &Cancel,
+    ) -> Result<(String, usize, std::time::Duration)> {
+        let (mels, recording) = self.preproc(pcm)?;
 
         let mut model = match self.model.lock() {
             Ok(m) => m,
@@ -340,12 +653,25 @@ impl WhisperWrap {
         let mut total_tokens = 0;
 
         while seek < content_frames {
+            if cancel.is_cancelled() {
+                anyhow::bail!("transcription cancelled");
+            }
+
             let start = std::time::Instant::now();
 
             let segment_size = usize::min(content_frames - seek, N_FRAMES);
             let mel_segment = mels.narrow(2, seek, segment_size)?;
 
-            let mut decoded = self.decode_segment(&mut model, &mel_segment)?;
+            // the encoder output is the same for every temperature
+            let ahead = recording
+                .filter(|_| segment_size == N_FRAMES)
+                .and_then(|(generation, max)| self.encoded(generation, seek / N_FRAMES, max));
+            let features = match ahead {
+                Some(f) => f,
+                None => model.encoder.forward(&mel_segment)?,
+            };
+
+            let mut decoded = self.decode_segment(&mut model, &features)?;
             seek += segment_size;
 
             total_dur += std::time::Instant::now() - start;
@@ -365,6 +691,11 @@ impl WhisperWrap {
             });
         }
 
+        info!(
+            "transcribed {total_tokens} tokens in {total_dur:?}, {:.1} tokens/s",
+            total_tokens as f64 / total_dur.as_secs_f64().max(f64::EPSILON)
+        );
+
         // Let us now create the final text output
         let instruct = self
             .tokenizer
@@ -374,3 +705,12 @@ impl WhisperWrap {
         Ok((instruct, total_tokens, total_dur))
     }
 }
+
+// identifies a recording, hashing all of its samples is much cheaper than computing their spectrogram
+fn fingerprint(pcm: &[f32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    pcm.len().hash(&mut hasher);
+    pcm.iter().for_each(|s| s.to_bits().hash(&mut hasher));
+
+    hasher.finish()
+}
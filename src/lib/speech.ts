@@ -0,0 +1,102 @@
+import type { SpeechEvent } from "./types";
+
+// base64 of 16 bit little endian samples to floats
+const decode = (pcm: string): Float32Array => {
+    const bytes = Uint8Array.from(atob(pcm), c => c.charCodeAt(0));
+    const view = new DataView(bytes.buffer);
+    const samples = new Float32Array(bytes.length >> 1);
+    for (let i = 0; i < samples.length; i++) {
+        samples[i] = view.getInt16(i * 2, true) / 32768;
+    }
+
+    return samples;
+}
+
+// Plays the spoken answers as their `speech` events come in
+// The sentences of an utterance are played in `seq` order, a newer utterance cuts the current one short
+export class Player {
+    private context: AudioContext|null = null;
+    private sources: AudioBufferSourceNode[] = [];
+    // the utterance being played, the ones before it are dropped, as is the last one stopped
+    private utterance = 0;
+    private stopped = 0;
+    // the next sentence to play, and the ones that came in ahead of it
+    private next = 0;
+    private pending = new Map<number, SpeechEvent>();
+    // when the sentences queued so far are done playing
+    private end = 0;
+
+    // called whenever playback starts or stops
+    onchange: (playing: boolean) => void = () => {};
+
+    get playing(): boolean {
+        return this.sources.length > 0;
+    }
+
+    push(e: SpeechEvent) {
+        if (e.utterance < this.utterance || e.utterance <= this.stopped) {
+            return;
+        }
+        if (e.utterance > this.utterance) {
+            this.reset();
+            this.utterance = e.utterance;
+        }
+
+        this.pending.set(e.seq, e);
+        for (let s = this.pending.get(this.next); s; s = this.pending.get(this.next)) {
+            this.pending.delete(this.next);
+            this.next++;
+            this.play(s);
+        }
+    }
+
+    // stops playing, the rest of the current utterance is dropped
+    stop() {
+        this.stopped = this.utterance;
+        this.reset();
+    }
+
+    private reset() {
+        this.sources.forEach(s => {
+            s.onended = null;
+            s.stop();
+        });
+        this.sources = [];
+        this.pending.clear();
+        this.next = 0;
+        this.end = 0;
+        this.onchange(false);
+    }
+
+    private play(e: SpeechEvent) {
+        // a sentence that failed to synthesize is skipped
+        if (!e.pcm || !e.sample_rate) {
+            return;
+        }
+
+        if (!this.context) {
+            this.context = new AudioContext();
+        }
+        const samples = decode(e.pcm);
+        const buffer = this.context.createBuffer(1, samples.length, e.sample_rate);
+        buffer.copyToChannel(samples, 0);
+
+        const source = this.context.createBufferSource();
+        source.buffer = buffer;
+        source.connect(this.context.destination);
+        source.onended = () => {
+            this.sources = this.sources.filter(s => s !== source);
+            if (!this.playing) {
+                this.onchange(false);
+            }
+        };
+
+        // right after the sentence before it
+        this.end = Math.max(this.end, this.context.currentTime);
+        source.start(this.end);
+        this.end += buffer.duration;
+
+        this.sources.push(source);
+        this.onchange(true);
+    }
+}
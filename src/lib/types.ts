@@ -1,6 +1,16 @@
 export interface Meta {
     n_tokens: number,
-    n_secs: number
+    n_secs: number,
+    prefix_cache?: PrefixCacheStats
+}
+
+export interface PrefixCacheStats {
+    // `skipped` when the rest of the prompt is long enough to recompute the prefix along with it
+    outcome: "hit"|"miss"|"skipped",
+    tokens: number,
+    hits: number,
+    misses: number,
+    skipped: number
 }
 
 export interface QuestionAnswer {
@@ -14,5 +24,126 @@ export interface QuestionAnswer {
 export interface Inference {
     instruct: string,
     text: string,
-    meta?: Meta
-}
\ No newline at end of file
+    meta?: Meta,
+    profile?: string,
+    id?: string,
+    conversation?: string,
+    json?: unknown,
+    tools?: ToolCall[],
+    sources?: Source[]
+}
+
+export interface Source {
+    id: number,
+    file: string,
+    score: number
+}
+
+export interface ToolCall {
+    name: string,
+    parameters: object,
+    result: unknown
+}
+
+export type ResponseFormat =
+    { type: "text" } |
+    { type: "json_object" } |
+    { type: "json_schema", json_schema: { name?: string, schema: object } } |
+    { type: "grammar", grammar: string }
+
+export interface HistoryEntry {
+    id: string,
+    conversation: string,
+    ts: number,
+    mode: "audio"|"text",
+    instruct: string,
+    answer: string,
+    meta: Meta
+}
+
+export interface Profile {
+    name: string,
+    system_prompt: string,
+    temperature: number,
+    top_p: number,
+    top_k: number,
+    max_tokens: number,
+    seed?: number
+}
+
+export interface SpeechEvent {
+    utterance: number,
+    seq: number,
+    text: string,
+    sample_rate: number,
+    // base64 of 16 bit little endian samples
+    pcm: string,
+    last: boolean
+}
+
+export type Speed = "slow"|"normal"|"fast"
+
+export interface TtsConfig {
+    enabled: boolean,
+    voice: string,
+    speed: Speed,
+    idle_secs: number
+}
+
+export interface WakeConfig {
+    enabled: boolean,
+    sensitivity: number,
+    max_secs: number
+}
+
+// the parts of the backend's `config.json` the page reads
+export interface Config {
+    tts: TtsConfig,
+    wake: WakeConfig
+}
+
+export interface WakeEvent {
+    state: "triggered"|"captured"
+}
+
+export interface Turn {
+    speaker: number,
+    start: number,
+    end: number,
+    text: string
+}
+
+export interface Diarization {
+    turns: Turn[],
+    speakers: number,
+    summary?: string
+}
+
+export interface QueueEvent {
+    id: string,
+    state: "queued"|"running"|"done"|"cancelled"|"failed",
+    position: number
+}
+
+export interface ModelEvent {
+    kind: "llm"|"asr"|"tts"|"embedding"|"speakers",
+    name: string,
+    state: "loading"|"ready"|"draining"|"unloaded"|"failed",
+    error?: string
+}
+
+export interface ModelInfo {
+    name: string,
+    loaded: boolean
+}
+
+export interface SystemInfo {
+    device: string,
+    threads: number,
+    cores: number,
+    mmap: boolean,
+    asr_dtype: "f32"|"f16"|"bf16",
+    llm: ModelInfo,
+    asr: ModelInfo,
+    cpu_features: string[]
+}